@@ -0,0 +1,262 @@
+use chrono::prelude::*;
+use hyper::header::AUTHORIZATION;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::{info, warn, error};
+
+use crate::executor::Execute;
+use crate::kill::KillCommand;
+use crate::portfolio::{Bin, PortfolioConfig, Signals};
+use crate::treasury;
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct AdminConfig {
+    // Local address to listen on, e.g. 127.0.0.1:9899
+    pub addr: String,
+    // Every request must send `Authorization: Bearer <token>`
+    pub token: String,
+    // Every change is appended here as a JSON line
+    #[serde(default = "default_audit_log")]
+    pub audit_log: String,
+}
+
+fn default_audit_log() -> String {
+    "audit.jsonl".to_string()
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Command {
+    // JSON merge patch over the market's current PortfolioConfig
+    PatchConfig(Value),
+    Pause(bool),
+    ReduceOnly(bool),
+    Execute(Execute),
+}
+
+pub struct AdminRequest {
+    pub command: Command,
+    pub reply: oneshot::Sender<Result<Value, String>>,
+}
+
+// Flags set through the API. Applied over the config file, so hot reloads don't clear them
+#[derive(PartialEq, Clone, Copy, Debug, Default, Serialize)]
+pub struct Overrides {
+    pub pause: bool,
+    pub reduce_only: bool,
+}
+
+// What GET /state reports for each market
+#[derive(PartialEq, Clone, Debug, Default, Serialize)]
+pub struct MarketStatus {
+    pub name: String,
+    pub block: u64,
+    pub active_id: u32,
+    pub cex_bid: f64,
+    pub cex_ask: f64,
+    pub config: Option<PortfolioConfig>,
+    pub overrides: Overrides,
+    pub halted: bool,
+    pub x_balance: u128,
+    pub y_balance: u128,
+    pub positions: Vec<Bin>,
+    pub signals: Signals,
+    pub treasury: treasury::Report,
+}
+
+// Market side of the API
+pub struct MarketLink {
+    pub rx: mpsc::Receiver<AdminRequest>,
+    pub status: watch::Sender<MarketStatus>,
+}
+
+// Server side of the API
+#[derive(Clone)]
+pub struct MarketHandle {
+    tx: mpsc::Sender<AdminRequest>,
+    status: watch::Receiver<MarketStatus>,
+}
+
+pub fn link(name: &str) -> (MarketHandle, MarketLink) {
+    let (tx, rx) = mpsc::channel(16);
+    let (status_tx, status_rx) = watch::channel(MarketStatus { name: name.to_string(), ..Default::default() });
+    (MarketHandle { tx, status: status_rx }, MarketLink { rx, status: status_tx })
+}
+
+// Merges a JSON merge patch (RFC 7386) into a value
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        },
+        _ => *target = patch.clone(),
+    }
+}
+
+struct AdminState {
+    config: AdminConfig,
+    markets: HashMap<String, MarketHandle>,
+    kill_tx: watch::Sender<Option<KillCommand>>,
+    // Sweeps are owner only, so they're refused when the owner isn't loaded
+    can_sweep: bool,
+}
+
+pub fn spawn_server(config: AdminConfig, markets: HashMap<String, MarketHandle>, kill_tx: watch::Sender<Option<KillCommand>>, can_sweep: bool) {
+    let addr = config.addr.parse::<SocketAddr>().unwrap();
+    if !addr.ip().is_loopback() {
+        warn!(addr = %addr, "Admin API is listening on a non local address");
+    }
+    let server = Arc::new(AdminState { config, markets, kill_tx, can_sweep });
+    tokio::spawn(async move {
+        let make_svc = make_service_fn(move |_| {
+            let server = server.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(server.clone(), req))) }
+        });
+        let bound = match Server::try_bind(&addr) {
+            Ok(bound) => bound,
+            Err(e) => {
+                error!(addr = %addr, error = ?e, "Failed to bind admin server");
+                return;
+            }
+        };
+        info!(addr = %addr, "Serving admin API");
+        if let Err(e) = bound.serve(make_svc).await {
+            error!(error = ?e, "Admin server stopped");
+        }
+    });
+}
+
+async fn handle(server: Arc<AdminState>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if !bearer_authorized(&req, &server.config.token) {
+        warn!(method = %req.method(), path = %req.uri().path(), "Unauthorized admin request");
+        return Ok(respond(StatusCode::UNAUTHORIZED, json!({"error": "unauthorized"})));
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(bytes) if bytes.is_empty() => Value::Null,
+        Ok(bytes) => match serde_json::from_slice::<Value>(&bytes) {
+            Ok(body) => body,
+            Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()}))),
+        },
+        Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()}))),
+    };
+
+    let (status, res) = route(&server, &method, &path, &body).await;
+    if method != Method::GET {
+        audit(&server.config.audit_log, &method, &path, &body, status, &res);
+    }
+    Ok(respond(status, res))
+}
+
+async fn route(server: &AdminState, method: &Method, path: &str, body: &Value) -> (StatusCode, Value) {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+    // Routes under /markets/<name>/ only apply to that market
+    let (markets, action) = match segments.as_slice() {
+        ["markets", name, rest @ ..] => match server.markets.get(*name) {
+            Some(handle) => (vec![(name.to_string(), handle.clone())], rest.to_vec()),
+            None => return (StatusCode::NOT_FOUND, json!({"error": format!("unknown market {}", name)})),
+        },
+        rest => (server.markets.iter().map(|(name, handle)| (name.clone(), handle.clone())).collect(), rest.to_vec()),
+    };
+
+    let command = match (method, action.as_slice()) {
+        (&Method::GET, ["state"]) | (&Method::GET, []) => {
+            let statuses = markets.iter().map(|(_, handle)| handle.status.borrow().clone()).collect::<Vec<MarketStatus>>();
+            return (StatusCode::OK, json!({"markets": statuses}));
+        },
+        (&Method::POST, ["kill"]) => {
+            let sweep = body.get("sweep").and_then(|sweep| sweep.as_bool()).unwrap_or(false);
+            if sweep && !server.can_sweep {
+                return (StatusCode::CONFLICT, json!({"error": "sweeping needs the owner, which isn't loaded. Kill without sweep"}));
+            }
+            server.kill_tx.send(Some(KillCommand { sweep, reason: "admin API".to_string() })).ok();
+            return (StatusCode::OK, json!({"killed": true, "sweep": sweep}));
+        },
+        (&Method::PATCH, ["config"]) if markets.len() == 1 => Command::PatchConfig(body.clone()),
+        (&Method::POST, ["execute"]) if markets.len() == 1 => match serde_json::from_value::<Execute>(body.clone()) {
+            Ok(action) => Command::Execute(action),
+            Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e.to_string()})),
+        },
+        (&Method::POST, ["pause"]) => Command::Pause(true),
+        (&Method::POST, ["resume"]) => Command::Pause(false),
+        (&Method::POST, ["reduce-only"]) => Command::ReduceOnly(body.get("enabled").and_then(|enabled| enabled.as_bool()).unwrap_or(true)),
+        _ => return (StatusCode::NOT_FOUND, json!({"error": format!("no route for {} {}", method, path)})),
+    };
+
+    let mut results = serde_json::Map::new();
+    let mut ok = true;
+    for (name, handle) in markets {
+        let (reply, rx) = oneshot::channel();
+        let res = match handle.tx.send(AdminRequest { command: command.clone(), reply }).await {
+            Ok(_) => rx.await.unwrap_or_else(|_| Err("market stopped".to_string())),
+            Err(_) => Err("market stopped".to_string()),
+        };
+        ok &= res.is_ok();
+        results.insert(name, match res {
+            Ok(value) => json!({"ok": value}),
+            Err(e) => json!({"error": e}),
+        });
+    }
+    match ok {
+        true => (StatusCode::OK, Value::Object(results)),
+        false => (StatusCode::UNPROCESSABLE_ENTITY, Value::Object(results)),
+    }
+}
+
+fn audit(path: &str, method: &Method, route: &str, body: &Value, status: StatusCode, res: &Value) {
+    let entry = json!({
+        "ts": Utc::now().to_rfc3339(),
+        "method": method.as_str(),
+        "path": route,
+        "body": body,
+        "status": status.as_u16(),
+        "result": res,
+    });
+    info!(audit = %entry, "Admin change");
+    let written = OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = written {
+        error!(path = path, error = ?e, "Failed to write audit log");
+    }
+}
+
+// JSON response. Shared with the stand-in signer used in tests
+pub(crate) fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// Whether the request sends `Authorization: Bearer <token>`
+pub(crate) fn bearer_authorized(req: &Request<Body>, token: &str) -> bool {
+    req.headers().get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map_or(false, |given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -3,7 +3,7 @@ use binance::model::*;
 use binance::market::*;
 use tokio::sync::watch;
 use binance::websockets::*;
-use std::sync::atomic::{AtomicBool};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::VecDeque;
 
 use tokio::sync::watch::Sender;
@@ -25,6 +25,13 @@ pub struct CexData {
     pub ask_sz: f64,
 }
 
+// The market drops its receiver when it switches feeds or reconnects. Stops the event loop so the feed thread returns
+fn send(tx: &Sender<CexData>, data: CexData, keep_running: &AtomicBool) {
+    if tx.send(data).is_err() {
+        keep_running.store(false, Ordering::Relaxed);
+    }
+}
+
 pub fn run_cex_feed(symbol: &str, tx: Sender<CexData>)  {
     loop {
         let endpoints =vec![
@@ -38,12 +45,12 @@ pub fn run_cex_feed(symbol: &str, tx: Sender<CexData>)  {
                 WebsocketEvent::BookTicker(e) => {
                     if last != e.best_bid.clone() + &e.best_ask {
                         last = e.best_bid.clone() + &e.best_ask;
-                        tx.send(CexData {
+                        send(&tx, CexData {
                             bid_px: e.best_bid.parse::<f64>().unwrap(),
                             bid_sz: e.best_bid_qty.parse::<f64>().unwrap(),
                             ask_px: e.best_ask.parse::<f64>().unwrap(),
                             ask_sz: e.best_ask_qty.parse::<f64>().unwrap(),
-                        }).unwrap();
+                        }, &keep_running);
                     }
                 },
                 // WebsocketEvent::Trade(e) => {
@@ -56,7 +63,13 @@ pub fn run_cex_feed(symbol: &str, tx: Sender<CexData>)  {
         });
 
         web_socket.connect_multiple_streams(&endpoints).unwrap(); // check error
-        if let Err(e) = web_socket.event_loop(&keep_running) {
+        let res = web_socket.event_loop(&keep_running);
+        if tx.is_closed() {
+            info!(endpoints = ?endpoints, "CEX feed receiver dropped. Stopping feed");
+            web_socket.disconnect().ok();
+            return;
+        }
+        if let Err(e) = res {
             error!(error=?e, "websocket error");
             panic!("web_socket error: {:?}", e);
         } else {
@@ -102,12 +115,12 @@ pub fn run_cex_feed_trade(symbol: &str, tx: Sender<CexData>, threshold_volume: f
                     // Calculate VWAP for the trades within the threshold volume
                     let vwap = current_value / current_volume;
 
-                    tx.send(CexData {
+                    send(&tx, CexData {
                         bid_px: vwap,
                         bid_sz: std::f64::NAN,
                         ask_px: vwap,
                         ask_sz: std::f64::NAN,
-                    }).unwrap();
+                    }, &keep_running);
                 },
                 _ => (),
             }
@@ -116,7 +129,13 @@ pub fn run_cex_feed_trade(symbol: &str, tx: Sender<CexData>, threshold_volume: f
         });
 
         web_socket.connect_multiple_streams(&endpoints).unwrap(); // check error
-        if let Err(e) = web_socket.event_loop(&keep_running) {
+        let res = web_socket.event_loop(&keep_running);
+        if tx.is_closed() {
+            info!(endpoints = ?endpoints, "CEX feed receiver dropped. Stopping feed");
+            web_socket.disconnect().ok();
+            return;
+        }
+        if let Err(e) = res {
             error!(error=?e, "websocket error");
             panic!("web_socket error: {:?}", e);
         } else {
@@ -144,12 +163,16 @@ pub async fn run_cex_feed_kucoin(symbol: String, tx: Sender<CexData>)  {
             let e = msg.data;
             if last != e.best_bid.clone() + &e.best_ask {
                 last = e.best_bid.clone() + &e.best_ask;
-                tx.send(CexData {
+                let data = CexData {
                     bid_px: e.best_bid.parse::<f64>().unwrap(),
                     bid_sz: e.best_bid_size.parse::<f64>().unwrap(),
                     ask_px: e.best_ask.parse::<f64>().unwrap(),
                     ask_sz: e.best_ask_size.parse::<f64>().unwrap(),
-                }).unwrap();
+                };
+                if tx.send(data).is_err() {
+                    info!("CEX feed receiver dropped. Stopping feed");
+                    return;
+                }
             }
         } else if let Some(KucoinWebsocketMsg::WelcomeMsg(msg)) = out {
             debug!(msg=?msg, "Kucoin ws message");
@@ -253,7 +276,7 @@ pub fn run_cex_feed_impl(symbol1: &str, symbol2: &str, tx: Sender<CexData>)  {
                         ask_px: cur_ask,
                         ask_sz: f64::NAN,
                     };
-                    tx.send(last.clone()).unwrap();
+                    send(&tx, last.clone(), &keep_running);
                 } else if (last.bid_px-cur_bid).abs()/cur_bid > 0.0001 || (last.ask_px-cur_ask).abs()/cur_bid > 0.0001 {
                     last = CexData {
                         bid_px: cur_bid,
@@ -261,7 +284,7 @@ pub fn run_cex_feed_impl(symbol1: &str, symbol2: &str, tx: Sender<CexData>)  {
                         ask_px: cur_ask,
                         ask_sz: f64::NAN,
                     };
-                    tx.send(last.clone()).unwrap();
+                    send(&tx, last.clone(), &keep_running);
                 }
                 
             }
@@ -270,7 +293,13 @@ pub fn run_cex_feed_impl(symbol1: &str, symbol2: &str, tx: Sender<CexData>)  {
         });
 
         web_socket.connect_multiple_streams(&endpoints).unwrap(); // check error
-        if let Err(e) = web_socket.event_loop(&keep_running) {
+        let res = web_socket.event_loop(&keep_running);
+        if tx.is_closed() {
+            info!(endpoints = ?endpoints, "CEX feed receiver dropped. Stopping feed");
+            web_socket.disconnect().ok();
+            return;
+        }
+        if let Err(e) = res {
             error!(error=?e, "websocket error");
             panic!("web_socket error: {:?}", e);
         } else {
@@ -344,14 +373,20 @@ pub fn run_cex_feed_impl_trade(symbol1: &str, symbol2: &str, tx: Sender<CexData>
                     ask_px: implied_ask,
                     ask_sz: f64::NAN,
                 };
-                tx.send(cex_data).unwrap();
+                send(&tx, cex_data, &keep_running);
             }
 
             Ok(())
         });
 
         web_socket.connect_multiple_streams(&endpoints).unwrap(); // check error
-        if let Err(e) = web_socket.event_loop(&keep_running) {
+        let res = web_socket.event_loop(&keep_running);
+        if tx.is_closed() {
+            info!(endpoints = ?endpoints, "CEX feed receiver dropped. Stopping feed");
+            web_socket.disconnect().ok();
+            return;
+        }
+        if let Err(e) = res {
             error!(error=?e, "websocket error");
             panic!("web_socket error: {:?}", e);
         } else {
@@ -0,0 +1,140 @@
+use clap::{Parser, Subcommand};
+use std::io::Write;
+
+use crate::executor;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Liquidity Book market maker")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file. JSON, TOML or YAML by extension. QUOTER__<FIELD>__<FIELD> env vars override its fields
+    #[arg(long, global = true, default_value = "config.json")]
+    pub config: String,
+    /// Simulate every tx with eth_call instead of sending it
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Quote every market in the config (default)
+    Run,
+    /// Print balances, gas, active id and fees of each market
+    Status {
+        /// Only this market. Defaults to every market
+        #[arg(long)]
+        market: Option<String>,
+    },
+    /// Print every bin each market holds liquidity in, with the tokens it is worth
+    Positions {
+        /// Only this market. Defaults to every market
+        #[arg(long)]
+        market: Option<String>,
+    },
+    /// Pull all liquidity and collect fees, optionally sweeping tokens and AVAX to the owner
+    #[command(alias = "kill")]
+    CancelAll {
+        /// Sweep both tokens and the native balance to the owner afterwards
+        #[arg(long)]
+        sweep: bool,
+        /// Only this market. Defaults to every market
+        #[arg(long)]
+        market: Option<String>,
+        /// Bins either side of the active id to check for liquidity
+        #[arg(long, default_value_t = executor::KILL_SCAN_RANGE)]
+        range: u32,
+    },
+    /// Collect fees from every bin held or touched since the last claim
+    Claim {
+        /// Only this market. Defaults to every market
+        #[arg(long)]
+        market: Option<String>,
+    },
+    /// Send tokens held by the executor to the owner
+    Sweep {
+        /// Only this market. Defaults to every market
+        #[arg(long)]
+        market: Option<String>,
+        /// Tokens to sweep, 0x0 for the native balance. Defaults to both tokens of the pair and the native balance
+        #[arg(long)]
+        token: Vec<String>,
+    },
+    /// Owner only contract administration. Shows a preview of each call and asks before sending it
+    Owner {
+        #[command(subcommand)]
+        call: OwnerCommand,
+        /// Market whose contract to call. Required when there is more than one
+        #[arg(long)]
+        market: Option<String>,
+        /// Send without asking for confirmation
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Load and validate the config, print every error and exit
+    CheckConfig,
+    /// Run the strategy over a recorded market on a simulated pair and print its PnL against holding
+    Backtest {
+        /// Frames written to <record_dir>/<market>.jsonl by a live run
+        input: String,
+        /// Market whose portfolio_config to use. Required when there is more than one
+        #[arg(long)]
+        market: Option<String>,
+        /// Swap fee of the simulated pair, in bps
+        #[arg(long, default_value_t = 10)]
+        fee_bps: u128,
+    },
+    /// Re-run the strategy on each recorded frame as the live run saw it and print every decision
+    Replay {
+        /// Frames written to <record_dir>/<market>.jsonl by a live run
+        input: String,
+        /// Market whose portfolio_config to use. Required when there is more than one
+        #[arg(long)]
+        market: Option<String>,
+        /// Swap fee of the simulated pair, in bps
+        #[arg(long, default_value_t = 10)]
+        fee_bps: u128,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum OwnerCommand {
+    /// Allow an address to make, move, cancel, take and claim
+    Whitelist {
+        caller: String,
+        /// Revoke instead of allow
+        #[arg(long)]
+        revoke: bool,
+    },
+    /// Send a token held by the contract to the owner, 0x0 for the native balance
+    Sweep {
+        token: String,
+        /// Amount in the token's smallest unit. Defaults to the whole balance
+        #[arg(long)]
+        amount: Option<String>,
+    },
+    /// Collect fees of the given bins into the contract
+    GetRewards {
+        ids: Vec<u32>,
+    },
+    /// Have the contract make arbitrary calls, all or nothing
+    Execute {
+        /// JSON file holding [["<target>", "<calldata>"], ...]
+        calls: String,
+    },
+    /// Let a caller pull the contract's WETH to top up its gas
+    ApproveGas {
+        caller: String,
+        /// Amount in wei. Defaults to unlimited
+        #[arg(long)]
+        amount: Option<String>,
+    },
+}
+
+// Asks on stdin, defaulting to no
+pub fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
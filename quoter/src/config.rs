@@ -0,0 +1,212 @@
+use ethers::prelude::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::{env, fs};
+use thiserror::Error;
+
+use crate::Config;
+use crate::signer::SignerSource;
+use crate::state::StateStore;
+
+// Env vars starting with this override fields of the config file. Nested keys are separated by `__`,
+// e.g. QUOTER__MARKETS__0__PORTFOLIO_CONFIG__PAUSE=true. Values are parsed as JSON, falling back to a string.
+pub const ENV_PREFIX: &str = "QUOTER__";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {path}: {source}")]
+    Read{path: String, source: std::io::Error},
+    #[error("failed to parse {path}: {message}")]
+    Parse{path: String, message: String},
+    #[error("invalid config {path}:\n  {}", .errors.join("\n  "))]
+    Invalid{path: String, errors: Vec<String>},
+    #[error("invalid config {path}:\n  state_dir: can't open {dir}: {source}")]
+    StateDir{path: String, dir: String, source: std::io::Error},
+}
+
+// Reads a JSON, TOML or YAML config (by extension), applies env overrides and validates every field.
+// Used at startup, by --check-config and by hot reload.
+pub fn load(path: &str) -> Result<Config, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read{path: path.to_string(), source})?;
+    let overrides = env_overrides(env::vars());
+    let config = match overrides.is_empty() {
+        // Straight from the source so u128 fields above u64::MAX survive
+        true => parse::<Config>(path, &contents)?,
+        false => {
+            let mut value = parse::<Value>(path, &contents)?;
+            for (keys, override_value) in overrides {
+                set(&mut value, &keys, override_value);
+            }
+            serde_json::from_value(value).map_err(|e| ConfigError::Parse{path: path.to_string(), message: e.to_string()})?
+        }
+    };
+    config.validate().map_err(|errors| ConfigError::Invalid{path: path.to_string(), errors})?;
+    Ok(config)
+}
+
+// Markets load and persist their state here, so it has to be usable before any of them start
+pub fn open_state_dir(path: &str, config: &Config) -> Result<(), ConfigError> {
+    for market in config.markets() {
+        StateStore::open(&config.state_dir, &market.name).map_err(|source| ConfigError::StateDir{path: path.to_string(), dir: config.state_dir.clone(), source})?;
+    }
+    Ok(())
+}
+
+fn parse<T: DeserializeOwned>(path: &str, contents: &str) -> Result<T, ConfigError> {
+    let extension = Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or("json");
+    let res = match extension {
+        "toml" => toml::from_str(contents).map_err(|e| e.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(contents).map_err(|e| e.to_string()),
+    };
+    res.map_err(|message| ConfigError::Parse{path: path.to_string(), message})
+}
+
+fn env_overrides<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<(Vec<String>, Value)> {
+    let mut overrides = vars.filter_map(|(key, value)| {
+        let keys = key.strip_prefix(ENV_PREFIX)?.split("__").map(|key| key.to_lowercase()).collect::<Vec<String>>();
+        let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+        Some((keys, value))
+    }).collect::<Vec<(Vec<String>, Value)>>();
+    overrides.sort_by(|a, b| a.0.cmp(&b.0));
+    overrides
+}
+
+// Sets a nested field. Object keys match case insensitively, arrays are indexed by number
+fn set(target: &mut Value, keys: &[String], value: Value) {
+    let (key, rest) = match keys.split_first() {
+        Some(split) => split,
+        None => {
+            *target = value;
+            return;
+        }
+    };
+    if let Value::Array(items) = target {
+        if let Some(item) = key.parse::<usize>().ok().and_then(|index| items.get_mut(index)) {
+            set(item, rest, value);
+        }
+        return;
+    }
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let object = target.as_object_mut().unwrap();
+    let existing = object.keys().find(|existing| existing.to_lowercase() == *key).cloned();
+    set(object.entry(existing.unwrap_or(key.clone())).or_insert(Value::Null), rest, value);
+}
+
+impl Config {
+    // Every invalid field, as `path.to.field: reason`
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if !self.wsrpc.starts_with("ws://") && !self.wsrpc.starts_with("wss://") {
+            errors.push("wsrpc: must be a ws:// or wss:// url".to_string());
+        }
+        if !self.archiverpc.starts_with("http://") && !self.archiverpc.starts_with("https://") {
+            errors.push("archiverpc: must be an http:// or https:// url".to_string());
+        }
+        if self.weth.parse::<Address>().is_err() {
+            errors.push("weth: not an address".to_string());
+        }
+        match (self.signer.as_ref(), self.owner_key.as_ref()) {
+            (Some(_), Some(_)) => errors.push("owner_key: must not be set alongside signer".to_string()),
+            (None, None) if self.callers.is_empty() => errors.push("signer: required unless owner_key or callers is set".to_string()),
+            // Never echo the key itself
+            (None, Some(key)) if key.parse::<LocalWallet>().is_err() => errors.push("owner_key: not a valid private key".to_string()),
+            (Some(SignerSource::Remote{url, ..}), None) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                errors.push("signer.Remote.url: must be an http:// or https:// url".to_string());
+            },
+            _ => {},
+        }
+        for (i, caller) in self.callers.iter().enumerate() {
+            if let SignerSource::Remote{url, ..} = caller {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    errors.push(format!("callers[{}].Remote.url: must be an http:// or https:// url", i));
+                }
+            }
+        }
+        if self.state_dir.is_empty() {
+            errors.push("state_dir: must not be empty".to_string());
+        }
+        if self.record_dir.as_ref().map_or(false, |dir| dir.is_empty()) {
+            errors.push("record_dir: must not be empty".to_string());
+        }
+        if self.metrics_addr.parse::<SocketAddr>().is_err() {
+            errors.push("metrics_addr: not a socket address".to_string());
+        }
+        if let Some(admin) = self.admin.as_ref() {
+            if admin.addr.parse::<SocketAddr>().is_err() {
+                errors.push("admin.addr: not a socket address".to_string());
+            }
+            if admin.token.is_empty() {
+                errors.push("admin.token: must not be empty".to_string());
+            }
+        }
+        if self.health.max_loop_age_secs == 0 || self.health.max_cex_age_secs == 0 || self.health.max_dex_age_secs == 0 {
+            errors.push("health: max ages must be > 0".to_string());
+        }
+        if let Some(risk) = self.risk {
+            for (field, limit) in [("max_deployed_notional", risk.max_deployed_notional), ("max_tx_notional", risk.max_tx_notional), ("max_daily_drawdown", risk.max_daily_drawdown)] {
+                if limit.map_or(false, |limit| !(limit > 0.0)) {
+                    errors.push(format!("risk.{}: must be > 0", field));
+                }
+            }
+        }
+        if let Some(treasury) = self.treasury {
+            if treasury.max_daily_topup == 0 {
+                errors.push("treasury.max_daily_topup: must be > 0".to_string());
+            }
+        }
+
+        if self.markets.is_empty() {
+            for (field, missing) in [("cex_param", self.cex_param.is_none()), ("executor_address", self.executor_address.is_none()), ("portfolio_config", self.portfolio_config.is_none())] {
+                if missing {
+                    errors.push(format!("{}: required when markets is empty", field));
+                }
+            }
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+        }
+        let mut names = HashSet::new();
+        for (i, market) in self.markets().iter().enumerate() {
+            let prefix = match self.markets.is_empty() {
+                true => String::new(),
+                false => format!("markets[{}].", i),
+            };
+            if market.name.is_empty() || !names.insert(market.name.clone()) {
+                errors.push(format!("{}name: must be unique and not empty", prefix));
+            }
+            if market.executor_address.parse::<Address>().is_err() {
+                errors.push(format!("{}executor_address: not an address", prefix));
+            }
+            if let Err(portfolio_errors) = market.portfolio_config.validate() {
+                errors.extend(portfolio_errors.into_iter().map(|e| format!("{}portfolio_config.{}", prefix, e)));
+            }
+            if !(market.notional_px > 0.0) {
+                errors.push(format!("{}notional_px: must be > 0", prefix));
+            }
+            if market.reconcile.interval_blocks == 0 {
+                errors.push(format!("{}reconcile.interval_blocks: must be > 0", prefix));
+            }
+            if let Some(hedger) = market.hedger.as_ref() {
+                if !(hedger.qty_step > 0.0) {
+                    errors.push(format!("{}hedger.qty_step: must be > 0", prefix));
+                }
+                if hedger.fee_bps < 0.0 {
+                    errors.push(format!("{}hedger.fee_bps: must be >= 0", prefix));
+                }
+                if !hedger.paper && (hedger.api_key.is_none() || hedger.secret_key.is_none()) {
+                    errors.push(format!("{}hedger: api_key and secret_key are required unless paper is set", prefix));
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
@@ -1,14 +1,20 @@
 use ethers::prelude::*;
-use ethers::abi::AbiEncode;
+use ethers::abi::Detokenize;
+use ethers::providers::MiddlewareError;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::collections::HashMap;
 use std::{convert::TryFrom, sync::{Arc, Mutex}};
 use std::{thread, time, time::{Instant, Duration}};
 use amm::{AMM, lb};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
 use tracing::{trace, debug, info, warn, error};
 
-use crate::portfolio::{Bin, self};
+use crate::portfolio::{Bin, Portfolio, self};
+use crate::state::{self, MarketState, StateStore};
+use crate::metrics;
+use crate::treasury::Treasury;
 abigen!(
     MM,
     "./src/MM.json",
@@ -38,10 +44,10 @@ abigen!(
 pub enum Execute {
     Make(Vec<(Tick, u128, u128)>),
     Move{ 
-        from: Vec<(Tick, u128)>,
+        from: Vec<(Tick, Amount)>,
         to: Vec<(Tick, u128, u128)>,
     },
-    Cancel(Vec<(Tick, u128)>),
+    Cancel(Vec<(Tick, Amount)>),
     Take{
         amt_in: u128,
         amt_out: u128,
@@ -51,25 +57,187 @@ pub enum Execute {
         amt_in: u128,
         amt_out: u128,
         swap_for_y: bool,
-        orders: Vec<(Tick, u128)>,
+        orders: Vec<(Tick, Amount)>,
     },
     Claim,
     CheckGas,
+    // Sent as one MM.execute, so either every action lands or none do
+    Batch(Vec<Execute>),
 }
+impl Execute {
+    // Variant name, used as a metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            Execute::Make(_) => "Make",
+            Execute::Move{..} => "Move",
+            Execute::Cancel(_) => "Cancel",
+            Execute::Take{..} => "Take",
+            Execute::CancelNTake{..} => "CancelNTake",
+            Execute::Claim => "Claim",
+            Execute::CheckGas => "CheckGas",
+            Execute::Batch(_) => "Batch",
+        }
+    }
+
+    // The actions themselves, with nested batches opened up
+    pub fn flatten(&self) -> Vec<Execute> {
+        match self {
+            Execute::Batch(actions) => actions.iter().flat_map(|action| action.flatten()).collect(),
+            action => vec![action.clone()],
+        }
+    }
+
+    // Bins the action mints into or burns from
+    pub fn bin_ids(&self, curid: u32) -> Vec<u32> {
+        let ticks = match self {
+            Execute::Make(orders) => orders.iter().map(|(tick, _, _)| tick.clone()).collect(),
+            Execute::Move{from, to} => from.iter().map(|(tick, _)| tick.clone()).chain(to.iter().map(|(tick, _, _)| tick.clone())).collect(),
+            Execute::Cancel(orders) | Execute::CancelNTake{orders, ..} => orders.iter().map(|(tick, _)| tick.clone()).collect(),
+            Execute::Batch(actions) => return actions.iter().flat_map(|action| action.bin_ids(curid)).collect(),
+            _ => Vec::new(),
+        };
+        ticks.into_iter().map(|tick| tick.id(curid)).collect()
+    }
+
+    // Token amounts the action adds as liquidity
+    pub fn added(&self) -> (u128, u128) {
+        match self {
+            Execute::Make(orders) | Execute::Move{ to: orders, .. } => {
+                orders.iter().fold((0, 0), |(x, y), (_, order_x, order_y)| (x + order_x, y + order_y))
+            },
+            Execute::Batch(actions) => actions.iter().fold((0, 0), |(x, y), action| {
+                let (add_x, add_y) = action.added();
+                (x + add_x, y + add_y)
+            }),
+            _ => (0, 0),
+        }
+    }
+}
+
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum Tick {
     Delta(i32),
     Exact(u32),
 }
 
+impl Tick {
+    pub fn id(&self, curid: u32) -> u32 {
+        match self {
+            Tick::Delta(delta) => ((curid as i32) + delta) as u32,
+            Tick::Exact(tick) => *tick,
+        }
+    }
+}
+
+// How much liquidity to pull from a bin. Capped at what we hold
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum Amount {
+    // Value of our share of the bin, in token y at the bin's price
     Notional(u128),
+    // Tokens x and y. Pulls enough LB tokens to get at least both
     Exact(u128, u128),
     All,
+    // LB tokens
+    Tokens(u128),
 }
 
 
+// Bins either side of the active id checked for liquidity by the kill switch
+pub const KILL_SCAN_RANGE: u32 = 2000;
+// Non-empty bins returned per MM.left/MM.right call during discovery
+const DISCOVERY_PAGE: u64 = 100;
+// Bins cancelled per tx by the kill switch
+const KILL_CHUNK: usize = 50;
+
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct KillReport {
+    pub active_id: u32,
+    pub bins: Vec<u32>,
+    pub fees_x: u128,
+    pub fees_y: u128,
+    pub fee_claim_block: Option<u64>,
+    // Bins in each cancel tx, and the block it was mined in
+    pub cancels: Vec<(Vec<u32>, Option<u64>)>,
+    pub sweeps: Vec<(Address, Option<u64>)>,
+    pub remaining_bins: Vec<u32>,
+}
+
+// Executor balances and bins read from the chain at one block
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Snapshot {
+    pub block: u64,
+    pub x_balance: u128,
+    pub y_balance: u128,
+    // LB tokens held, reserves and total supply of every non-empty bin we hold
+    pub bins: HashMap<u32, (u128, U256, U256, u128)>,
+}
+
+// Owner only calls to the MM contract, apart from GetRewards which any whitelisted caller can make
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum OwnerCall {
+    // Allow or revoke a caller of make, move, cancel, take and getRewards
+    SetWhitelist{caller: Address, allowed: bool},
+    // Send the whole balance of a token, or the native balance for the zero address, to the owner
+    Sweep{token: Address},
+    // Send an amount of a token, or of the native balance for the zero address, to the owner
+    SweepAmount{token: Address, amount: U256},
+    // Collect fees of the given bins into the contract
+    GetRewards{ids: Vec<u32>},
+    // Calls made by the contract in order. Reverts as a whole if any fails
+    Execute{calls: Vec<(Address, Bytes)>},
+    // Lets a caller pull up to amount of the contract's WETH to top up its gas, through execute
+    ApproveGas{caller: Address, amount: U256},
+}
+
+// What an owner call will do, shown before it is sent
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct Preview {
+    pub call: OwnerCall,
+    pub from: Address,
+    pub to: Address,
+    pub calldata: Bytes,
+    // Whether the sender is the owner, or whitelisted for GetRewards
+    pub authorized: bool,
+    pub gas: Option<U256>,
+    // Return data of the call simulated against the latest block, or why it reverted
+    pub simulation: Result<Bytes, String>,
+}
+
+// What the status command reports for a market
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Status {
+    pub active_id: u32,
+    pub x_balance: u128,
+    pub y_balance: u128,
+    pub gas_balance: u128,
+    pub bins: usize,
+    pub fees_x_pending: u128,
+    pub fees_y_pending: u128,
+    pub fees_x_claimed: u128,
+    pub fees_y_claimed: u128,
+    pub fee_pnl: Decimal,
+}
+
+// Fees waiting in the pair, and what claiming them costs. Values are in token y at the CEX mid
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct Claimable {
+    pub ids: Vec<U256>,
+    pub fees_x: u128,
+    pub fees_y: u128,
+    pub gas: u64,
+    pub value: Decimal,
+    pub gas_cost: Decimal,
+}
+
+// Liquidity held in one bin, and the token amounts it is worth
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Position {
+    pub id: u32,
+    pub tokens: u128,
+    pub x: u128,
+    pub y: u128,
+}
+
 #[derive(Clone, Debug)]
 pub struct Executor <M> {
     pub address: Address,
@@ -85,13 +253,36 @@ pub struct Executor <M> {
     consecutive_failures: u32,
     bins_touched: HashMap<u32, bool>,
     sent_ts: Vec<Instant>,
+    pending_txs: Vec<TxHash>,
+    pub fees_x_claimed: u128,
+    pub fees_y_claimed: u128,
+    // Claimed fees valued in token y at the CEX mid when they were claimed
+    pub fee_pnl: Decimal,
+    // Latest CEX mid, set by the market loop. Claims without one leave token x out of fee_pnl
+    pub mark: Option<f64>,
+    // Whether this contract can take batches through MM.execute
+    can_batch: Option<bool>,
+    pub store: Option<StateStore>,
+    // Market name used to label metrics, and the action whose txs are being sent
+    pub market: String,
+    action: &'static str,
+    // Lowest caller native balance as of the last gas check
+    pub gas_balance: Option<u128>,
+    // Contract's WETH as of the last gas check, and whether the daily budget cut a top-up short
+    pub reserve: Option<u128>,
+    pub topup_capped: bool,
+    // Gas spend and top-up budget, shared with the other markets when running
+    pub treasury: Arc<Mutex<Treasury>>,
+    // Simulate txs with eth_call instead of sending them
+    pub dry_run: bool,
+    // Whitelisted callers that send market txs in turn, so nonces don't queue behind each other.
+    // Txs are sent from client when empty
+    pub callers: Vec<Arc<M>>,
+    next_caller: usize,
 }
 
 impl <M: Middleware> Executor <M> {
     pub async fn new(client: Arc<M>, address: Address, weth_address: Address, config: portfolio::PortfolioConfig) -> Self {
-
-        assert!(config.tx_limit_5min > 0, "tx_limit_1min must be greater than 0");
-
         let mm = MM::new(address, client.clone());
         let pair_address = mm.lb_pair().call().await.unwrap();
         let pair = LBPair::new(pair_address, client.clone());
@@ -112,6 +303,22 @@ impl <M: Middleware> Executor <M> {
             consecutive_failures: 0,
             bins_touched: HashMap::new(),
             sent_ts: Vec::new(),
+            pending_txs: Vec::new(),
+            fees_x_claimed: 0,
+            fees_y_claimed: 0,
+            fee_pnl: Decimal::ZERO,
+            mark: None,
+            can_batch: None,
+            store: None,
+            market: String::new(),
+            action: "",
+            gas_balance: None,
+            reserve: None,
+            topup_capped: false,
+            treasury: Arc::new(Mutex::new(Treasury::new(None))),
+            dry_run: false,
+            callers: Vec::new(),
+            next_caller: 0,
         }
     }
 
@@ -121,6 +328,69 @@ impl <M: Middleware> Executor <M> {
         (x_bal.as_u128(), y_bal.as_u128())
     }
 
+    pub fn tokens(&self) -> (Address, Address) {
+        (self.x.address(), self.y.address())
+    }
+
+    pub async fn active_id(&self) -> u32 {
+        let (_, _, active_id) = self.pair.get_reserves_and_id().call().await.unwrap();
+        active_id.as_u32()
+    }
+
+    fn next_caller(&mut self) -> Arc<M> {
+        if self.callers.is_empty() {
+            return self.client.clone();
+        }
+        self.next_caller = (self.next_caller + 1) % self.callers.len();
+        self.callers[self.next_caller].clone()
+    }
+
+    fn all_callers(&self) -> Vec<Arc<M>> {
+        match self.callers.is_empty() {
+            true => vec![self.client.clone()],
+            false => self.callers.clone(),
+        }
+    }
+
+    // Includes bins in the next fee claim
+    pub fn touch(&mut self, bin_ids: Vec<u32>) {
+        bin_ids.into_iter().for_each(|id| { self.bins_touched.insert(id, true); });
+    }
+
+    pub async fn status(&self) -> Status {
+        let (x_balance, y_balance) = self.get_balances().await;
+        let positions = self.discover_positions().await;
+        let ids = positions.keys().map(|id| U256::from(*id)).collect::<Vec<U256>>();
+        let (fees_x, fees_y) = self.pair.pending_fees(self.address, ids).call().await.unwrap();
+        let caller = self.client.default_sender().unwrap();
+        Status {
+            active_id: self.active_id().await,
+            x_balance,
+            y_balance,
+            gas_balance: self.client.get_balance(caller, None).await.unwrap().as_u128(),
+            bins: positions.len(),
+            fees_x_pending: fees_x.as_u128(),
+            fees_y_pending: fees_y.as_u128(),
+            fees_x_claimed: self.fees_x_claimed,
+            fees_y_claimed: self.fees_y_claimed,
+            fee_pnl: self.fee_pnl,
+        }
+    }
+
+    // Every bin we hold, valued at its reserves as of the latest block
+    pub async fn positions(&self) -> Vec<Position> {
+        let block = self.client.get_block_number().await.unwrap().as_u64();
+        let ids = self.discover_positions().await.into_keys().collect::<Vec<u32>>();
+        let mut positions = self.snapshot(ids, block).await.bins.into_iter().map(|(id, (tokens, reserve_x, reserve_y, supply))| Position {
+            id,
+            tokens,
+            x: (reserve_x * U256::from(tokens) / U256::from(supply)).as_u128(),
+            y: (reserve_y * U256::from(tokens) / U256::from(supply)).as_u128(),
+        }).collect::<Vec<Position>>();
+        positions.sort_by_key(|position| position.id);
+        positions
+    }
+
     pub async fn get_decs(&self) -> (usize, usize) {
         let x_bal = self.x.decimals().call().await.unwrap();
         let y_bal = self.y.decimals().call().await.unwrap();
@@ -142,6 +412,75 @@ impl <M: Middleware> Executor <M> {
         positions
     }
 
+    // Every bin the executor holds liquidity in. Walks each non-empty bin of the pair outwards
+    // from the active id with MM.left/MM.right, then checks our balance in each.
+    pub async fn discover_positions(&self) -> HashMap<u32, u128> {
+        let (_, _, active_id) = self.pair.get_reserves_and_id().call().await.unwrap();
+        let active_id = active_id.as_u32();
+        let mut ids = vec![active_id];
+        for go_left in [true, false] {
+            let mut start = active_id;
+            loop {
+                let page = match go_left {
+                    true => self.mm.left(start, DISCOVERY_PAGE.into()).call().await.unwrap(),
+                    false => self.mm.right(start, DISCOVERY_PAGE.into()).call().await.unwrap(),
+                };
+                // Unfilled slots are zero once the search runs off the end of the pair
+                let found = page.into_iter().filter(|id| *id != 0).collect::<Vec<u32>>();
+                let full = found.len() as u64 == DISCOVERY_PAGE;
+                match found.last() {
+                    Some(last) if *last != start => start = *last,
+                    _ => break,
+                }
+                ids.extend(found);
+                if !full {
+                    break;
+                }
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        let positions = self.get_liq_tokens(ids.clone()).await;
+        info!(active_id = active_id, non_empty_bins = ids.len(), my_bins = ?positions.keys().collect::<Vec<&u32>>(), "Discovered positions");
+        positions
+    }
+
+    // Reserves and LB token supply of each bin, read from the chain
+    pub async fn get_bins(&self, bin_ids: Vec<u32>) -> HashMap<u32, (U256, U256, u128)> {
+        let supply = self.get_supply(bin_ids.clone()).await;
+        let mut bins = HashMap::new();
+        for ids in bin_ids.chunks(50) {
+            let (reserve_x, reserve_y) = self.mm.get_reserves(ids.to_vec()).call().await.unwrap();
+            for ((id, x), y) in ids.iter().zip(reserve_x).zip(reserve_y) {
+                bins.insert(*id, (x, y, *supply.get(id).unwrap()));
+            }
+        }
+        bins
+    }
+
+    pub async fn snapshot(&self, bin_ids: Vec<u32>, block: u64) -> Snapshot {
+        let x_balance = self.x.balance_of(self.address).block(block).call().await.unwrap();
+        let y_balance = self.y.balance_of(self.address).block(block).call().await.unwrap();
+        let mut bins = HashMap::new();
+        for ids in bin_ids.chunks(50) {
+            let u256_ids = ids.iter().map(|x| U256::from(*x)).collect::<Vec<U256>>();
+            let tokens = self.mm.my_bins(u256_ids.clone()).block(block).call().await.unwrap();
+            let supply = self.mm.supply(u256_ids).block(block).call().await.unwrap();
+            let (reserve_x, reserve_y) = self.mm.get_reserves(ids.to_vec()).block(block).call().await.unwrap();
+            for i in 0..ids.len() {
+                if !tokens[i].is_zero() {
+                    bins.insert(ids[i], (tokens[i].as_u128(), reserve_x[i], reserve_y[i], supply[i].as_u128()));
+                }
+            }
+        }
+        Snapshot {
+            block,
+            x_balance: x_balance.as_u128(),
+            y_balance: y_balance.as_u128(),
+            bins,
+        }
+    }
+
     pub async fn get_supply(&self, bin_ids: Vec<u32>) -> HashMap<u32, u128> {
         let mut positions = HashMap::new();
         for ids in bin_ids.chunks(50) {
@@ -155,9 +494,233 @@ impl <M: Middleware> Executor <M> {
         positions
     }
 
-    pub async fn execute(&mut self, todo: Execute, curid: u32)  -> Option <u64> {
+    // Batches the contract can't take are sent as one tx per action, and counted as unbatched.
+    // Returns the last block any of them was mined in
+    pub async fn execute(&mut self, todo: Execute, curid: u32) -> Option<u64> {
+        if let Execute::Batch(_) = todo {
+            if !self.can_batch().await {
+                warn!(todo = ?todo, "Can't batch. Sending each action on its own");
+                metrics::TXS.with_label_values(&[&self.market, "Batch", "unbatched"]).inc();
+                let mut block = None;
+                for action in todo.flatten() {
+                    block = block.max(self.send(action, curid).await);
+                }
+                return block;
+            }
+        }
+        self.send(todo, curid).await
+    }
+
+    async fn send(&mut self, todo: Execute, curid: u32)  -> Option <u64> {
         info!(todo = ?todo, tick = curid);
-        let mut call = match todo.clone() {
+        self.action = todo.name();
+        let todo = self.resolve(todo).await;
+        if let Execute::Cancel(orders) = &todo {
+            if orders.is_empty() {
+                info!("Nothing to cancel");
+                return None;
+            }
+        }
+        let actions = todo.flatten();
+        let mut claims = Vec::new();
+        let mut tx = match todo.clone() {
+            Execute::Claim => {
+                return match self.pending_claim().await {
+                    Some(claimable) => self.claim(claimable).await,
+                    None => None,
+                };
+            },
+            Execute::CheckGas => {
+                let reserve = self.weth.balance_of(self.address).call().await.unwrap().as_u128();
+                metrics::WETH_RESERVE.with_label_values(&[&self.market]).set(reserve as f64 / 1e18);
+                self.reserve = Some(reserve);
+                if self.treasury.lock().unwrap().low_reserve(reserve) {
+                    warn!(reserve = reserve, "Contract WETH reserve is low");
+                }
+                // Callers below min_gas are topped up to twice that, within the daily budget
+                let target = self.config.min_gas * 2;
+                self.topup_capped = false;
+                let mut lowest: Option<u128> = None;
+                for caller in self.all_callers() {
+                    let contract_caller = caller.default_sender().unwrap();
+                    let mut gas_balance = self.client.get_balance(contract_caller, None).await.unwrap().as_u128();
+                    if gas_balance < self.config.min_gas {
+                        let needed = target - gas_balance;
+                        let granted = self.treasury.lock().unwrap().reserve_topup(&self.market, needed);
+                        self.topup_capped |= granted < needed;
+                        if granted > 0 {
+                            info!(caller = ?contract_caller, gas_balance = gas_balance, amount = granted, "Topping up gas");
+                            let unwrapped = self.top_up(caller, granted).await;
+                            self.treasury.lock().unwrap().refund(granted - unwrapped);
+                            metrics::GAS_TOPPED_UP.with_label_values(&[&self.market]).inc_by(unwrapped as f64 / 1e18);
+                            gas_balance = self.client.get_balance(contract_caller, None).await.unwrap().as_u128();
+                        }
+                    }
+                    lowest = Some(lowest.map_or(gas_balance, |lowest| lowest.min(gas_balance)));
+                }
+                // Health and metrics watch the caller closest to running dry
+                if let Some(lowest) = lowest {
+                    metrics::GAS_BALANCE.with_label_values(&[&self.market]).set(lowest as f64 / 1e18);
+                    self.gas_balance = Some(lowest);
+                }
+                return None;
+            },
+            Execute::Batch(_) => {
+                // One MM.execute: actions as calls back into the contract, the claim as collectFees straight
+                // on the pair. Either every call lands or none do
+                let mut calls = Vec::new();
+                for action in actions.iter().cloned() {
+                    match action {
+                        Execute::Claim if claims.is_empty() => if let Some(claim) = self.pending_claim().await {
+                            let call_data = self.pair.collect_fees(self.address, claim.ids.clone()).calldata().unwrap();
+                            calls.push(mm::Call{target: self.pair_address, call_data});
+                            claims.push(claim);
+                        },
+                        Execute::Claim => (),
+                        Execute::CheckGas => warn!("Gas checks can't be batched. Skipping"),
+                        action => {
+                            let call_data = self.action_tx(action, curid).data().cloned().unwrap_or_default();
+                            calls.push(mm::Call{target: self.address, call_data});
+                        },
+                    }
+                }
+                if calls.is_empty() {
+                    return None;
+                }
+                self.mm.execute(calls).tx
+            },
+            action => self.action_tx(action, curid),
+        };
+        let any = |names: &[&str]| actions.iter().any(|action| names.contains(&action.name()));
+        let (makes, takes) = (any(&["Make", "Move"]), any(&["Take", "CancelNTake"]));
+        if makes {
+            if self.sent_ts.len() > self.config.tx_limit_5min {
+                let earliest = self.sent_ts.first().unwrap();
+                if earliest.elapsed() < Duration::from_secs(300) {
+                    warn!(sent_ts = ?self.sent_ts, "On Chain Tx limit reached");
+                    metrics::TXS.with_label_values(&[&self.market, self.action, "rate_limited"]).inc();
+                    return None;
+                } else {
+                    self.sent_ts.remove(0);
+                }
+            }
+        }
+        if takes {
+            tx.set_gas_price(self.client.get_gas_price().await.unwrap() * self.config.take_gas_price_scaling / 100);
+        } else if !makes {
+            tx.set_gas_price(self.client.get_gas_price().await.unwrap());
+        }
+        if !makes && self.sent_ts.len() > self.config.tx_limit_5min {
+            self.sent_ts = self.sent_ts.as_slice()[self.sent_ts.len()-self.config.tx_limit_5min..].to_vec();
+        }
+
+        // A batch is one tx and takes one rate limit slot
+        self.sent_ts.push(Instant::now());
+        let calls = actions.iter().filter(|action| !matches!(action, Execute::Claim | Execute::CheckGas)).count().max(1);
+        tx.set_gas(self.config.gas_constant * calls as u64 + claims.iter().map(|claim| claim.gas).sum::<u64>());
+        // Only the owner can send MM.execute. client is the owner whenever it's loaded
+        let caller = match todo {
+            Execute::Batch(_) => self.client.clone(),
+            _ => self.next_caller(),
+        };
+        if self.dry_run {
+            self.simulate_tx(&caller, &tx).await;
+            return None;
+        }
+        match caller.send_transaction(tx.clone(), None).await {
+            Ok(pending) => {
+                // record the tx
+                let hash = *pending;
+                info!(tx_hash = ?hash, caller = ?caller.default_sender(), "Submitted tx");
+                let block = self.deal_with_tx(hash).await;
+                // Reverted batches claimed nothing
+                if block.is_some() {
+                    claims.iter().for_each(|claim| self.claimed(claim));
+                }
+                return block
+            }
+            Err(err) => {
+                let err = err.to_string();
+                error!(err = ?err, tx = ?tx, "Failed to submit on chain tx");
+                metrics::TXS.with_label_values(&[&self.market, self.action, "submit_failed"]).inc();
+            }
+        };
+        None
+    }
+
+    // Removals as exact bins and LB token amounts. Delta ticks are taken from the active id as of now
+    // rather than when the action was decided. Bins with nothing to pull are dropped
+    async fn resolve(&self, todo: Execute) -> Execute {
+        match todo {
+            Execute::Move{from, to} => Execute::Move{from: self.resolve_removals(from).await, to},
+            Execute::Cancel(orders) => Execute::Cancel(self.resolve_removals(orders).await),
+            Execute::CancelNTake{amt_in, amt_out, swap_for_y, orders} => {
+                Execute::CancelNTake{amt_in, amt_out, swap_for_y, orders: self.resolve_removals(orders).await}
+            },
+            Execute::Batch(actions) => {
+                let mut resolved = Vec::new();
+                for action in Execute::Batch(actions).flatten() {
+                    resolved.push(match action {
+                        Execute::Move{from, to} => Execute::Move{from: self.resolve_removals(from).await, to},
+                        Execute::Cancel(orders) => Execute::Cancel(self.resolve_removals(orders).await),
+                        Execute::CancelNTake{amt_in, amt_out, swap_for_y, orders} => {
+                            Execute::CancelNTake{amt_in, amt_out, swap_for_y, orders: self.resolve_removals(orders).await}
+                        },
+                        action => action,
+                    });
+                }
+                Execute::Batch(resolved.into_iter().filter(|action| *action != Execute::Cancel(Vec::new())).collect())
+            },
+            todo => todo,
+        }
+    }
+
+    async fn resolve_removals(&self, orders: Vec<(Tick, Amount)>) -> Vec<(Tick, Amount)> {
+        if orders.iter().all(|order| matches!(order, (Tick::Exact(_), Amount::Tokens(_)))) {
+            return orders;
+        }
+        let active_id = self.active_id().await;
+        let ids = orders.iter().map(|(tick, _)| tick.id(active_id)).collect::<Vec<u32>>();
+        let held = self.get_liq_tokens(ids.clone()).await;
+        let bins = self.get_bins(ids.clone()).await;
+        let bin_step = match orders.iter().any(|(_, amount)| matches!(amount, Amount::Notional(_))) {
+            true => Some(self.pair.fee_parameters().call().await.unwrap().bin_step),
+            false => None,
+        };
+
+        let mut resolved = Vec::new();
+        for ((tick, amount), id) in orders.into_iter().zip(ids) {
+            let held = held.get(&id).cloned().unwrap_or(0);
+            let (reserve_x, reserve_y, supply) = bins.get(&id).cloned().unwrap_or_default();
+            // Our share of the bin
+            let (our_x, our_y) = match supply {
+                0 => (U256::zero(), U256::zero()),
+                supply => (reserve_x * held / supply, reserve_y * held / supply),
+            };
+            let share = |amount: U256, ours: U256| match ours.is_zero() {
+                true => 0,
+                false => (amount * held / ours).min(held.into()).as_u128(),
+            };
+            let tokens = match amount {
+                Amount::Tokens(tokens) => tokens,
+                Amount::All => held,
+                Amount::Exact(x, y) => share(x.into(), our_x).max(share(y.into(), our_y)),
+                Amount::Notional(value) => {
+                    let px_128 = lb::Bin::getPriceFromId(id.into(), bin_step.unwrap().into());
+                    share(value.into(), ((px_128 * our_x) >> 128) + our_y)
+                },
+            }.min(held);
+            match tokens {
+                0 => debug!(tick = ?tick, id = id, "Nothing to remove"),
+                _ => resolved.push((Tick::Exact(id), Amount::Tokens(tokens))),
+            }
+        }
+        resolved
+    }
+
+    // The MM call for one action. Marks the bins it mints into for the next fee claim
+    fn action_tx(&mut self, todo: Execute, curid: u32) -> TypedTransaction {
+        match todo {
             Execute::Make(orders) => {
                 let mut amount_x = 0;
                 let mut amount_y = 0;
@@ -167,10 +730,7 @@ impl <M: Middleware> Executor <M> {
                 for (tick, x, y) in orders {
                     amount_x += x;
                     amount_y += y;
-                    let tick = match tick {
-                        Tick::Delta(delta) => ((curid as i32) + delta) as u32,
-                        Tick::Exact(tick) => tick,
-                    };
+                    let tick = tick.id(curid);
                     bin_ids.push(U256::from(tick));
                     self.bins_touched.insert(tick, true);
                     distribution_x.push(U256::from(x) * U256::exp10(18));
@@ -178,7 +738,7 @@ impl <M: Middleware> Executor <M> {
                 }
                 distribution_x.iter_mut().for_each(|x| if !x.is_zero() {*x /= U256::from(amount_x)});
                 distribution_y.iter_mut().for_each(|x| if !x.is_zero()  {*x /= U256::from(amount_y)});
-                self.mm.make(curid.into(), amount_x.into(), amount_y.into(), bin_ids, distribution_x, distribution_y)
+                self.mm.make(curid.into(), amount_x.into(), amount_y.into(), bin_ids, distribution_x, distribution_y).tx
             },
             Execute::Move{from, to} => {
                 let mut amount_x = 0;
@@ -191,10 +751,7 @@ impl <M: Middleware> Executor <M> {
                 for (tick, x, y) in to {
                     amount_x += x;
                     amount_y += y;
-                    let tick = match tick {
-                        Tick::Delta(delta) => ((curid as i32) + delta) as u32,
-                        Tick::Exact(tick) => tick,
-                    };
+                    let tick = tick.id(curid);
                     ids_in.push(U256::from(tick));
                     self.bins_touched.insert(tick, true);
                     distribution_x.push(U256::from(x) * U256::exp10(18));
@@ -209,35 +766,35 @@ impl <M: Middleware> Executor <M> {
                 };
 
                 for (tick, amount) in from {
-                    assert!(amount != 0 && amount != u128::MAX);
-                    let tick = match tick {
-                        Tick::Exact(tick) => tick,
-                        Tick::Delta(delta) => panic!("can't cancel delta ticks"),
+                    let (tick, amount) = match (tick, amount) {
+                        (Tick::Exact(tick), Amount::Tokens(amount)) => (tick, amount),
+                        removal => unreachable!("removal {:?} wasn't resolved", removal),
                     };
+                    assert!(amount != 0 && amount != u128::MAX);
                     ids_out.push(U256::from(tick));
                     amounts.push(U256::from(amount));
                 }
 
-                self.mm.move_(meta, ids_in, distribution_x, distribution_y, ids_out, amounts)
+                self.mm.move_(meta, ids_in, distribution_x, distribution_y, ids_out, amounts).tx
             },
             Execute::Cancel(orders) => {
                 let mut ids = Vec::new();
                 let mut amounts = Vec::new();
                 for (tick, amount) in orders {
-                    assert!(amount != 0 && amount != u128::MAX);
-                    let tick = match tick {
-                        Tick::Exact(tick) => tick,
-                        Tick::Delta(delta) => panic!("can't cancel delta ticks"),
+                    let (tick, amount) = match (tick, amount) {
+                        (Tick::Exact(tick), Amount::Tokens(amount)) => (tick, amount),
+                        removal => unreachable!("removal {:?} wasn't resolved", removal),
                     };
+                    assert!(amount != 0 && amount != u128::MAX);
                     ids.push(U256::from(tick));
                     amounts.push(U256::from(amount));
                 }
-                self.mm.cancel(ids, amounts)
+                self.mm.cancel(ids, amounts).tx
             },
             Execute::Take{amt_in, amt_out, swap_for_y} => {
                 match swap_for_y {  
-                    true => self.mm.take(curid.into(), amt_in.into(), amt_out.into(), swap_for_y),
-                    false => self.mm.take(curid.into(), amt_out.into(), amt_in.into(), swap_for_y),
+                    true => self.mm.take(curid.into(), amt_in.into(), amt_out.into(), swap_for_y).tx,
+                    false => self.mm.take(curid.into(), amt_out.into(), amt_in.into(), swap_for_y).tx,
                 }
                 
             },
@@ -245,125 +802,486 @@ impl <M: Middleware> Executor <M> {
                 let mut ids = Vec::new();
                 let mut amounts = Vec::new();
                 for (tick, amount) in orders {
-                    assert!(amount != 0 && amount != u128::MAX);
-                    let tick = match tick {
-                        Tick::Exact(tick) => tick,
-                        Tick::Delta(delta) => panic!("can't cancel delta ticks"),
+                    let (tick, amount) = match (tick, amount) {
+                        (Tick::Exact(tick), Amount::Tokens(amount)) => (tick, amount),
+                        removal => unreachable!("removal {:?} wasn't resolved", removal),
                     };
+                    assert!(amount != 0 && amount != u128::MAX);
                     ids.push(U256::from(tick));
                     amounts.push(U256::from(amount));
                 }
                 match swap_for_y {
-                    true => self.mm.cancel_n_take(curid.into(), amt_in.into(), amt_out.into(), swap_for_y, ids, amounts),
-                    false => self.mm.cancel_n_take(curid.into(), amt_out.into(), amt_in.into(), swap_for_y, ids, amounts),
+                    true => self.mm.cancel_n_take(curid.into(), amt_in.into(), amt_out.into(), swap_for_y, ids, amounts).tx,
+                    false => self.mm.cancel_n_take(curid.into(), amt_out.into(), amt_in.into(), swap_for_y, ids, amounts).tx,
                 }
             },
-            Execute::Claim => {
-                let mut to_claim = self.get_liq_tokens(
-                    self.bins_touched.keys().cloned().collect()
-                ).await.iter().filter_map(
-                    |(id, tokens)| if *tokens == 0 {None} else {Some(U256::from(*id))}
-                ).collect::<Vec<U256>>();
-                to_claim.sort();
-                to_claim.dedup();
-                
-                let (total_x, total_y) = self.pair.pending_fees(self.address, to_claim.clone()).call().await.unwrap();
-                info!(to_claim = ?to_claim, total_x = ?total_x, total_y = ?total_y, "claiming");
-                // let bins = self.bins_touched.keys().cloned().map(|x| U256::from(x)).collect::<Vec<U256>>();
-                // let mut to_claim = Vec::new();
-                // let mut total_x = U256::zero(); 
-                // let mut total_y = U256::zero();
-                // for tick in bins {
-                //     let (x,y) = self.pair.pending_fees(self.address, vec![tick]).call().await.unwrap();
-                //     if !x.is_zero() || !y.is_zero() {
-                //         total_x += x;
-                //         total_y += y;
-                //         to_claim.push(tick);
-                //     }
-                // }
-                if total_x.as_u128() < self.config.token_x_dust && total_y.as_u128() < self.config.token_y_dust {
-                    return None;
-                }
+            todo => unreachable!("{} isn't a single MM call", todo.name()),
+        }
+    }
 
-                self.bins_touched.clear();
-                // let calldata = self.pair.collect_fees(self.address, to_claim).encode().unwrap();
-                let calldata = LBPairCalls::CollectFees(CollectFeesCall{account: self.address, ids: to_claim}).encode();
-                // let calldata = calldata.encode();
-                let calldata = vec![mm::Call{target: self.pair_address, call_data: calldata.into()}];
-                let call = self.mm.execute(calldata);
-                // let call = call.gas_price(self.client.get_gas_price().await.unwrap() * self.config.take_gas_price_scaling / 100);
-                let hash = call.send().await.unwrap();
-                info!(tx_hash = ?hash, "Submitted fee claim");
-                return self.deal_with_tx(*hash).await;
-            },
-            Execute::CheckGas => {
-                let contract_caller = self.client.default_sender().unwrap();
-                let gas_balance = self.client.get_balance(contract_caller, None).await.unwrap();
-                let to_refill = self.config.min_gas * 2;
-                if gas_balance.as_u128() < self.config.min_gas {
-                    info!(gas_balance = ?gas_balance, "Topping up gas");
-                    if self.weth.balance_of(contract_caller).call().await.unwrap().as_u128() < to_refill {
-                        let call = self.weth.transfer_from(self.address, contract_caller, to_refill.into());
-                        let hash = call.send().await.unwrap();
-                        info!(tx_hash = ?hash, "Submitted weth deposit");
-                        self.deal_with_tx(*hash).await;
-                    } else {
-                        let call = self.weth.withdraw(to_refill.into());
-                        let hash = call.send().await.unwrap();
-                        info!(tx_hash = ?hash, "Submitted weth withdraw");
-                        self.deal_with_tx(*hash).await;
-                    }
+    // Sends a Move or Cancel with the claim inside the same MM.execute, otherwise the action then the
+    // claim. Returns the last block either was mined in
+    pub async fn execute_with_claim(&mut self, todo: Execute, curid: u32, claim: Claimable) -> Option<u64> {
+        if matches!(todo, Execute::Move{..} | Execute::Cancel(_)) {
+            return self.execute(Execute::Batch(vec![todo, Execute::Claim]), curid).await;
+        }
+        let block = self.execute(todo, curid).await;
+        let claimed = self.claim(claim).await;
+        block.max(claimed)
+    }
+
+    // MM.execute is owner only, and the action is a call back into the contract, which must be whitelisted
+    async fn can_batch(&mut self) -> bool {
+        if let Some(can_batch) = self.can_batch {
+            return can_batch;
+        }
+        // Not cached on failure, so the next batch asks again
+        let owner = match self.mm.owner().call().await {
+            Ok(owner) => owner,
+            Err(e) => {
+                warn!(err = ?e.to_string(), "Failed to read the MM owner. Not batching");
+                return false;
+            }
+        };
+        let can_batch = match self.client.default_sender() == Some(owner) {
+            true => match self.mm.whitelisted_callers(self.address).call().await {
+                Ok(whitelisted) => whitelisted,
+                Err(e) => {
+                    warn!(err = ?e.to_string(), "Failed to read whether the MM whitelists itself. Not batching");
+                    return false;
                 }
+            },
+            false => false,
+        };
+        if !can_batch {
+            info!("Batching is off. It needs the owner to send and the contract to whitelist itself");
+        }
+        self.can_batch = Some(can_batch);
+        can_batch
+    }
+
+    // Pending fees once they are worth claim_gas_multiple times the gas to claim them
+    pub async fn claimable(&self) -> Option<Claimable> {
+        let claimable = self.pending_claim().await?;
+        if claimable.value < Portfolio::dec(self.config.claim_gas_multiple).saturating_mul(claimable.gas_cost) {
+            debug!(claimable = ?claimable, "Fees not worth claiming yet");
+            return None;
+        }
+        Some(claimable)
+    }
+
+    // Fees of the bins touched since the last claim that we still hold. None below dust
+    async fn pending_claim(&self) -> Option<Claimable> {
+        let mut ids = self.get_liq_tokens(
+            self.bins_touched.keys().cloned().collect()
+        ).await.keys().map(|id| U256::from(*id)).collect::<Vec<U256>>();
+        ids.sort();
+        if ids.is_empty() {
+            return None;
+        }
+        let (fees_x, fees_y) = self.pair.pending_fees(self.address, ids.clone()).call().await.unwrap();
+        let (fees_x, fees_y) = (fees_x.as_u128(), fees_y.as_u128());
+        if fees_x < self.config.token_x_dust && fees_y < self.config.token_y_dust {
+            return None;
+        }
+
+        let mark = self.mark.unwrap_or_else(|| {
+            warn!("No CEX mid to value token x fees at");
+            0.0
+        });
+        let (x_dec, y_dec) = self.get_decs().await;
+        let value = Portfolio::to_dec(fees_x.into(), x_dec as u32).saturating_mul(Portfolio::dec(mark))
+            .saturating_add(Portfolio::to_dec(fees_y.into(), y_dec as u32));
+        let mut tx = self.claim_call(ids.clone()).tx;
+        tx.set_from(self.client.default_sender().unwrap_or_default());
+        let gas = match self.client.estimate_gas(&tx, None).await {
+            Ok(gas) => gas.as_u64(),
+            Err(e) => {
+                warn!(error = %e, "Failed to estimate claim gas");
+                self.config.gas_constant
+            }
+        };
+        let gas_price = self.client.get_gas_price().await.unwrap();
+        let gas_cost = Portfolio::to_dec(U256::from(gas) * gas_price, 18).saturating_mul(Portfolio::dec(self.native_px(mark)));
+        Some(Claimable{ids, fees_x, fees_y, gas, value, gas_cost})
+    }
+
+    // Token y per native token. Without native_px, when neither token is wrapped native, gas is left out
+    // and anything above dust is worth claiming
+    fn native_px(&self, mark: f64) -> f64 {
+        match self.weth.address() {
+            weth if weth == self.x.address() => mark,
+            weth if weth == self.y.address() => 1.0,
+            _ => self.config.native_px.unwrap_or(0.0),
+        }
+    }
+
+    // Claims through getRewards, from the next caller in turn
+    pub async fn claim(&mut self, claimable: Claimable) -> Option<u64> {
+        self.action = "Claim";
+        info!(claimable = ?claimable, "Claiming fees");
+        let call = self.claim_call(claimable.ids.clone());
+        if self.dry_run {
+            self.simulate(&call).await;
+            return None;
+        }
+        let caller = self.next_caller();
+        let hash = match caller.send_transaction(call.tx.clone(), None).await {
+            Ok(pending) => *pending,
+            Err(e) => {
+                error!(err = ?e.to_string(), "Failed to submit fee claim");
+                metrics::TXS.with_label_values(&[&self.market, self.action, "submit_failed"]).inc();
                 return None;
             }
         };
-        // panic!("Killing");
-        match todo {
-            Execute::Make(_) | Execute::Move { .. } => {
-                if self.sent_ts.len() > self.config.tx_limit_5min {
-                    let earliest = self.sent_ts.first().unwrap();
-                    if earliest.elapsed() < Duration::from_secs(300) {
-                        warn!(sent_ts = ?self.sent_ts, "On Chain Tx limit reached");
-                        return None;
-                    } else {
-                        self.sent_ts.remove(0);
-                    }
+        info!(tx_hash = ?hash, "Submitted fee claim");
+        let block = self.deal_with_tx(hash).await;
+        if block.is_some() {
+            self.claimed(&claimable);
+        }
+        block
+    }
+
+    // Attributes claimed fees to PnL
+    fn claimed(&mut self, claimable: &Claimable) {
+        self.bins_touched.clear();
+        self.fees_x_claimed += claimable.fees_x;
+        self.fees_y_claimed += claimable.fees_y;
+        self.fee_pnl = self.fee_pnl.saturating_add(claimable.value);
+        metrics::FEE_PNL.with_label_values(&[&self.market]).set(self.fee_pnl.to_f64().unwrap_or_default());
+        info!(fees_x_claimed = self.fees_x_claimed, fees_y_claimed = self.fees_y_claimed, fee_pnl = %self.fee_pnl, gas_cost = %claimable.gas_cost, "Fees claimed");
+        self.persist();
+    }
+
+    // Unwraps amount of WETH into the caller's gas, first pulling whatever the caller lacks from the
+    // contract. Returns how much was unwrapped, checked against the caller's balances
+    async fn top_up(&mut self, caller: Arc<M>, amount: u128) -> u128 {
+        let contract_caller = caller.default_sender().unwrap();
+        let weth = WETH::new(self.weth.address(), caller.clone());
+        let held = weth.balance_of(contract_caller).call().await.unwrap().as_u128();
+        if held < amount {
+            let allowance = weth.allowance(self.address, contract_caller).call().await.unwrap().as_u128();
+            if allowance < amount - held {
+                error!(caller = ?contract_caller, allowance = allowance, "Caller can't pull the contract's WETH. Run owner approve-gas");
+                return 0;
+            }
+            let call = weth.transfer_from(self.address, contract_caller, (amount - held).into());
+            if self.dry_run {
+                self.simulate(&call).await;
+                return 0;
+            }
+            let hash = match call.send().await {
+                Ok(pending) => *pending,
+                Err(e) => {
+                    error!(error = %e, "Failed to submit weth pull");
+                    return 0;
                 }
-            },
+            };
+            info!(tx_hash = ?hash, "Submitted weth pull");
+            if self.deal_with_tx(hash).await.is_none() {
+                return 0;
+            }
+        }
+
+        let weth_before = weth.balance_of(contract_caller).call().await.unwrap().as_u128();
+        let native_before = self.client.get_balance(contract_caller, None).await.unwrap().as_u128();
+        let call = weth.withdraw(amount.into());
+        if self.dry_run {
+            self.simulate(&call).await;
+            return 0;
+        }
+        let hash = match call.send().await {
+            Ok(pending) => *pending,
+            Err(e) => {
+                error!(error = %e, "Failed to submit weth withdraw");
+                return 0;
+            }
+        };
+        info!(tx_hash = ?hash, "Submitted weth withdraw");
+        if self.deal_with_tx(hash).await.is_none() {
+            return 0;
+        }
+        // The caller pays the withdraw's gas, so native must move by amount less that
+        let gas_cost = match self.client.get_transaction_receipt(hash).await {
+            Ok(Some(receipt)) => (receipt.gas_used.unwrap_or_default() * receipt.effective_gas_price.unwrap_or_default()).as_u128(),
             _ => {
-                match todo {
-                    Execute::Take{..} | Execute::CancelNTake { .. } => {
-                        call = call.gas_price(self.client.get_gas_price().await.unwrap() * self.config.take_gas_price_scaling / 100);
+                error!(tx_hash = ?hash, "Failed to read the weth withdraw receipt");
+                return 0;
+            }
+        };
+        let weth_after = weth.balance_of(contract_caller).call().await.unwrap().as_u128();
+        let native_after = self.client.get_balance(contract_caller, None).await.unwrap().as_u128();
+        if weth_before.saturating_sub(weth_after) != amount || native_after != (native_before + amount).saturating_sub(gas_cost) {
+            error!(caller = ?contract_caller, weth_before = weth_before, weth_after = weth_after, native_before = native_before, native_after = native_after, gas_cost = gas_cost, "Unwrap didn't credit the caller");
+            return weth_before.saturating_sub(weth_after).min(amount);
+        }
+        amount
+    }
+
+    // Pulls every bin the executor holds, collects fees, and optionally sweeps both tokens and
+    // the native balance to the owner. Sweeps are owner only.
+    pub async fn kill(&mut self, sweep: bool, scan_range: u32) -> KillReport {
+        let mut report = KillReport::default();
+        self.action = "Kill";
+        let (_, _, active_id) = self.pair.get_reserves_and_id().call().await.unwrap();
+        report.active_id = active_id.as_u32();
+        let scan = (report.active_id.saturating_sub(scan_range)..=report.active_id + scan_range).collect::<Vec<u32>>();
+        let positions = self.get_liq_tokens(scan.clone()).await;
+        report.bins = positions.keys().cloned().collect();
+        report.bins.sort();
+        info!(active_id = report.active_id, bins = ?report.bins, "Kill switch: found bins");
+
+        // Collect fees before burning, while the bins are still ours
+        let ids = report.bins.iter().map(|id| U256::from(*id)).collect::<Vec<U256>>();
+        if !ids.is_empty() {
+            let (fees_x, fees_y) = self.pair.pending_fees(self.address, ids.clone()).call().await.unwrap();
+            report.fees_x = fees_x.as_u128();
+            report.fees_y = fees_y.as_u128();
+            if !fees_x.is_zero() || !fees_y.is_zero() {
+                let call = self.claim_call(ids);
+                match self.dry_run {
+                    true => self.simulate(&call).await,
+                    false => match call.send().await {
+                        Ok(hash) => {
+                            info!(tx_hash = ?hash, fees_x = report.fees_x, fees_y = report.fees_y, "Kill switch: submitted fee claim");
+                            report.fee_claim_block = self.deal_with_tx(*hash).await;
+                        },
+                        Err(e) => error!(err = ?e.to_string(), "Kill switch: failed to submit fee claim"),
                     },
-                    _ => {
-                        call = call.gas_price(self.client.get_gas_price().await.unwrap());
-                    }
-                }
-                if self.sent_ts.len() > self.config.tx_limit_5min {
-                    self.sent_ts = self.sent_ts.as_slice()[self.sent_ts.len()-self.config.tx_limit_5min..].to_vec();
                 }
             }
         }
-        
-        self.sent_ts.push(Instant::now());
-        let tx = call.tx.clone();
-        let call = call.gas(U256::from(self.config.gas_constant));
-        match call
-        .send().await {
-            Ok(hash) => {
-                // record the tx
-                info!(tx_hash = ?hash, "Submitted tx");
-                return self.deal_with_tx(*hash).await
+
+        let mut bins = positions.into_iter().collect::<Vec<(u32, u128)>>();
+        bins.sort();
+        for chunk in bins.chunks(KILL_CHUNK) {
+            let ids = chunk.iter().map(|(id, _)| *id).collect::<Vec<u32>>();
+            let call = self.mm.cancel(
+                chunk.iter().map(|(id, _)| U256::from(*id)).collect(),
+                chunk.iter().map(|(_, tokens)| U256::from(*tokens)).collect(),
+            );
+            let call = call.gas_price(self.client.get_gas_price().await.unwrap());
+            if self.dry_run {
+                self.simulate(&call).await;
+                report.cancels.push((ids, None));
+                continue;
             }
-            Err(err) => {
-                let err = err.to_string();
-                error!(err = ?err, tx = ?tx, "Failed to submit on chain tx");
+            let block = match call.send().await {
+                Ok(hash) => {
+                    info!(tx_hash = ?hash, bins = ?ids, "Kill switch: submitted cancel");
+                    self.deal_with_tx(*hash).await
+                },
+                Err(e) => {
+                    error!(err = ?e.to_string(), bins = ?ids, "Kill switch: failed to submit cancel");
+                    None
+                }
+            };
+            report.cancels.push((ids, block));
+        }
+
+        // Callers can pull liquidity but only the owner can sweep
+        let owner = self.mm.owner().call().await.unwrap();
+        if sweep && self.client.default_sender() != Some(owner) {
+            warn!(owner = ?owner, "Not running as the owner. Skipping the sweep");
+        } else if sweep {
+            report.sweeps = self.sweep(vec![self.x.address(), self.y.address(), Address::zero()]).await;
+        }
+
+        report.remaining_bins = self.get_liq_tokens(scan).await.keys().cloned().collect();
+        report.remaining_bins.sort();
+        if report.remaining_bins.is_empty() {
+            info!(report = ?report, "Kill switch complete");
+        } else {
+            error!(report = ?report, "Kill switch left liquidity behind");
+        }
+        report
+    }
+
+    // Sends each token's whole balance, or the native balance for the zero address, to the owner. Owner only
+    pub async fn sweep(&mut self, tokens: Vec<Address>) -> Vec<(Address, Option<u64>)> {
+        self.action = "Sweep";
+        let mut sweeps = Vec::new();
+        for token in tokens {
+            let call = self.mm.sweep(token);
+            if self.dry_run {
+                self.simulate(&call).await;
+                sweeps.push((token, None));
+                continue;
             }
+            let block = match call.send().await {
+                Ok(hash) => {
+                    info!(tx_hash = ?hash, token = ?token, "Submitted sweep");
+                    self.deal_with_tx(*hash).await
+                },
+                Err(e) => {
+                    error!(err = ?e.to_string(), token = ?token, "Failed to submit sweep");
+                    None
+                }
+            };
+            sweeps.push((token, block));
+        }
+        sweeps
+    }
+
+    fn owner_tx(&self, call: &OwnerCall) -> TypedTransaction {
+        match call.clone() {
+            OwnerCall::SetWhitelist{caller, allowed} => self.mm.set_whitelist(caller, allowed).tx,
+            OwnerCall::Sweep{token} => self.mm.sweep(token).tx,
+            OwnerCall::SweepAmount{token, amount} => self.mm.sweep_with_amt(token, amount).tx,
+            OwnerCall::GetRewards{ids} => self.mm.get_rewards(ids.into_iter().map(U256::from).collect()).tx,
+            OwnerCall::Execute{calls} => self.mm.execute(calls.into_iter().map(|(target, call_data)| mm::Call{target, call_data}).collect()).tx,
+            OwnerCall::ApproveGas{caller, amount} => {
+                let call_data = self.weth.approve(caller, amount).calldata().unwrap();
+                self.mm.execute(vec![mm::Call{target: self.weth.address(), call_data}]).tx
+            },
+        }
+    }
+
+    // Encodes and simulates an owner call without sending it
+    pub async fn preview(&self, call: OwnerCall) -> Preview {
+        let mut tx = self.owner_tx(&call);
+        let from = self.client.default_sender().unwrap_or_default();
+        tx.set_from(from);
+        let authorized = match call {
+            OwnerCall::GetRewards{..} => self.mm.whitelisted_callers(from).call().await.unwrap(),
+            _ => self.mm.owner().call().await.unwrap() == from,
         };
-        None
+        let simulation = self.client.call(&tx, None).await.map_err(|e| match e.as_error_response() {
+            Some(res) => res.message.clone(),
+            None => e.to_string(),
+        });
+        Preview {
+            call,
+            from,
+            to: self.address,
+            calldata: tx.data().cloned().unwrap_or_default(),
+            authorized,
+            gas: self.client.estimate_gas(&tx, None).await.ok(),
+            simulation,
+        }
+    }
+
+    // Previews the call, and only sends it once confirm accepts the preview. Refuses calls that
+    // would revert or that the sender isn't allowed to make. Dry runs stop after the preview.
+    pub async fn owner_call<F: FnOnce(&Preview) -> bool>(&mut self, call: OwnerCall, confirm: F) -> Result<Option<u64>, String> {
+        self.action = "Owner";
+        let preview = self.preview(call.clone()).await;
+        info!(preview = ?preview, "Owner call preview");
+        if !preview.authorized {
+            return Err(format!("{:?} isn't allowed to make this call", preview.from));
+        }
+        if let Err(reason) = preview.simulation.as_ref() {
+            return Err(format!("call would revert: {}", reason));
+        }
+        if !confirm(&preview) {
+            return Err("not confirmed".to_string());
+        }
+        if self.dry_run {
+            return Ok(None);
+        }
+        let mut tx = self.owner_tx(&call);
+        if let Some(gas) = preview.gas {
+            tx.set_gas(gas);
+        }
+        let hash = match self.client.send_transaction(tx, None).await {
+            Ok(pending) => *pending,
+            Err(e) => {
+                metrics::TXS.with_label_values(&[&self.market, self.action, "submit_failed"]).inc();
+                return Err(e.to_string());
+            }
+        };
+        info!(tx_hash = ?hash, call = ?call, "Submitted owner call");
+        Ok(self.deal_with_tx(hash).await)
+    }
+
+    // Runs the call against the latest block instead of sending it
+    async fn simulate<D: Detokenize>(&self, call: &ContractCall<M, D>) {
+        let res = call.call().await.map(|_| ()).map_err(|e| e.to_string());
+        self.dry_run_outcome(&call.tx, res);
+    }
+
+    // Runs the tx as sender against the latest block instead of sending it
+    async fn simulate_tx(&self, sender: &M, tx: &TypedTransaction) {
+        let res = sender.call(tx, None).await.map(|_| ()).map_err(|e| e.to_string());
+        self.dry_run_outcome(tx, res);
+    }
+
+    fn dry_run_outcome(&self, tx: &TypedTransaction, res: Result<(), String>) {
+        match res {
+            Ok(_) => {
+                info!(action = self.action, to = ?tx.to(), "Dry run: tx would succeed");
+                println!("[dry run] {} to {:?} would succeed", self.action, tx.to());
+            },
+            Err(e) => {
+                warn!(action = self.action, to = ?tx.to(), err = ?e, "Dry run: tx would revert");
+                println!("[dry run] {} to {:?} would revert: {}", self.action, tx.to(), e);
+            },
+        }
+        metrics::TXS.with_label_values(&[&self.market, self.action, "dry_run"]).inc();
     }
 
-    async fn deal_with_tx(&mut self,hash: TxHash) -> Option<u64> {
+    // getRewards is open to every whitelisted caller, unlike collecting through execute
+    fn claim_call(&self, ids: Vec<U256>) -> ContractCall<M, ()> {
+        self.mm.get_rewards(ids)
+    }
+
+    // Checks persisted state against the chain before adopting it
+    pub async fn restore(&mut self, saved: &MarketState) {
+        for hash in saved.pending_txs.iter() {
+            match self.client.get_transaction_receipt(*hash).await {
+                Ok(Some(receipt)) => info!(tx_hash = ?hash, status = ?receipt.status, block = ?receipt.block_number, "Pending tx from last run mined"),
+                Ok(None) => warn!(tx_hash = ?hash, "Pending tx from last run not found"),
+                Err(e) => error!(tx_hash = ?hash, err = ?e.to_string(), "Failed to check pending tx from last run"),
+            }
+        }
+
+        // Keep bins we still hold, or that still have fees to claim
+        let held = self.get_liq_tokens(saved.bins_touched.clone()).await;
+        for id in saved.bins_touched.iter() {
+            if held.contains_key(id) {
+                self.bins_touched.insert(*id, true);
+                continue;
+            }
+            let (fees_x, fees_y) = self.pair.pending_fees(self.address, vec![U256::from(*id)]).call().await.unwrap();
+            if !fees_x.is_zero() || !fees_y.is_zero() {
+                self.bins_touched.insert(*id, true);
+            }
+        }
+        self.sent_ts = saved.sent_ts.iter().map(|ts| state::from_millis(*ts)).collect();
+        self.fees_x_claimed = saved.fees_x_claimed;
+        self.fees_y_claimed = saved.fees_y_claimed;
+        self.fee_pnl = saved.fee_pnl;
+        info!(bins_touched = ?self.bins_touched.keys().collect::<Vec<&u32>>(), dropped = saved.bins_touched.len().saturating_sub(self.bins_touched.len()), "Restored executor state");
+        self.persist();
+    }
+
+    pub fn export(&self, state: &mut MarketState) {
+        state.bins_touched = self.bins_touched.keys().cloned().collect();
+        state.bins_touched.sort();
+        state.pending_txs = self.pending_txs.clone();
+        state.sent_ts = self.sent_ts.iter().map(|ts| state::to_millis(*ts)).collect();
+        state.fees_x_claimed = self.fees_x_claimed;
+        state.fees_y_claimed = self.fees_y_claimed;
+        state.fee_pnl = self.fee_pnl;
+    }
+
+    fn persist(&self) {
+        if let Some(store) = self.store.as_ref() {
+            store.update(|state| self.export(state));
+        }
+    }
+
+    // Tracks the tx as pending in the state store until it is mined or times out
+    async fn deal_with_tx(&mut self, hash: TxHash) -> Option<u64> {
+        self.pending_txs.push(hash);
+        self.persist();
+        let block = self.wait_for_tx(hash).await;
+        self.pending_txs.retain(|pending| *pending != hash);
+        self.persist();
+        block
+    }
+
+    async fn wait_for_tx(&mut self,hash: TxHash) -> Option<u64> {
         let start = Instant::now();
         loop {
             let receipt = self.client
@@ -372,13 +1290,20 @@ impl <M: Middleware> Executor <M> {
             .map_err(ContractError::MiddlewareError::<M>).unwrap();
             
             if let Some(receipt) = receipt {
+                metrics::TX_CONFIRMATION.with_label_values(&[&self.market, self.action]).observe(start.elapsed().as_secs_f64());
+                let cost = receipt.gas_used.unwrap_or_default() * receipt.effective_gas_price.unwrap_or_default();
+                self.treasury.lock().unwrap().record(&self.market, self.action, cost.as_u128(), receipt.status == Some(1.into()));
                 // Mined
                 if receipt.status == Some(1.into()) {
                     info!(tx_hash = ?hash, block = receipt.block_number.unwrap().as_u64(), "Tx mined successfully");
+                    metrics::TXS.with_label_values(&[&self.market, self.action, "mined"]).inc();
                     self.consecutive_failures = 0;
                     return Some(receipt.block_number.unwrap().as_u64());
                 } else {
-                    error!(txhash = ?hash, "Transaction failed");
+                    let reason = self.revert_reason(hash, receipt.block_number.unwrap().as_u64()).await;
+                    error!(txhash = ?hash, reason = %reason, "Transaction failed");
+                    metrics::TXS.with_label_values(&[&self.market, self.action, "reverted"]).inc();
+                    metrics::REVERTS.with_label_values(&[&self.market, revert_label(&reason)]).inc();
                     self.consecutive_failures += 1;
                     if self.consecutive_failures > 20 {
                         panic!("Too many consecutive failures");
@@ -389,6 +1314,7 @@ impl <M: Middleware> Executor <M> {
             }
             if start.elapsed() > Duration::from_secs(60) {
                 error!(txhash = ?hash, "Transaction timed out");
+                metrics::TXS.with_label_values(&[&self.market, self.action, "timeout"]).inc();
                 break;
             }
             debug!(tx_hash = ?hash, "Waiting for tx to be mined");
@@ -397,4 +1323,241 @@ impl <M: Middleware> Executor <M> {
         None
         
     }
-}
\ No newline at end of file
+
+    // Replays a reverted tx on top of the block before it. Custom errors are reported by selector
+    async fn revert_reason(&self, hash: TxHash, block: u64) -> String {
+        let tx = match self.client.get_transaction(hash).await {
+            Ok(Some(tx)) => tx,
+            _ => return "unknown".to_string(),
+        };
+        let call: TypedTransaction = (&tx).into();
+        match self.client.call(&call, Some((block - 1).into())).await {
+            Ok(_) => "not reproduced".to_string(),
+            Err(e) => match e.as_error_response() {
+                Some(res) => match res.data.as_ref().and_then(|data| data.as_str()) {
+                    Some(data) if data.len() >= 10 && !data.starts_with("0x08c379a0") => data[..10].to_string(),
+                    _ => res.message.clone(),
+                },
+                None => "unknown".to_string(),
+            },
+        }
+    }
+}
+
+// Buckets a revert reason into a fixed set of metric labels so the counter's cardinality stays bounded.
+// MM.sol's own reasons keep their name, anything else is grouped by kind
+fn revert_label(reason: &str) -> &'static str {
+    match reason.trim_start_matches("execution reverted").trim_start_matches(':').trim() {
+        "ID" => "ID",
+        "X_S" => "X_S",
+        "Y_S" => "Y_S",
+        // Bare requires, i.e. the onlyOwner and onlyWhitelist modifiers
+        "" => "unauthorized",
+        "Multicall aggregate: call failed" => "multicall",
+        "Failed to send Ether" => "send_ether",
+        "not reproduced" => "not_reproduced",
+        "unknown" => "unknown",
+        r if r.len() == 10 && r.starts_with("0x") => "custom_error",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{self, Token};
+    use ethers::providers::{JsonRpcError, MockProvider, MockResponse};
+    use serde_json::Value;
+    use std::cell::Cell;
+
+    use crate::treasury::Spend;
+
+    const OWNER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    fn value<T: Serialize>(value: T) -> MockResponse {
+        MockResponse::Value(serde_json::to_value(value).unwrap())
+    }
+
+    fn returns(tokens: &[Token]) -> MockResponse {
+        value(Bytes::from(abi::encode(tokens)))
+    }
+
+    fn reverts(reason: &str) -> MockResponse {
+        MockResponse::Error(JsonRpcError{code: 3, message: format!("execution reverted: {}", reason), data: None})
+    }
+
+    fn mined(block: u64) -> MockResponse {
+        value(TransactionReceipt {
+            status: Some(1.into()),
+            block_number: Some(block.into()),
+            gas_used: Some(100_000.into()),
+            effective_gas_price: Some(1_000_000_000.into()),
+            ..Default::default()
+        })
+    }
+
+    // What filling in EIP-1559 fees reads
+    fn fees() -> Vec<MockResponse> {
+        vec![
+            value(Block::<TxHash>{base_fee_per_gas: Some(1_000_000_000.into()), ..Default::default()}),
+            value(FeeHistory{base_fee_per_gas: vec![1_000_000_000.into()], gas_used_ratio: vec![0.5], oldest_block: 1.into(), reward: vec![vec![1_000_000_000.into()]]}),
+        ]
+    }
+
+    // Executor sending from sender, with the RPC answering responses in order after the constructor's reads
+    async fn executor(market: &str, sender: Address, responses: Vec<MockResponse>) -> Executor<Provider<MockProvider>> {
+        let (provider, mock) = Provider::mocked();
+        let constructor = [Address::repeat_byte(1), Address::repeat_byte(2), Address::repeat_byte(3)].map(|address| returns(&[Token::Address(address)]));
+        // Mocked responses are popped last in, first out
+        for response in constructor.into_iter().chain(responses).rev() {
+            mock.push_response(response);
+        }
+        let client = Arc::new(provider.with_sender(sender));
+        let mut executor = Executor::new(client, Address::repeat_byte(4), Address::repeat_byte(5), portfolio::tests::config()).await;
+        executor.market = market.to_string();
+        executor
+    }
+
+    fn batch() -> Execute {
+        Execute::Batch(vec![
+            Execute::Cancel(vec![(Tick::Exact(1 << 23), Amount::Tokens(5))]),
+            Execute::Take{amt_in: 10, amt_out: 9, swap_for_y: true},
+        ])
+    }
+
+    #[test]
+    fn revert_labels_are_bounded() {
+        assert_eq!(revert_label("execution reverted: ID"), "ID");
+        assert_eq!(revert_label("execution reverted: X_S"), "X_S");
+        assert_eq!(revert_label("execution reverted: Y_S"), "Y_S");
+        assert_eq!(revert_label("execution reverted"), "unauthorized");
+        assert_eq!(revert_label("0x8a4c8e1b"), "custom_error");
+        assert_eq!(revert_label("not reproduced"), "not_reproduced");
+        assert_eq!(revert_label("execution reverted: slippage 0x1234 at block 99"), "other");
+    }
+
+    #[tokio::test]
+    async fn batch_is_sent_and_accounted_as_one_tx() {
+        let owner = OWNER.parse::<Address>().unwrap();
+        let mut executor = executor("batch", owner, vec![
+            returns(&[Token::Address(owner)]),
+            returns(&[Token::Bool(true)]),
+            value(U256::from(1_000_000_000)),
+            value(H256::repeat_byte(9)),
+            mined(7),
+        ]).await;
+
+        assert_eq!(executor.execute(batch(), 1 << 23).await, Some(7));
+        assert_eq!(executor.sent_ts.len(), 1);
+        let spent = executor.treasury.lock().unwrap().report("batch").spent;
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent["Batch"], Spend{txs: 1, mined: 1, wei: 100_000 * 1_000_000_000});
+        assert_eq!(metrics::TXS.with_label_values(&["batch", "Batch", "mined"]).get(), 1);
+        assert_eq!(metrics::TXS.with_label_values(&["batch", "Batch", "unbatched"]).get(), 0);
+    }
+
+    #[tokio::test]
+    async fn batch_from_non_owner_is_sent_action_by_action() {
+        let mut executor = executor("unbatched", Address::repeat_byte(6), vec![
+            returns(&[Token::Address(OWNER.parse().unwrap())]),
+            value(U256::from(1_000_000_000)),
+            value(H256::repeat_byte(9)),
+            mined(7),
+            value(U256::from(1_000_000_000)),
+            value(H256::repeat_byte(10)),
+            mined(8),
+        ]).await;
+
+        assert_eq!(executor.execute(batch(), 1 << 23).await, Some(8));
+        assert_eq!(executor.sent_ts.len(), 2);
+        let spent = executor.treasury.lock().unwrap().report("unbatched").spent;
+        assert_eq!(spent["Cancel"].txs, 1);
+        assert_eq!(spent["Take"].txs, 1);
+        assert!(!spent.contains_key("Batch"));
+        assert_eq!(metrics::TXS.with_label_values(&["unbatched", "Batch", "unbatched"]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn preview_encodes_and_simulates_owner_calls() {
+        let owner = OWNER.parse::<Address>().unwrap();
+        let caller = Address::repeat_byte(7);
+        let executor = executor("preview", owner, vec![
+            returns(&[Token::Address(owner)]),
+            value(Bytes::default()),
+            value(U256::from(50_000)),
+        ]).await;
+
+        let call = OwnerCall::SetWhitelist{caller, allowed: true};
+        let preview = executor.preview(call.clone()).await;
+        assert_eq!(preview, Preview {
+            call,
+            from: owner,
+            to: executor.address,
+            calldata: executor.mm.set_whitelist(caller, true).calldata().unwrap(),
+            authorized: true,
+            gas: Some(50_000.into()),
+            simulation: Ok(Bytes::default()),
+        });
+    }
+
+    #[tokio::test]
+    async fn owner_call_sends_once_confirmed() {
+        let owner = OWNER.parse::<Address>().unwrap();
+        let mut responses = vec![
+            returns(&[Token::Address(owner)]),
+            value(Bytes::default()),
+            value(U256::from(50_000)),
+        ];
+        responses.extend(fees());
+        responses.extend([value(H256::repeat_byte(9)), mined(7)]);
+        let mut executor = executor("owner", owner, responses).await;
+
+        let token = Address::repeat_byte(8);
+        let calldata = executor.mm.sweep(token).calldata().unwrap();
+        let confirmed = Cell::new(false);
+        let res = executor.owner_call(OwnerCall::Sweep{token}, |preview| {
+            assert_eq!(preview.calldata, calldata);
+            confirmed.set(true);
+            true
+        }).await;
+        assert_eq!(res, Ok(Some(7)));
+        assert!(confirmed.get());
+        let spent = executor.treasury.lock().unwrap().report("owner").spent;
+        assert_eq!(spent["Owner"].txs, 1);
+    }
+
+    #[tokio::test]
+    async fn owner_call_refuses_unauthorized_and_reverting_calls() {
+        let owner = OWNER.parse::<Address>().unwrap();
+        let other = Address::repeat_byte(6);
+        let mut executor = executor("refused", other, vec![
+            returns(&[Token::Address(owner)]),
+            reverts("Ownable: caller is not the owner"),
+            reverts("Ownable: caller is not the owner"),
+            returns(&[Token::Bool(true)]),
+            reverts("no fees"),
+            reverts("no fees"),
+        ]).await;
+
+        let calls = vec![(Address::repeat_byte(8), Bytes::from(vec![1, 2, 3]))];
+        let res = executor.owner_call(OwnerCall::Execute{calls}, |_| panic!("asked to confirm an unauthorized call")).await;
+        assert_eq!(res, Err(format!("{:?} isn't allowed to make this call", other)));
+
+        // Open to whitelisted callers, but simulated first
+        let res = executor.owner_call(OwnerCall::GetRewards{ids: vec![1 << 23]}, |_| panic!("asked to confirm a reverting call")).await;
+        assert_eq!(res, Err("call would revert: execution reverted: no fees".to_string()));
+    }
+
+    #[tokio::test]
+    async fn owner_call_stops_after_preview_on_dry_run_or_refusal() {
+        let owner = OWNER.parse::<Address>().unwrap();
+        let preview = || [returns(&[Token::Address(owner)]), value(Bytes::default()), value(U256::from(50_000))];
+        let mut executor = executor("dry-run", owner, preview().into_iter().chain(preview()).collect()).await;
+
+        let call = OwnerCall::SweepAmount{token: Address::zero(), amount: 1000.into()};
+        assert_eq!(executor.owner_call(call.clone(), |_| false).await, Err("not confirmed".to_string()));
+        executor.dry_run = true;
+        assert_eq!(executor.owner_call(call, |_| true).await, Ok(None));
+        assert!(executor.treasury.lock().unwrap().report("dry-run").spent.is_empty());
+    }
+}
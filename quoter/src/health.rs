@@ -0,0 +1,382 @@
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{debug, info, warn, error};
+
+use crate::{metrics, Config};
+
+// How often markets are checked and the heartbeat is sent
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct HealthConfig {
+    // A market is degraded once any of these is older than its limit
+    pub max_loop_age_secs: u64,
+    pub max_cex_age_secs: u64,
+    pub max_dex_age_secs: u64,
+    // Notified whenever a market's issues change
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_loop_age_secs: 30,
+            max_cex_age_secs: 60,
+            max_dex_age_secs: 60,
+            webhooks: Vec::new(),
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum Webhook {
+    Slack{url: String},
+    Discord{url: String},
+    // Events API v2. Degraded markets trigger an incident that is resolved on recovery
+    PagerDuty{url: String, routing_key: String},
+    // Posts the report as JSON
+    Generic{url: String},
+}
+
+// Latest state reported by a market's loop
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Beat {
+    pub at: Instant,
+    pub last_cex: Instant,
+    pub last_dex: Instant,
+    pub dex_block: u64,
+    pub gas_balance: Option<u128>,
+    pub min_gas: u128,
+    // Contract WETH left to top up gas from, and the level to alert below
+    pub reserve: Option<u128>,
+    pub min_reserve: Option<u128>,
+    pub topup_capped: bool,
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub enum Issue {
+    LoopStalled{secs: u64},
+    CexStale{secs: u64},
+    DexStale{secs: u64, block: u64},
+    LowGas{balance: u128, min_gas: u128},
+    LowReserve{reserve: u128, min_reserve: u128},
+    TopUpCapped,
+}
+
+impl Issue {
+    fn kind(&self) -> &'static str {
+        match self {
+            Issue::LoopStalled{..} => "LoopStalled",
+            Issue::CexStale{..} => "CexStale",
+            Issue::DexStale{..} => "DexStale",
+            Issue::LowGas{..} => "LowGas",
+            Issue::LowReserve{..} => "LowReserve",
+            Issue::TopUpCapped => "TopUpCapped",
+        }
+    }
+}
+
+// Markets report here every loop iteration
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    beats: Arc<Mutex<HashMap<String, Beat>>>,
+}
+
+impl Registry {
+    pub fn beat(&self, market: &str, beat: Beat) {
+        self.beats.lock().unwrap().insert(market.to_string(), beat);
+    }
+}
+
+pub fn check(config: &HealthConfig, beat: &Beat) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let age = |instant: Instant| instant.elapsed().as_secs();
+    if age(beat.at) > config.max_loop_age_secs {
+        issues.push(Issue::LoopStalled{secs: age(beat.at)});
+    }
+    if age(beat.last_cex) > config.max_cex_age_secs {
+        issues.push(Issue::CexStale{secs: age(beat.last_cex)});
+    }
+    if age(beat.last_dex) > config.max_dex_age_secs {
+        issues.push(Issue::DexStale{secs: age(beat.last_dex), block: beat.dex_block});
+    }
+    if let Some(balance) = beat.gas_balance {
+        if balance < beat.min_gas {
+            issues.push(Issue::LowGas{balance, min_gas: beat.min_gas});
+        }
+    }
+    if let (Some(reserve), Some(min_reserve)) = (beat.reserve, beat.min_reserve) {
+        if reserve < min_reserve {
+            issues.push(Issue::LowReserve{reserve, min_reserve});
+        }
+    }
+    if beat.topup_capped {
+        issues.push(Issue::TopUpCapped);
+    }
+    issues
+}
+
+// Replaces the plain heartbeat. The heartbeat url is only pinged while every market is healthy,
+// and webhooks are told whenever a market's set of issues changes. Limits, webhooks and the
+// heartbeat url are read from the latest config on every check.
+pub fn spawn_monitor(config_rx: watch::Receiver<Arc<Config>>, registry: Registry, markets: Vec<String>) {
+    tokio::spawn(async move {
+        let mut monitor = Monitor::new(markets);
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let (config, heartbeat) = {
+                let latest = config_rx.borrow();
+                (latest.health.clone(), latest.heartbeat.clone())
+            };
+            let beats = registry.beats.lock().unwrap().clone();
+            monitor.tick(&config, &heartbeat, &beats).await;
+        }
+    });
+}
+
+// Remembers the issues last reported for each market, so webhooks only hear about changes
+struct Monitor {
+    client: reqwest::Client,
+    started: Instant,
+    markets: Vec<String>,
+    reported: HashMap<String, Vec<&'static str>>,
+}
+
+impl Monitor {
+    fn new(markets: Vec<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            started: Instant::now(),
+            markets,
+            reported: HashMap::new(),
+        }
+    }
+
+    // Returns whether every market is healthy
+    async fn tick(&mut self, config: &HealthConfig, heartbeat: &str, beats: &HashMap<String, Beat>) -> bool {
+        let mut healthy = true;
+        for market in self.markets.iter() {
+            let issues = match beats.get(market) {
+                Some(beat) => check(config, beat),
+                // Startup reads the chain before the loop begins
+                None if self.started.elapsed().as_secs() <= config.max_loop_age_secs * 2 => Vec::new(),
+                None => vec![Issue::LoopStalled{secs: self.started.elapsed().as_secs()}],
+            };
+            healthy &= issues.is_empty();
+            metrics::HEALTHY.with_label_values(&[market]).set(if issues.is_empty() { 1.0 } else { 0.0 });
+
+            let kinds = issues.iter().map(|issue| issue.kind()).collect::<Vec<&'static str>>();
+            if self.reported.get(market).map_or(!kinds.is_empty(), |previous| *previous != kinds) {
+                match issues.is_empty() {
+                    true => info!(market = %market, "Market healthy"),
+                    false => warn!(market = %market, issues = ?issues, "Market degraded"),
+                }
+                for webhook in config.webhooks.iter() {
+                    notify(&self.client, webhook, market, &issues).await;
+                }
+                self.reported.insert(market.clone(), kinds);
+            }
+        }
+        if healthy {
+            if let Err(e) = self.client.get(heartbeat).send().await {
+                warn!(error = ?e, "Failed to send heartbeat");
+            }
+        }
+        healthy
+    }
+}
+
+fn payload(webhook: &Webhook, market: &str, issues: &[Issue]) -> (String, Value) {
+    let summary = match issues.is_empty() {
+        true => format!("quoter market {} recovered", market),
+        false => format!("quoter market {} degraded: {}", market, issues.iter().map(|issue| format!("{:?}", issue)).collect::<Vec<String>>().join(", ")),
+    };
+    match webhook {
+        Webhook::Slack{url} => (url.clone(), json!({"text": summary})),
+        Webhook::Discord{url} => (url.clone(), json!({"content": summary})),
+        Webhook::PagerDuty{url, routing_key} => (url.clone(), json!({
+            "routing_key": routing_key,
+            "event_action": if issues.is_empty() { "resolve" } else { "trigger" },
+            "dedup_key": format!("quoter-{}", market),
+            "payload": {
+                "summary": summary,
+                "source": "quoter",
+                "severity": "warning",
+                "custom_details": {"market": market, "issues": issues},
+            },
+        })),
+        Webhook::Generic{url} => (url.clone(), json!({"market": market, "healthy": issues.is_empty(), "issues": issues, "summary": summary})),
+    }
+}
+
+async fn notify(client: &reqwest::Client, webhook: &Webhook, market: &str, issues: &[Issue]) {
+    let (url, body) = payload(webhook, market, issues);
+    let res = client.post(&url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send().await;
+    match res {
+        Ok(res) if res.status().is_success() => debug!(url = %url, "Sent health webhook"),
+        Ok(res) => error!(url = %url, status = %res.status(), "Health webhook rejected"),
+        Err(e) => error!(url = %url, error = ?e, "Failed to send health webhook"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, Server};
+    use std::convert::Infallible;
+
+    // Local stand-in for Slack, Discord, PagerDuty and the heartbeat. Records every JSON body posted to it
+    #[derive(Clone, Debug, Default)]
+    struct MockWebhook {
+        received: Arc<Mutex<Vec<Value>>>,
+        pings: Arc<Mutex<usize>>,
+    }
+
+    impl MockWebhook {
+        // Serves on a free local port and returns its url
+        fn spawn(&self) -> String {
+            let mock = self.clone();
+            let make_svc = make_service_fn(move |_| {
+                let mock = mock.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let mock = mock.clone();
+                        async move {
+                            if req.method() == Method::GET {
+                                *mock.pings.lock().unwrap() += 1;
+                            } else {
+                                let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                                mock.received.lock().unwrap().push(serde_json::from_slice::<Value>(&bytes).unwrap_or(Value::Null));
+                            }
+                            Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+            let url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            url
+        }
+
+        fn take(&self) -> Vec<Value> {
+            std::mem::take(&mut *self.received.lock().unwrap())
+        }
+    }
+
+    fn ago(secs: u64) -> Instant {
+        Instant::now() - Duration::from_secs(secs)
+    }
+
+    fn healthy_beat() -> Beat {
+        Beat {
+            at: Instant::now(),
+            last_cex: Instant::now(),
+            last_dex: Instant::now(),
+            dex_block: 100,
+            gas_balance: Some(10),
+            min_gas: 5,
+            reserve: Some(10),
+            min_reserve: Some(5),
+            topup_capped: false,
+        }
+    }
+
+    #[test]
+    fn healthy_beat_has_no_issues() {
+        assert_eq!(check(&HealthConfig::default(), &healthy_beat()), vec![]);
+    }
+
+    #[test]
+    fn raises_every_issue_of_a_degraded_beat() {
+        let beat = Beat {
+            at: ago(31),
+            last_cex: ago(61),
+            last_dex: ago(62),
+            dex_block: 100,
+            gas_balance: Some(4),
+            min_gas: 5,
+            reserve: Some(3),
+            min_reserve: Some(5),
+            topup_capped: true,
+        };
+        assert_eq!(check(&HealthConfig::default(), &beat), vec![
+            Issue::LoopStalled{secs: 31},
+            Issue::CexStale{secs: 61},
+            Issue::DexStale{secs: 62, block: 100},
+            Issue::LowGas{balance: 4, min_gas: 5},
+            Issue::LowReserve{reserve: 3, min_reserve: 5},
+            Issue::TopUpCapped,
+        ]);
+    }
+
+    #[test]
+    fn unknown_balances_raise_nothing() {
+        let beat = Beat { gas_balance: None, reserve: None, ..healthy_beat() };
+        assert_eq!(check(&HealthConfig::default(), &beat), vec![]);
+        let beat = Beat { reserve: Some(0), min_reserve: None, ..healthy_beat() };
+        assert_eq!(check(&HealthConfig::default(), &beat), vec![]);
+    }
+
+    #[tokio::test]
+    async fn monitor_notifies_webhooks_on_changes_and_pings_while_healthy() {
+        let mock = MockWebhook::default();
+        let url = mock.spawn();
+        let config = HealthConfig {
+            webhooks: vec![
+                Webhook::Slack{url: url.clone()},
+                Webhook::PagerDuty{url: url.clone(), routing_key: "key".to_string()},
+                Webhook::Generic{url: url.clone()},
+            ],
+            ..HealthConfig::default()
+        };
+        let mut monitor = Monitor::new(vec!["avax".to_string()]);
+        let mut beats = HashMap::from([("avax".to_string(), healthy_beat())]);
+
+        assert!(monitor.tick(&config, &url, &beats).await);
+        assert_eq!(mock.take(), Vec::<Value>::new());
+        assert_eq!(*mock.pings.lock().unwrap(), 1);
+
+        beats.insert("avax".to_string(), Beat { gas_balance: Some(1), ..healthy_beat() });
+        assert!(!monitor.tick(&config, &url, &beats).await);
+        let received = mock.take();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0], json!({"text": "quoter market avax degraded: LowGas { balance: 1, min_gas: 5 }"}));
+        assert_eq!(received[1]["routing_key"], "key");
+        assert_eq!(received[1]["event_action"], "trigger");
+        assert_eq!(received[1]["dedup_key"], "quoter-avax");
+        assert_eq!(received[1]["payload"]["custom_details"]["issues"], json!([{"LowGas": {"balance": 1, "min_gas": 5}}]));
+        assert_eq!(received[2]["healthy"], false);
+        assert_eq!(*mock.pings.lock().unwrap(), 1);
+
+        // Still degraded the same way, so nothing new is sent
+        assert!(!monitor.tick(&config, &url, &beats).await);
+        assert_eq!(mock.take(), Vec::<Value>::new());
+
+        beats.insert("avax".to_string(), healthy_beat());
+        assert!(monitor.tick(&config, &url, &beats).await);
+        let received = mock.take();
+        assert_eq!(received.len(), 3);
+        assert_eq!(received[0], json!({"text": "quoter market avax recovered"}));
+        assert_eq!(received[1]["event_action"], "resolve");
+        assert_eq!(received[2]["healthy"], true);
+        assert_eq!(*mock.pings.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn discord_payload_uses_content() {
+        let (url, body) = payload(&Webhook::Discord{url: "http://discord".to_string()}, "avax", &[Issue::TopUpCapped]);
+        assert_eq!(url, "http://discord");
+        assert_eq!(body, json!({"content": "quoter market avax degraded: TopUpCapped"}));
+    }
+}
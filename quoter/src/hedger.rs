@@ -0,0 +1,411 @@
+use binance::api::*;
+use binance::account::*;
+use rust_decimal::Decimal;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, info, warn, error};
+
+use crate::cex_feed::CexData;
+use crate::signer::{self, SignerError};
+
+// Wait after a failed hedge order, doubling on each failure in a row up to the max
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct HedgeConfig {
+    // Binance symbol trading token x, e.g. AVAXUSDT. PnL is marked with the CEX feed, so
+    // this should be quoted in the same currency as cex_param
+    pub symbol: String,
+    // Where the Binance API and secret keys are read from. Only needed when not paper trading
+    #[serde(default)]
+    pub api_key: Option<CredentialSource>,
+    #[serde(default)]
+    pub secret_key: Option<CredentialSource>,
+
+    // Hedge once net delta of token x exceeds this, in token x units
+    pub threshold: u128,
+    // Exchange lot size in whole tokens. Orders are rounded down to a multiple of this
+    pub qty_step: f64,
+    // Taker fee charged by the local matching engine
+    #[serde(default)]
+    pub fee_bps: f64,
+    // Send orders to the local matching engine instead of Binance
+    #[serde(default)]
+    pub paper: bool,
+}
+
+// Where a venue credential comes from. Keeps it out of the config file, which is hot reloaded and shared,
+// so only the source is ever logged or diffed
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum CredentialSource {
+    Env{var: String},
+    // File only its owner can read or write (mode 600 or stricter)
+    File{path: String},
+}
+
+impl CredentialSource {
+    pub fn load(&self) -> Result<String, SignerError> {
+        let value = match self {
+            CredentialSource::Env{var} => std::env::var(var).map_err(|_| SignerError::MissingEnv(var.clone()))?,
+            CredentialSource::File{path} => {
+                signer::check_permissions(path)?;
+                fs::read_to_string(path).map_err(|source| SignerError::Read{path: path.clone(), source})?
+            },
+        };
+        Ok(value.trim().to_string())
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct HedgeFill {
+    pub side: Side,
+    // Whole tokens of x filled and quote paid or received, as reported by the venue
+    pub qty: f64,
+    pub quote_qty: f64,
+    // Signed change in token x units. Positive when bought
+    pub x_delta: i128,
+    // Signed change in quote. Positive when sold
+    pub quote_delta: Decimal,
+}
+
+#[derive(Error, Debug)]
+pub enum HedgeError {
+    #[error("order rejected: {0}")]
+    Rejected(String),
+    #[error("no liquidity on the {0:?} side")]
+    NoLiquidity(Side),
+    #[error("credentials: {0}")]
+    Credentials(String),
+}
+
+// Where hedge orders are sent. Quantities are in whole tokens.
+pub trait HedgeVenue {
+    // Returns (filled qty, quote qty)
+    fn market_order(&mut self, symbol: &str, side: Side, qty: f64) -> Result<(f64, f64), HedgeError>;
+
+    // Latest CEX book. Only used by simulated venues
+    fn on_book(&mut self, _book: &CexData) {}
+}
+
+// The Binance client is blocking, so it is built and dropped off the async runtime
+pub struct BinanceVenue {
+    account: Option<Account>,
+}
+
+impl BinanceVenue {
+    pub fn new(api_key: String, secret_key: String) -> Self {
+        Self {
+            account: Some(tokio::task::block_in_place(|| Binance::new(Some(api_key), Some(secret_key)))),
+        }
+    }
+}
+
+impl Drop for BinanceVenue {
+    fn drop(&mut self) {
+        let account = self.account.take();
+        tokio::task::block_in_place(|| drop(account));
+    }
+}
+
+impl HedgeVenue for BinanceVenue {
+    fn market_order(&mut self, symbol: &str, side: Side, qty: f64) -> Result<(f64, f64), HedgeError> {
+        let account = self.account.as_ref().unwrap();
+        let res = match side {
+            Side::Buy => account.market_buy(symbol, qty),
+            Side::Sell => account.market_sell(symbol, qty),
+        };
+        match res {
+            Ok(tx) => {
+                debug!(tx = ?tx, "Binance order");
+                Ok((tx.executed_qty, tx.cummulative_quote_qty))
+            },
+            Err(e) => Err(HedgeError::Rejected(e.to_string())),
+        }
+    }
+}
+
+// Local matching engine. Market orders walk a book of (px, qty) levels, best first.
+// A NaN size from a VWAP feed is treated as unlimited depth.
+#[derive(Clone, Debug, Default)]
+pub struct MatchingEngine {
+    pub bids: Vec<(f64, f64)>,
+    pub asks: Vec<(f64, f64)>,
+    pub fee_bps: f64,
+}
+
+impl MatchingEngine {
+    pub fn new(fee_bps: f64) -> Self {
+        Self {
+            fee_bps,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_book(&mut self, bids: Vec<(f64, f64)>, asks: Vec<(f64, f64)>) {
+        self.bids = bids;
+        self.asks = asks;
+    }
+}
+
+impl HedgeVenue for MatchingEngine {
+    fn market_order(&mut self, _symbol: &str, side: Side, qty: f64) -> Result<(f64, f64), HedgeError> {
+        let levels = match side {
+            Side::Buy => &mut self.asks,
+            Side::Sell => &mut self.bids,
+        };
+        let mut filled = 0.0;
+        let mut quote = 0.0;
+        for level in levels.iter_mut() {
+            if filled >= qty {
+                break;
+            }
+            let size = if level.1.is_nan() { qty - filled } else { level.1.min(qty - filled) };
+            filled += size;
+            quote += size * level.0;
+            if !level.1.is_nan() {
+                level.1 -= size;
+            }
+        }
+        levels.retain(|(_, sz)| sz.is_nan() || *sz > 0.0);
+        if filled == 0.0 {
+            return Err(HedgeError::NoLiquidity(side));
+        }
+        let fee = quote * self.fee_bps / 10000.0;
+        match side {
+            Side::Buy => Ok((filled, quote + fee)),
+            Side::Sell => Ok((filled, quote - fee)),
+        }
+    }
+
+    fn on_book(&mut self, book: &CexData) {
+        self.set_book(vec![(book.bid_px, book.bid_sz)], vec![(book.ask_px, book.ask_sz)]);
+    }
+}
+
+pub struct Hedger {
+    pub config: HedgeConfig,
+    venue: Box<dyn HedgeVenue + Send>,
+    x_decimals: usize,
+    // Failed orders in a row, and when the next one may be sent
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Hedger {
+    pub fn new(config: HedgeConfig, x_decimals: usize) -> Result<Self, HedgeError> {
+        let venue: Box<dyn HedgeVenue + Send> = if config.paper {
+            Box::new(MatchingEngine::new(config.fee_bps))
+        } else {
+            let (api_key, secret_key) = match (config.api_key.as_ref(), config.secret_key.as_ref()) {
+                (Some(api_key), Some(secret_key)) => (api_key.load(), secret_key.load()),
+                _ => return Err(HedgeError::Credentials("api_key and secret_key are required unless paper is set".to_string())),
+            };
+            let api_key = api_key.map_err(|e| HedgeError::Credentials(format!("api_key: {}", e)))?;
+            let secret_key = secret_key.map_err(|e| HedgeError::Credentials(format!("secret_key: {}", e)))?;
+            Box::new(BinanceVenue::new(api_key, secret_key))
+        };
+        Ok(Self::with_venue(config, venue, x_decimals))
+    }
+
+    pub fn with_venue(config: HedgeConfig, venue: Box<dyn HedgeVenue + Send>, x_decimals: usize) -> Self {
+        Self {
+            config,
+            venue,
+            x_decimals,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    pub fn on_book(&mut self, book: &CexData) {
+        self.venue.on_book(book);
+    }
+
+    // net_delta is the token x exposure across the DEX and existing hedges
+    pub fn on_delta(&mut self, net_delta: i128) -> Option<HedgeFill> {
+        if net_delta.unsigned_abs() <= self.config.threshold {
+            return None;
+        }
+        let side = if net_delta > 0 { Side::Sell } else { Side::Buy };
+        let unit = 10.0_f64.powi(self.x_decimals as i32);
+        let qty = net_delta.unsigned_abs() as f64 / unit;
+        let qty = (qty / self.config.qty_step).floor() * self.config.qty_step;
+        if qty <= 0.0 {
+            return None;
+        }
+        if self.retry_at.map_or(false, |at| Instant::now() < at) {
+            debug!(net_delta = net_delta, failures = self.failures, "Hedge backing off");
+            return None;
+        }
+        info!(net_delta = net_delta, side = ?side, qty = qty, "Sending hedge order");
+        match self.venue.market_order(&self.config.symbol, side, qty) {
+            Ok((filled, quote_qty)) => {
+                if filled < qty {
+                    warn!(qty = qty, filled = filled, "Hedge partially filled");
+                }
+                // Scaled in Decimal, since filled * unit in f64 is off by up to hundreds of units
+                let x_units = match Decimal::from_f64(filled).zip(Decimal::from_i128(10_i128.pow(self.x_decimals as u32)))
+                    .and_then(|(filled, unit)| filled.checked_mul(unit))
+                    .and_then(|x_units| x_units.trunc().to_i128()) {
+                    Some(x_units) => x_units,
+                    None => {
+                        error!(filled = filled, "Hedge fill overflows Decimal. Accounting it in f64");
+                        (filled * unit) as i128
+                    }
+                };
+                let quote = Decimal::from_f64(quote_qty).unwrap_or_default();
+                let fill = match side {
+                    Side::Buy => HedgeFill { side, qty: filled, quote_qty, x_delta: x_units, quote_delta: -quote },
+                    Side::Sell => HedgeFill { side, qty: filled, quote_qty, x_delta: -x_units, quote_delta: quote },
+                };
+                info!(fill = ?fill, "Hedge filled");
+                self.failures = 0;
+                self.retry_at = None;
+                Some(fill)
+            },
+            Err(e) => {
+                self.failures += 1;
+                let backoff = (RETRY_BACKOFF * 2_u32.saturating_pow(self.failures - 1)).min(MAX_RETRY_BACKOFF);
+                self.retry_at = Some(Instant::now() + backoff);
+                error!(error = %e, side = ?side, qty = qty, failures = self.failures, backoff_secs = backoff.as_secs(), "Hedge order failed");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const UNIT: i128 = 1_000_000_000_000_000_000;
+
+    fn config() -> HedgeConfig {
+        HedgeConfig {
+            symbol: "AVAXUSDT".to_string(),
+            api_key: None,
+            secret_key: None,
+            threshold: UNIT as u128,
+            qty_step: 0.1,
+            fee_bps: 10.0,
+            paper: true,
+        }
+    }
+
+    fn hedger(bids: Vec<(f64, f64)>, asks: Vec<(f64, f64)>) -> Hedger {
+        let mut engine = MatchingEngine::new(10.0);
+        engine.set_book(bids, asks);
+        Hedger::with_venue(config(), Box::new(engine), 18)
+    }
+
+    // Counts orders and rejects every one of them
+    struct Rejecting(Arc<AtomicUsize>);
+
+    impl HedgeVenue for Rejecting {
+        fn market_order(&mut self, _symbol: &str, _side: Side, _qty: f64) -> Result<(f64, f64), HedgeError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(HedgeError::Rejected("insufficient balance".to_string()))
+        }
+    }
+
+    #[test]
+    fn sells_long_delta_and_nets_it_out() {
+        let mut hedger = hedger(vec![(20.0, 100.0)], vec![(20.1, 100.0)]);
+        let net_delta = 5 * UNIT;
+        let fill = hedger.on_delta(net_delta).unwrap();
+        assert_eq!(fill.side, Side::Sell);
+        assert_eq!(fill.qty, 5.0);
+        assert_eq!(net_delta + fill.x_delta, 0);
+        // 5 * 20 less 10bps
+        assert!((fill.quote_delta - dec!(99.9)).abs() < dec!(0.000000001));
+    }
+
+    #[test]
+    fn buys_short_delta_rounded_down_to_the_lot_size() {
+        let mut hedger = hedger(vec![(20.0, 100.0)], vec![(20.0, 100.0)]);
+        let net_delta = -(5 * UNIT + UNIT / 20);
+        let fill = hedger.on_delta(net_delta).unwrap();
+        assert_eq!(fill.side, Side::Buy);
+        assert_eq!(fill.qty, 5.0);
+        assert_eq!(fill.x_delta, 5 * UNIT);
+        assert!(fill.quote_delta < Decimal::ZERO);
+        // What is left is below the lot size, so there is nothing more to hedge
+        assert_eq!(net_delta + fill.x_delta, -UNIT / 20);
+    }
+
+    #[test]
+    fn ignores_delta_within_threshold() {
+        let mut hedger = hedger(vec![(20.0, 100.0)], vec![(20.1, 100.0)]);
+        assert_eq!(hedger.on_delta(UNIT), None);
+        assert_eq!(hedger.on_delta(-UNIT), None);
+    }
+
+    #[test]
+    fn partial_fill_walks_the_book_and_reports_what_filled() {
+        let mut hedger = hedger(vec![(20.0, 2.0), (19.0, 1.0)], vec![(20.1, 100.0)]);
+        let fill = hedger.on_delta(5 * UNIT).unwrap();
+        assert_eq!(fill.qty, 3.0);
+        assert_eq!(fill.x_delta, -3 * UNIT);
+        assert!((fill.quote_qty - (2.0 * 20.0 + 19.0) * 0.999).abs() < 1e-9);
+        // The book was consumed, so the rest of the delta is rejected
+        assert_eq!(hedger.on_delta(2 * UNIT + UNIT / 2), None);
+    }
+
+    #[test]
+    fn fills_are_scaled_to_x_units_exactly() {
+        // 2.3 * 1e18 in f64 is 2299999999999999744
+        let mut hedger = hedger(vec![(20.0, 2.3)], vec![(20.1, 100.0)]);
+        let fill = hedger.on_delta(5 * UNIT).unwrap();
+        assert_eq!(fill.qty, 2.3);
+        assert_eq!(fill.x_delta, -23 * UNIT / 10);
+    }
+
+    #[test]
+    fn live_hedger_needs_both_credentials() {
+        let live = HedgeConfig { paper: false, ..config() };
+        assert!(matches!(Hedger::new(live.clone(), 18), Err(HedgeError::Credentials(_))));
+        let missing = CredentialSource::Env{var: "QUOTER_TEST_MISSING_BINANCE_KEY".to_string()};
+        let live = HedgeConfig { api_key: Some(missing.clone()), secret_key: Some(missing), ..live };
+        assert!(matches!(Hedger::new(live, 18), Err(HedgeError::Credentials(e)) if e.starts_with("api_key")));
+        assert!(Hedger::new(config(), 18).is_ok());
+    }
+
+    #[test]
+    fn rejects_when_the_book_is_empty() {
+        let mut engine = MatchingEngine::new(0.0);
+        engine.set_book(vec![], vec![(20.0, 1.0)]);
+        assert!(matches!(engine.market_order("AVAXUSDT", Side::Sell, 1.0), Err(HedgeError::NoLiquidity(Side::Sell))));
+        assert_eq!(engine.market_order("AVAXUSDT", Side::Buy, 1.0).unwrap(), (1.0, 20.0));
+        assert!(engine.asks.is_empty());
+    }
+
+    #[test]
+    fn backs_off_after_a_rejection() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut hedger = Hedger::with_venue(config(), Box::new(Rejecting(calls.clone())), 18);
+        assert_eq!(hedger.on_delta(5 * UNIT), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Not sent again until the backoff has passed
+        assert_eq!(hedger.on_delta(5 * UNIT), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        hedger.retry_at = Some(Instant::now());
+        assert_eq!(hedger.on_delta(5 * UNIT), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(hedger.failures, 2);
+        assert!(hedger.retry_at.unwrap() > Instant::now() + RETRY_BACKOFF);
+    }
+}
@@ -0,0 +1,47 @@
+use std::fs;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn, error};
+
+// Touching this file triggers the kill switch. Writing "sweep" into it also sweeps to the owner
+pub const KILL_FILE: &str = "KILL";
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct KillCommand {
+    pub sweep: bool,
+    pub reason: String,
+}
+
+// Sends a kill command to every market on SIGUSR1 or when KILL_FILE appears.
+// A sweep asked for without the owner loaded is refused, but the kill still goes ahead
+pub fn spawn_triggers(tx: watch::Sender<Option<KillCommand>>, default_sweep: bool, can_sweep: bool) {
+    let signal_tx = tx.clone();
+    tokio::spawn(async move {
+        let mut usr1 = signal(SignalKind::user_defined1()).unwrap();
+        usr1.recv().await;
+        warn!(sweep = default_sweep, "Kill switch triggered by SIGUSR1");
+        signal_tx.send(Some(KillCommand { sweep: default_sweep, reason: "SIGUSR1".to_string() })).ok();
+    });
+    tokio::spawn(async move {
+        loop {
+            if let Ok(contents) = fs::read_to_string(KILL_FILE) {
+                let mut sweep = default_sweep || contents.trim() == "sweep";
+                if sweep && !can_sweep {
+                    error!(file = KILL_FILE, "Kill file asks for a sweep but the owner isn't loaded. Killing without sweeping");
+                    sweep = false;
+                }
+                warn!(sweep = sweep, file = KILL_FILE, "Kill switch triggered by file");
+                // Remove the flag so the next start doesn't kill itself
+                if let Err(e) = fs::remove_file(KILL_FILE) {
+                    warn!(error = ?e, "Failed to remove kill file");
+                }
+                tx.send(Some(KillCommand { sweep, reason: format!("file {}", KILL_FILE) })).ok();
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    info!(file = KILL_FILE, "Kill switch armed. Send SIGUSR1 or create the file to trigger");
+}
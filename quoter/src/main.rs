@@ -1,6 +1,7 @@
 use std::fs;
 use std::sync::{Arc, Mutex, RwLock};
-use tracing::{trace, debug, info, warn, error, Level};
+use std::collections::HashMap;
+use tracing::{trace, debug, info, warn, error, info_span, Instrument, Level};
 use tracing_subscriber;
 use tracing_appender;
 use ethers::prelude::*;
@@ -14,23 +15,115 @@ use reqwest;
 mod portfolio;
 mod executor;
 mod cex_feed;
+mod pricing;
+mod hedger;
+mod market;
+mod risk;
+mod kill;
+mod cli;
+mod shutdown;
+mod state;
+mod tracker;
+mod reconcile;
+mod metrics;
+mod admin;
+mod health;
+mod config;
+mod signer;
+mod reload;
+mod treasury;
+mod sim;
 use cex_feed::CexData;
 use tokio::sync::watch;
 use chrono::prelude::*;
+use clap::Parser;
+
+pub type Client = NonceManagerMiddleware<SignerMiddleware<Provider<Ws>, signer::AnySigner>>;
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
-    pub cex_param: CexFeedType,
-    // pub cex_ticker: String,
-    // pub secondary_ticker: Option<String>,
     pub wsrpc: String,
     pub archiverpc: String,
+    // Pinged while every market is healthy
     pub heartbeat: String,
-    pub executor_address: String,
     pub weth: String,
-    pub owner_key: String,
-    pub portfolio_config: portfolio::PortfolioConfig,
+    // Where the owner key is loaded from
+    #[serde(default)]
+    pub signer: Option<signer::SignerSource>,
+    // Plaintext owner key. Only used when signer isn't set, prefer a keystore, env var or key file
+    #[serde(default)]
+    pub owner_key: Option<String>,
+    // Whitelisted non-owner keys that send market txs in turn. The owner is then only loaded for admin
+    // commands, or when the kill switch or exit policy sweeps
+    #[serde(default)]
+    pub callers: Vec<signer::SignerSource>,
+
+    // Single market mode
+    pub cex_param: Option<CexFeedType>,
+    pub executor_address: Option<String>,
+    pub portfolio_config: Option<portfolio::PortfolioConfig>,
+    #[serde(default)]
+    pub hedger: Option<hedger::HedgeConfig>,
+    #[serde(default)]
+    pub reconcile: reconcile::ReconcileConfig,
+
+    // Multi market mode. Used instead of the single market fields when not empty
+    #[serde(default)]
+    pub markets: Vec<market::MarketConfig>,
+    #[serde(default)]
+    pub risk: Option<risk::RiskConfig>,
+    // Caps on gas top-ups and the WETH reserve to alert below
+    #[serde(default)]
+    pub treasury: Option<treasury::TreasuryConfig>,
+
+    // Whether the kill switch sweeps to the owner when triggered by signal or file
+    #[serde(default)]
+    pub kill_sweep: bool,
+    // What to do with positions on SIGINT/SIGTERM
+    #[serde(default)]
+    pub exit_policy: shutdown::ExitPolicy,
+    // Directory holding each market's persisted state
+    #[serde(default = "default_state_dir")]
+    pub state_dir: String,
+    // Directory each market appends the frames it quotes on to, for backtest and replay. Off when not set
+    #[serde(default)]
+    pub record_dir: Option<String>,
+    // Liveness limits and where to report degraded markets
+    #[serde(default)]
+    pub health: health::HealthConfig,
+    // Authenticated local HTTP API for inspecting and steering markets. Off when not set
+    #[serde(default)]
+    pub admin: Option<admin::AdminConfig>,
+    // Local address serving Prometheus metrics on /metrics
+    #[serde(default = "default_metrics_addr")]
+    pub metrics_addr: String,
+}
+
+fn default_state_dir() -> String {
+    "state".to_string()
+}
+
+fn default_metrics_addr() -> String {
+    "127.0.0.1:9898".to_string()
 }
+
+impl Config {
+    pub fn markets(&self) -> Vec<market::MarketConfig> {
+        if !self.markets.is_empty() {
+            return self.markets.clone();
+        }
+        vec![market::MarketConfig {
+            name: "default".to_string(),
+            cex_param: self.cex_param.clone().expect("cex_param or markets must be set"),
+            executor_address: self.executor_address.clone().expect("executor_address or markets must be set"),
+            portfolio_config: self.portfolio_config.expect("portfolio_config or markets must be set"),
+            hedger: self.hedger.clone(),
+            notional_px: 1.0,
+            reconcile: self.reconcile,
+        }]
+    }
+}
+
 #[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct DisplayBin {
     pub id: u32,
@@ -70,186 +163,360 @@ fn generate_cex_feed(param: CexFeedType, tx: watch::Sender<CexData>) {
 
 #[tokio::main]
 async fn main() {
+    let cli = cli::Cli::parse();
     let file_appender = tracing_appender::rolling::daily("./log", "quoter.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
     tracing_subscriber::fmt()
     .json()
     .with_writer(non_blocking).init();
 
-    let file = fs::File::open("config.json").unwrap();
-    let mut config: Config = serde_json::from_reader(file).unwrap();
-    let client = Arc::new({
-            // connect to the network
-            let client = Provider::<Ws>::connect(config.wsrpc).await.unwrap();
-            let chain_id = client.get_chainid().await.unwrap();
-    
-            // this wallet's private key
-            let wallet = config.owner_key
-                .parse::<LocalWallet>().unwrap()
-                .with_chain_id(chain_id.as_u64());
-    
-            SignerMiddleware::new(client, wallet)
-        });
-    let archive = Arc::new(Provider::<Http>::try_from(config.archiverpc).unwrap());
-    let x_id = Uuid::new_v4();
-    let y_id = Uuid::new_v4();
-
-    let amm = lb::LB::new_empty(x_id, y_id);
-    let address = config.executor_address.clone();
-    let new_client = client.clone();
-    let (tx, mut dex_rx) = watch::channel(amm.clone());
-    tokio::spawn(async move {
-        lb::LB::produce_new(amm, address.as_str(), archive, new_client.clone(), tx).await;
-    });
-    let (tx, mut cex_rx) = watch::channel(CexData::default());
-    // let ticker = config.cex_ticker.clone();
-    // if let Some(symbol2) = config.secondary_ticker.clone() {
-    //     thread::spawn(move || {cex_feed::run_cex_feed_impl(&ticker, &symbol2, tx)});
-    // } else if config.cex_ticker.contains("-") {
-    //     let t = ticker.clone();
-    //     tokio::spawn(cex_feed::run_cex_feed_kucoin(t, tx));
-    // } else {
-    //     thread::spawn(move || {cex_feed::run_cex_feed(&ticker, tx)});
-    // }
-
-    generate_cex_feed(config.cex_param.clone(), tx);
-    tokio::spawn(heartbeat(config.heartbeat));
-    assert!(dex_rx.changed().await.is_ok());
-    let mut amm = dex_rx.borrow().clone();
-    assert!(cex_rx.changed().await.is_ok());
-    let mut cex = cex_rx.borrow().clone();
-
-    let mut executor = executor::Executor::new(
-        client,
-        config.executor_address.parse::<Address>().unwrap(),
-        config.weth.parse::<Address>().unwrap(),
-        config.portfolio_config,
-    ).await;
-    let (mut x_amt, mut y_amt) = executor.get_balances().await;
-    let mut mypositions = executor.get_liq_tokens(amm.bins.keys().cloned().collect()).await;
-    let (x_dec, y_dec) = executor.get_decs().await;
-    info!(x_balance = x_amt, y_balance = y_amt, "Executor balances");
-
-    let mut portfolio = portfolio::Portfolio::new(
-        &amm,
-        x_amt,
-        y_amt,
-        x_dec,
-        y_dec,
-        config.portfolio_config,
-    );
-    
-    let mut block_executed = 0;
-
-    // let start = Utc.with_ymd_and_hms(2022, 12, 13, 13, 20, 0).unwrap();
-    // let end = Utc.with_ymd_and_hms(2022, 12, 13, 13, 40, 0).unwrap();
-    executor.execute(executor::Execute::CheckGas, 0).await;
-    executor.execute(executor::Execute::CheckGas, 0).await;
-    loop {
-        tokio::select! {
-            biased;
-            res = dex_rx.changed() => {
-                assert!(res.is_ok());
-                amm = dex_rx.borrow().clone();
-                
-                cex = cex_rx.borrow().clone();
-                info!(dex_block = amm.last_block, "DEX block");
+    let config = match config::load(&cli.config).and_then(|config| config::open_state_dir(&cli.config, &config).map(|_| config)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "Invalid config");
+            eprintln!("{}", e);
+            drop(guard);
+            std::process::exit(1);
+        }
+    };
+    let command = cli.command.unwrap_or(cli::Command::Run);
+    if let cli::Command::CheckConfig = command {
+        println!("{} is valid: {} market(s)", cli.config, config.markets().len());
+        return;
+    }
+    // Backtests and replays run on recorded frames and never connect
+    if let cli::Command::Backtest{input, market, fee_bps} | cli::Command::Replay{input, market, fee_bps} = &command {
+        let markets = config.markets().into_iter().filter(|m| market.as_ref().map_or(true, |name| *name == m.name)).collect::<Vec<_>>();
+        if markets.len() != 1 {
+            eprintln!("Pick one market with --market");
+            drop(guard);
+            std::process::exit(1);
+        }
+        let frames = match sim::read_frames(input) {
+            Ok(frames) => frames,
+            Err(e) => {
+                eprintln!("{}", e);
+                drop(guard);
+                std::process::exit(1);
+            }
+        };
+        let portfolio_config = markets[0].portfolio_config;
+        match &command {
+            cli::Command::Backtest{..} => match sim::backtest(&frames, portfolio_config, *fee_bps) {
+                Some(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+                None => eprintln!("{} holds no frames", input),
             },
-            res = cex_rx.changed() => {
-                // assert!(res.is_ok());
-                if !res.is_ok() {
-                    error!(error=?res, "websocket disconnected");
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    let tx;
-                    (tx, cex_rx) = watch::channel(CexData::default());
-                    // let ticker = config.cex_ticker.clone();
-                    // if let Some(symbol2) = config.secondary_ticker.clone() {
-                    //     thread::spawn(move || {cex_feed::run_cex_feed_impl(&ticker, &symbol2, tx)});
-                    // } else if config.cex_ticker.contains("-") {
-                    //     let t = ticker.clone();
-                    //     tokio::spawn(cex_feed::run_cex_feed_kucoin(t, tx));
-                    // } else {
-                    //     thread::spawn(move || {cex_feed::run_cex_feed(&ticker, tx)});
-                    // }
-                    generate_cex_feed(config.cex_param.clone(), tx);
-                    continue;
-                }
-                cex = cex_rx.borrow().clone();
-                info!(cex = ?cex, "CEX data");
+            _ => for decision in sim::replay(&frames, portfolio_config, *fee_bps) {
+                println!("{}", serde_json::to_string(&decision).unwrap());
             },
-            _ = tokio::time::sleep(Duration::from_secs(5)) => {
-                let file = fs::File::open("config.json").unwrap();
-                match serde_json::from_reader::<_, Config>(file) {
-                    Ok(new_config) => {
-                        if new_config.portfolio_config != config.portfolio_config {
-                            info!(?new_config.portfolio_config, "New config");
-                            config.portfolio_config = new_config.portfolio_config;
-                            portfolio.config = new_config.portfolio_config;
-                            executor.config = new_config.portfolio_config;
-                        } else {
-                            continue;
+        }
+        return;
+    }
+    // Sweeps and owner calls are the only commands that need the owner when callers are set
+    let owner = match &command {
+        cli::Command::Run => config.kill_sweep || config.exit_policy == shutdown::ExitPolicy::CancelAllAndSweep,
+        cli::Command::CancelAll{sweep, ..} => *sweep,
+        cli::Command::Sweep{..} | cli::Command::Owner{..} => true,
+        _ => false,
+    };
+    let clients = connect(&config, owner).await;
+    let weth = config.weth.parse::<Address>().unwrap();
+
+    match command {
+        cli::Command::Run => run(config, clients, weth, cli.config.clone(), cli.dry_run).await,
+        cli::Command::Status{market} => {
+            for (name, executor) in executors(&config, &clients, weth, market, cli.dry_run).await {
+                let status = executor.status().instrument(info_span!("market", market = %name)).await;
+                println!("{}: {}", name, serde_json::to_string_pretty(&status).unwrap());
+            }
+        },
+        cli::Command::Positions{market} => {
+            for (name, executor) in executors(&config, &clients, weth, market, cli.dry_run).await {
+                let positions = executor.positions().instrument(info_span!("market", market = %name)).await;
+                println!("{}: {}", name, serde_json::to_string_pretty(&positions).unwrap());
+            }
+        },
+        cli::Command::CancelAll{sweep, market, range} => {
+            for (name, mut executor) in executors(&config, &clients, weth, market, cli.dry_run).await {
+                let report = executor.kill(sweep, range).instrument(info_span!("market", market = %name)).await;
+                println!("{}: {}", name, serde_json::to_string_pretty(&report).unwrap());
+            }
+        },
+        cli::Command::Claim{market} => {
+            for (name, mut executor) in executors(&config, &clients, weth, market, cli.dry_run).await {
+                let span = info_span!("market", market = %name);
+                let block = async {
+                    // Bins touched by the last run may have been emptied but still hold fees
+                    let store = match state::StateStore::open(&config.state_dir, &name) {
+                        Ok(store) => store,
+                        Err(e) => {
+                            error!(error = %e, state_dir = %config.state_dir, "Failed to open state");
+                            return None;
                         }
+                    };
+                    executor.store = Some(store.clone());
+                    executor.restore(&store.get()).await;
+                    let held = executor.discover_positions().await;
+                    executor.touch(held.into_keys().collect());
+                    let active_id = executor.active_id().await;
+                    executor.execute(executor::Execute::Claim, active_id).await
+                }.instrument(span).await;
+                println!("{}: claim mined in {:?}", name, block);
+            }
+        },
+        cli::Command::Sweep{market, token} => {
+            let tokens = token.iter().map(|token| token.parse::<Address>().expect("invalid token address")).collect::<Vec<Address>>();
+            for (name, mut executor) in executors(&config, &clients, weth, market, cli.dry_run).await {
+                let tokens = match tokens.is_empty() {
+                    true => {
+                        let (x, y) = executor.tokens();
+                        vec![x, y, Address::zero()]
                     },
-                    Err(e) => {
-                        error!(?e, "Failed to read config");
-                        continue;
-                    }
+                    false => tokens.clone(),
+                };
+                let sweeps = executor.sweep(tokens).instrument(info_span!("market", market = %name)).await;
+                println!("{}: {}", name, serde_json::to_string_pretty(&sweeps).unwrap());
+            }
+        },
+        cli::Command::Owner{call, market, yes} => {
+            let call = match call {
+                cli::OwnerCommand::Whitelist{caller, revoke} => executor::OwnerCall::SetWhitelist{
+                    caller: caller.parse().expect("invalid caller address"),
+                    allowed: !revoke,
+                },
+                cli::OwnerCommand::Sweep{token, amount: None} => executor::OwnerCall::Sweep{token: token.parse().expect("invalid token address")},
+                cli::OwnerCommand::Sweep{token, amount: Some(amount)} => executor::OwnerCall::SweepAmount{
+                    token: token.parse().expect("invalid token address"),
+                    amount: U256::from_dec_str(&amount).expect("invalid amount"),
+                },
+                cli::OwnerCommand::GetRewards{ids} => executor::OwnerCall::GetRewards{ids},
+                cli::OwnerCommand::Execute{calls} => executor::OwnerCall::Execute{
+                    calls: serde_json::from_str(&fs::read_to_string(calls).expect("failed to read calls")).expect("invalid calls"),
+                },
+                cli::OwnerCommand::ApproveGas{caller, amount} => executor::OwnerCall::ApproveGas{
+                    caller: caller.parse().expect("invalid caller address"),
+                    amount: amount.map_or(U256::MAX, |amount| U256::from_dec_str(&amount).expect("invalid amount")),
+                },
+            };
+            let mut executors = executors(&config, &clients, weth, market, cli.dry_run).await;
+            if executors.len() != 1 {
+                eprintln!("Pick one market with --market");
+                drop(guard);
+                std::process::exit(1);
+            }
+            let (name, mut executor) = executors.remove(0);
+            let res = executor.owner_call(call, |preview| {
+                println!("{}: {}", name, serde_json::to_string_pretty(preview).unwrap());
+                yes || cli.dry_run || cli::confirm("Send?")
+            }).instrument(info_span!("market", market = %name)).await;
+            match res {
+                Ok(block) => println!("{}: mined in {:?}", name, block),
+                Err(e) => {
+                    eprintln!("{}: {}", name, e);
+                    drop(guard);
+                    std::process::exit(1);
                 }
-                
             }
-        };
+        },
+        cli::Command::CheckConfig | cli::Command::Backtest{..} | cli::Command::Replay{..} => unreachable!(),
+    }
+    info!("Exiting");
+    // Flush buffered logs
+    drop(guard);
+}
 
-        // let now = Utc::now();
-        // if now >= start && now < end && !portfolio.config.reduce_only {
-        //     info!("Setting portfolio to reduce only");
-        //     portfolio.config.reduce_only = true;
-        // } else if now > end && portfolio.config.reduce_only {
-        //     info!("Setting portfolio to normal");
-        //     portfolio.config.reduce_only = false;
-        // }
-
-        if block_executed > amm.last_block {
-            warn!(block_executed = block_executed, dex_block = amm.last_block, "DEX block not updated");
-            continue;
-        }
+// An executor for every market, or only the named one
+async fn executors(config: &Config, clients: &Clients, weth: Address, market: Option<String>, dry_run: bool) -> Vec<(String, executor::Executor<Client>)> {
+    let mut executors = Vec::new();
+    for m in config.markets().into_iter().filter(|m| market.as_ref().map_or(true, |name| *name == m.name)) {
+        let mut executor = executor::Executor::new(
+            clients.client(),
+            m.executor_address.parse::<Address>().unwrap(),
+            weth,
+            m.portfolio_config,
+        ).await;
+        executor.market = m.name.clone();
+        executor.dry_run = dry_run;
+        executor.callers = clients.callers.clone();
+        executors.push((m.name, executor));
+    }
+    if executors.is_empty() {
+        eprintln!("No market named {:?}", market);
+    }
+    executors
+}
 
-        let (action, id) = portfolio.on_state(cex.bid_px, cex.ask_px, &amm);
-        if let Some(action) = action {
-            if let Some(block) = executor.execute(action.clone(), id).await {
-                block_executed = block;
-            }
-            (x_amt, y_amt) = executor.get_balances().await;
-            mypositions = executor.get_liq_tokens((-10..10).map(|x| {(x + amm.active_id as i64) as u32}).collect()).await;
-            // mypositions = executor.get_liq_tokens(amm.bins.keys().cloned().collect()).await;
-            portfolio.x_balance = x_amt;
-            portfolio.y_balance = y_amt;
-            portfolio.x_free = x_amt;
-            portfolio.y_free = y_amt;
-            portfolio.positions = mypositions.iter().map(|(id, tokens)| {
-                let total_tokens = amm.supply.get(id).unwrap();
-                let bin = amm.bins.get(id).unwrap();
-                (*id, portfolio::Bin {
-                    id: *id,
-                    x: ((bin.x * U256::from(*tokens)) / U256::from(*total_tokens)).as_u128(),
-                    y: ((bin.y * U256::from(*tokens)) / U256::from(*total_tokens)).as_u128(),
-                    tokens: *tokens,
-                })
-            }).collect();
+// Every signer the process holds, each with its own nonce manager on the same provider
+#[derive(Clone)]
+pub struct Clients {
+    pub owner: Option<Arc<Client>>,
+    pub callers: Vec<Arc<Client>>,
+}
+
+impl Clients {
+    // Reads, kills and owner calls go through the owner when it's loaded. Market txs rotate through the callers
+    pub fn client(&self) -> Arc<Client> {
+        match self.owner.as_ref() {
+            Some(owner) => owner.clone(),
+            None => self.callers[0].clone(),
         }
-        
-        info!(curid = id, block = amm.last_block, my_bins = ?portfolio.positions.iter().map(|(id, bin)| DisplayBin{ id: *id, x: bin.x as f64 / 10.0_f64.powi(x_dec as i32), y: bin.y as f64 / 10.0_f64.powi(y_dec as i32), tokens: bin.tokens}).collect::<Vec<DisplayBin>>());
     }
-    
 
+    fn signers(&self) -> Vec<signer::AnySigner> {
+        self.owner.iter().chain(self.callers.iter()).map(|client| client.inner().signer().clone()).collect()
+    }
+}
+
+fn signed(provider: Provider<Ws>, wallet: signer::AnySigner) -> Arc<Client> {
+    let sender = wallet.address();
+    // markets share each signer, so nonces are tracked locally
+    Arc::new(NonceManagerMiddleware::new(SignerMiddleware::new(provider, wallet), sender))
+}
+
+fn signer_failed(e: String) -> ! {
+    error!(error = %e, "Failed to load signer");
+    eprintln!("Failed to load signer: {}", e);
+    std::process::exit(1);
+}
+
+// Loads every caller, and the owner when needed or when there are no callers
+async fn connect(config: &Config, owner: bool) -> Clients {
+    // connect to the network
+    let provider = Provider::<Ws>::connect(config.wsrpc.clone()).await.unwrap();
+    let chain_id = provider.get_chainid().await.unwrap().as_u64();
+    let mut callers = Vec::new();
+    for source in config.callers.iter() {
+        let wallet = source.load(chain_id).await.unwrap_or_else(|e| signer_failed(e.to_string()));
+        callers.push(signed(provider.clone(), wallet));
+    }
+    let owner = match owner || callers.is_empty() {
+        false => None,
+        true => {
+            // this wallet's private key
+            let wallet = match (config.signer.as_ref(), config.owner_key.as_ref()) {
+                (Some(source), _) => source.load(chain_id).await,
+                (None, Some(key)) => {
+                    warn!("owner_key is stored in plaintext in the config, set signer instead");
+                    key.parse::<LocalWallet>().map(|wallet| signer::AnySigner::Local(wallet).with_chain_id(chain_id)).map_err(Into::into)
+                },
+                (None, None) => signer_failed("this command needs the owner: set signer or owner_key".to_string()),
+            };
+            Some(signed(provider.clone(), wallet.unwrap_or_else(|e| signer_failed(e.to_string()))))
+        },
+    };
+    info!(owner = ?owner.as_ref().map(|owner| owner.inner().address()), callers = ?callers.iter().map(|caller| caller.inner().address()).collect::<Vec<Address>>(), "Loaded signers");
+    Clients{owner, callers}
 }
 
-async fn heartbeat(url: String) {
-    let client = reqwest::Client::new();
+// Connects the running signers to a new RPC, keeping the old connection if anything fails
+async fn reconnect(wsrpc: &str, archiverpc: &str, clients: &Clients) -> Result<(Clients, Arc<Provider<Http>>), String> {
+    let provider = Provider::<Ws>::connect(wsrpc).await.map_err(|e| e.to_string())?;
+    let chain_id = provider.get_chainid().await.map_err(|e| e.to_string())?;
+    if let Some(wallet) = clients.signers().into_iter().find(|wallet| wallet.chain_id() != chain_id.as_u64()) {
+        return Err(format!("wsrpc is on chain {}, expected {}", chain_id, wallet.chain_id()));
+    }
+    let archive = Provider::<Http>::try_from(archiverpc).map_err(|e| e.to_string())?;
+    let owner = clients.owner.as_ref().map(|owner| signed(provider.clone(), owner.inner().signer().clone()));
+    let callers = clients.callers.iter().map(|caller| signed(provider.clone(), caller.inner().signer().clone())).collect();
+    Ok((Clients{owner, callers}, Arc::new(archive)))
+}
+
+async fn run(config: Config, mut clients: Clients, weth: Address, config_path: String, dry_run: bool) {
+    let mut archive = Arc::new(Provider::<Http>::try_from(config.archiverpc.clone()).unwrap());
+    let risk = Arc::new(Mutex::new(risk::RiskManager::new(config.risk)));
+    let treasury = Arc::new(Mutex::new(treasury::Treasury::new(config.treasury)));
+    let (kill_tx, kill_rx) = watch::channel(None);
+    kill::spawn_triggers(kill_tx.clone(), config.kill_sweep, clients.owner.is_some());
+    let (shutdown_tx, shutdown_rx) = watch::channel(None);
+    shutdown::spawn_signal_handler(shutdown_tx.clone(), config.exit_policy);
+
+    let mut config_rx = reload::spawn(config_path, config.clone());
+    let health = health::Registry::default();
+    health::spawn_monitor(config_rx.clone(), health.clone(), config.markets().into_iter().map(|market| market.name).collect());
+    metrics::spawn_server(config.metrics_addr.parse().unwrap());
+    // Batches go through MM.execute, which only the owner can send
+    if clients.owner.is_none() {
+        warn!(callers = clients.callers.len(), "Batching is off: the owner isn't loaded, so each action of a batch is sent as its own tx");
+    }
+
+    let names = config.markets().into_iter().map(|market| market.name).collect::<Vec<String>>();
+    let mut handles = HashMap::new();
+    let mut links = names.iter().map(|name| {
+        let (handle, link) = admin::link(name);
+        handles.insert(name.clone(), handle);
+        link
+    }).collect::<Vec<_>>();
+    if let Some(admin_config) = config.admin.clone() {
+        admin::spawn_server(admin_config, handles, kill_tx.clone(), clients.owner.is_some());
+    }
+
+    // Markets run until stopped, or until the RPC changes and they are restarted on new providers
+    let mut rpc = (config.wsrpc.clone(), config.archiverpc.clone());
     loop {
-        // Send a GET request to the URL
-        let response = client.get(&url).send().await;
+        let (reconnect_tx, reconnect_rx) = watch::channel(false);
+        let shared = market::Shared {
+            client: clients.client(),
+            callers: clients.callers.clone(),
+            archive: archive.clone(),
+            weth,
+            risk: risk.clone(),
+            treasury: treasury.clone(),
+            kill_rx: kill_rx.clone(),
+            shutdown_rx: shutdown_rx.clone(),
+            state_dir: config.state_dir.clone(),
+            record_dir: config.record_dir.clone(),
+            health: health.clone(),
+            config_rx: config_rx.clone(),
+            reconnect_rx,
+            dry_run,
+        };
+        let latest = config_rx.borrow().clone();
+        let markets = links.into_iter().zip(names.iter()).map(|(link, name)| {
+            // Reloads can't add or remove markets
+            let market = latest.markets().into_iter().find(|market| market.name == *name).unwrap();
+            let span = info_span!("market", market = %market.name);
+            tokio::spawn(market::run_market(market, shared.clone(), link).instrument(span))
+        }).collect::<Vec<_>>();
+        let stopped = futures::future::join_all(markets);
+        tokio::pin!(stopped);
 
-        // Wait for 30 seconds before sending the next request
-        tokio::time::sleep(Duration::from_secs(30)).await;
+        let results = loop {
+            tokio::select! {
+                results = &mut stopped => break results,
+                Ok(_) = config_rx.changed() => {
+                    let latest = config_rx.borrow().clone();
+                    risk.lock().unwrap().config = latest.risk;
+                    treasury.lock().unwrap().config = latest.treasury;
+                    if (latest.wsrpc.clone(), latest.archiverpc.clone()) != rpc {
+                        info!("RPC changed. Restarting markets on new providers");
+                        reconnect_tx.send_replace(true);
+                    }
+                },
+            }
+        };
+        links = Vec::new();
+        for res in results {
+            match res {
+                Ok(Some(link)) => links.push(link),
+                Ok(None) => (),
+                Err(e) => error!(error = ?e, "Market stopped"),
+            }
+        }
+        // Every market must have stopped to reconnect, otherwise we're shutting down
+        if links.len() != names.len() {
+            break;
+        }
+        let latest = config_rx.borrow().clone();
+        match reconnect(&latest.wsrpc, &latest.archiverpc, &clients).await {
+            Ok((new_clients, new_archive)) => {
+                info!(wsrpc = %latest.wsrpc, archiverpc = %latest.archiverpc, "Reconnected");
+                clients = new_clients;
+                archive = new_archive;
+                rpc = (latest.wsrpc.clone(), latest.archiverpc.clone());
+            },
+            Err(e) => error!(error = %e, "Failed to reconnect. Restarting markets on the old providers"),
+        }
     }
-}
\ No newline at end of file
+    drop(kill_tx);
+    drop(shutdown_tx);
+}
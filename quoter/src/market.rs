@@ -0,0 +1,572 @@
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn, error};
+use ethers::prelude::*;
+use amm::{AMM, lb};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::{Config, CexFeedType, DisplayBin, generate_cex_feed};
+use crate::cex_feed::CexData;
+use crate::{admin, executor, health, hedger, metrics, portfolio, sim};
+use crate::risk::{BreachAction, Exposure, RiskManager};
+use crate::treasury::Treasury;
+use crate::kill::KillCommand;
+use crate::shutdown::ExitPolicy;
+use crate::state::StateStore;
+use crate::tracker::{self, PositionTracker};
+use crate::reconcile::{ReconcileConfig, Reconciler};
+
+// How often to walk the whole pair for bins we hold
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct MarketConfig {
+    // Used to match markets across config reloads, and in logs
+    pub name: String,
+    pub cex_param: CexFeedType,
+    pub executor_address: String,
+    pub portfolio_config: portfolio::PortfolioConfig,
+    #[serde(default)]
+    pub hedger: Option<hedger::HedgeConfig>,
+    // Price of one whole token y in the unit of the global risk limits
+    #[serde(default = "default_notional_px")]
+    pub notional_px: f64,
+    #[serde(default)]
+    pub reconcile: ReconcileConfig,
+}
+
+fn default_notional_px() -> f64 {
+    1.0
+}
+
+// Resources shared by every market in the process
+pub struct Shared<M> {
+    pub client: Arc<M>,
+    // Whitelisted callers that send market txs in turn. Empty when the client sends them
+    pub callers: Vec<Arc<M>>,
+    pub archive: Arc<Provider<Http>>,
+    pub weth: Address,
+    pub risk: Arc<Mutex<RiskManager>>,
+    pub treasury: Arc<Mutex<Treasury>>,
+    pub kill_rx: watch::Receiver<Option<KillCommand>>,
+    pub shutdown_rx: watch::Receiver<Option<ExitPolicy>>,
+    pub state_dir: String,
+    // Where to record frames for backtest and replay
+    pub record_dir: Option<String>,
+    pub health: health::Registry,
+    // Latest config that passed validation and can be applied live
+    pub config_rx: watch::Receiver<Arc<Config>>,
+    // Set when the RPC changes. Markets save their state and return so they can be restarted on the new providers
+    pub reconnect_rx: watch::Receiver<bool>,
+    // Simulate txs instead of sending them
+    pub dry_run: bool,
+}
+
+impl<M> Clone for Shared<M> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            callers: self.callers.clone(),
+            archive: self.archive.clone(),
+            weth: self.weth,
+            risk: self.risk.clone(),
+            treasury: self.treasury.clone(),
+            kill_rx: self.kill_rx.clone(),
+            shutdown_rx: self.shutdown_rx.clone(),
+            state_dir: self.state_dir.clone(),
+            record_dir: self.record_dir.clone(),
+            health: self.health.clone(),
+            config_rx: self.config_rx.clone(),
+            reconnect_rx: self.reconnect_rx.clone(),
+            dry_run: self.dry_run,
+        }
+    }
+}
+
+// Returns the admin link when stopped to reconnect, so the market can be restarted with it
+pub async fn run_market<M>(mut market: MarketConfig, shared: Shared<M>, mut admin: admin::MarketLink) -> Option<admin::MarketLink>
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+{
+    let Shared { client, callers, archive, weth, risk, treasury, mut kill_rx, mut shutdown_rx, state_dir, record_dir, health, mut config_rx, mut reconnect_rx, dry_run } = shared;
+    let x_id = Uuid::new_v4();
+    let y_id = Uuid::new_v4();
+
+    let amm = lb::LB::new_empty(x_id, y_id);
+    let address = market.executor_address.clone();
+    let new_client = client.clone();
+    let (tx, mut dex_rx) = watch::channel(amm.clone());
+    let producer = tokio::spawn(async move {
+        lb::LB::produce_new(amm, address.as_str(), archive, new_client.clone(), tx).await;
+    });
+    let (tx, mut cex_rx) = watch::channel(CexData::default());
+    generate_cex_feed(market.cex_param.clone(), tx);
+    assert!(dex_rx.changed().await.is_ok());
+    let mut amm = dex_rx.borrow().clone();
+    assert!(cex_rx.changed().await.is_ok());
+    let mut cex = cex_rx.borrow().clone();
+
+    let mut executor = executor::Executor::new(
+        client.clone(),
+        market.executor_address.parse::<Address>().unwrap(),
+        weth,
+        market.portfolio_config,
+    ).await;
+    executor.market = market.name.clone();
+    executor.dry_run = dry_run;
+    executor.callers = callers;
+    executor.treasury = treasury.clone();
+    let (x_amt, y_amt) = executor.get_balances().await;
+    let mypositions = executor.discover_positions().await;
+    let (x_dec, y_dec) = executor.get_decs().await;
+    info!(x_balance = x_amt, y_balance = y_amt, "Executor balances");
+
+    let mut portfolio = portfolio::Portfolio::new(
+        &amm,
+        x_amt,
+        y_amt,
+        x_dec,
+        y_dec,
+        market.portfolio_config,
+    );
+    portfolio.positions = get_positions(&executor, &amm, &mypositions).await;
+    let mut last_discovery = Instant::now();
+    // Bins the last walk of the whole pair found us holding. Always reconciled, wherever the active id is
+    let mut discovered = mypositions.keys().cloned().collect::<Vec<u32>>();
+
+    // Logs between the reads above and subscribing are picked up by the first resync
+    let (x_token, y_token) = executor.tokens();
+    let subs = tracker::spawn(client, market.name.clone(), executor.address, executor.pair_address, x_token, y_token);
+    let mut tracker = PositionTracker::new(subs, x_amt, y_amt, mypositions);
+    let mut reconciler = Reconciler::new(market.reconcile, amm.last_block);
+
+    let store = match StateStore::open(&state_dir, &market.name) {
+        Ok(store) => store,
+        Err(e) => {
+            error!(error = %e, state_dir = %state_dir, "Failed to open state. Stopping market");
+            producer.abort();
+            return None;
+        }
+    };
+    let saved = store.get();
+    // Recording is best effort, the market quotes without it
+    let mut recorder = record_dir.and_then(|dir| match sim::Recorder::open(&dir, &market.name) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            error!(error = %e, record_dir = %dir, "Failed to open recording");
+            None
+        }
+    });
+    executor.store = Some(store.clone());
+    executor.restore(&saved).await;
+    portfolio.restore(&saved);
+
+    let mut hedger = match market.hedger.clone().map(|hedge_config| hedger::Hedger::new(hedge_config, x_dec)).transpose() {
+        Ok(hedger) => hedger,
+        Err(e) => {
+            error!(error = %e, "Failed to start hedger. Stopping market");
+            producer.abort();
+            return None;
+        }
+    };
+    let mut block_executed = 0;
+    let mut last_dex = Instant::now();
+    let mut last_cex = Instant::now();
+    // Market as last read from the file. Admin patches apply until the file changes again
+    let mut file_market = market.clone();
+    let mut overrides = admin::Overrides::default();
+
+    executor.execute(executor::Execute::CheckGas, 0).await;
+    executor.execute(executor::Execute::CheckGas, 0).await;
+    loop {
+        health.beat(&market.name, health::Beat {
+            at: Instant::now(),
+            last_cex,
+            last_dex,
+            dex_block: amm.last_block,
+            gas_balance: executor.gas_balance,
+            min_gas: market.portfolio_config.min_gas,
+            reserve: executor.reserve,
+            min_reserve: treasury.lock().unwrap().config.map(|config| config.min_reserve),
+            topup_capped: executor.topup_capped,
+        });
+        metrics::FEED_AGE.with_label_values(&[&market.name, "dex"]).set(last_dex.elapsed().as_secs_f64());
+        metrics::FEED_AGE.with_label_values(&[&market.name, "cex"]).set(last_cex.elapsed().as_secs_f64());
+        admin.status.send_replace(admin::MarketStatus {
+            name: market.name.clone(),
+            block: amm.last_block,
+            active_id: amm.active_id,
+            cex_bid: cex.bid_px,
+            cex_ask: cex.ask_px,
+            config: Some(market.portfolio_config),
+            overrides,
+            halted: reconciler.halted,
+            x_balance: portfolio.x_balance,
+            y_balance: portfolio.y_balance,
+            positions: {
+                let mut bins = portfolio.positions.values().cloned().collect::<Vec<portfolio::Bin>>();
+                bins.sort_by_key(|bin| bin.id);
+                bins
+            },
+            signals: portfolio.signals,
+            treasury: treasury.lock().unwrap().report(&market.name),
+        });
+        tokio::select! {
+            biased;
+            res = shutdown_rx.changed() => {
+                // Txs are awaited inline, so nothing is in flight once we get here
+                let policy = *shutdown_rx.borrow();
+                if let (Ok(_), Some(policy)) = (res, policy) {
+                    info!(policy = ?policy, "Stopping market");
+                    match policy {
+                        ExitPolicy::Leave => (),
+                        ExitPolicy::CancelAll => { executor.kill(false, executor::KILL_SCAN_RANGE).await; },
+                        ExitPolicy::CancelAllAndSweep => { executor.kill(true, executor::KILL_SCAN_RANGE).await; },
+                    }
+                    store.update(|state| portfolio.export(state));
+                    return None;
+                }
+            },
+            res = kill_rx.changed() => {
+                let command = kill_rx.borrow().clone();
+                if let (Ok(_), Some(command)) = (res, command) {
+                    warn!(command = ?command, "Kill switch triggered. Stopping market");
+                    executor.kill(command.sweep, executor::KILL_SCAN_RANGE).await;
+                    store.update(|state| portfolio.export(state));
+                    return None;
+                }
+            },
+            Some(request) = admin.rx.recv() => {
+                let res = match request.command {
+                    admin::Command::PatchConfig(patch) => {
+                        let mut value = serde_json::to_value(market.portfolio_config).unwrap();
+                        admin::merge_patch(&mut value, &patch);
+                        match serde_json::from_value::<portfolio::PortfolioConfig>(value) {
+                            Ok(config) => match config.validate() {
+                                Ok(()) => {
+                                    info!(?config, "Config patched through admin API");
+                                    market.portfolio_config = config;
+                                    executor.config = config;
+                                    Ok(serde_json::to_value(config).unwrap())
+                                },
+                                Err(errors) => Err(errors.join("; ")),
+                            },
+                            Err(e) => Err(e.to_string()),
+                        }
+                    },
+                    admin::Command::Pause(pause) => {
+                        info!(pause = pause, "Pause set through admin API");
+                        overrides.pause = pause;
+                        Ok(serde_json::to_value(overrides).unwrap())
+                    },
+                    admin::Command::ReduceOnly(reduce_only) => {
+                        info!(reduce_only = reduce_only, "Reduce only set through admin API");
+                        overrides.reduce_only = reduce_only;
+                        Ok(serde_json::to_value(overrides).unwrap())
+                    },
+                    admin::Command::Execute(action) => {
+                        info!(action = ?action, "Manual action through admin API");
+                        let exposure = get_exposure(&portfolio, (cex.bid_px + cex.ask_px) / 2.0, market.notional_px);
+                        let checked = risk.lock().unwrap().check(&market.name, &action, &exposure, &portfolio.config);
+                        match checked {
+                            Ok(()) => match executor.execute(action.clone(), amm.active_id).await {
+                                Some(block) => {
+                                    block_executed = block;
+                                    if !tracker.wait_for_block(block).await {
+                                        resync(&executor, &mut tracker, action.bin_ids(amm.active_id), block).await;
+                                    }
+                                    set_balances(&mut portfolio, &tracker);
+                                    Ok(serde_json::json!({"block": block}))
+                                },
+                                None => Err("tx not mined".to_string()),
+                            },
+                            Err(limit) => Err(format!("rejected by risk limit {:?}", limit)),
+                        }
+                    },
+                };
+                request.reply.send(res).ok();
+                continue;
+            },
+            res = dex_rx.changed() => {
+                assert!(res.is_ok());
+                amm = dex_rx.borrow().clone();
+                last_dex = Instant::now();
+
+                cex = cex_rx.borrow().clone();
+                info!(dex_block = amm.last_block, "DEX block");
+            },
+            res = cex_rx.changed() => {
+                if !res.is_ok() {
+                    error!(error=?res, "websocket disconnected");
+                    metrics::RECONNECTS.with_label_values(&[&market.name, "cex"]).inc();
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    let tx;
+                    (tx, cex_rx) = watch::channel(CexData::default());
+                    generate_cex_feed(market.cex_param.clone(), tx);
+                    continue;
+                }
+                cex = cex_rx.borrow().clone();
+                last_cex = Instant::now();
+                info!(cex = ?cex, "CEX data");
+            },
+            res = reconnect_rx.changed() => {
+                if res.is_ok() && *reconnect_rx.borrow() {
+                    info!("Stopping market to reconnect");
+                    producer.abort();
+                    store.update(|state| portfolio.export(state));
+                    return Some(admin);
+                }
+            },
+            _ = config_rx.changed() => {
+                let new_market = match config_rx.borrow().markets().into_iter().find(|m| m.name == market.name) {
+                    Some(new_market) if new_market != file_market => new_market,
+                    _ => continue,
+                };
+                if new_market.portfolio_config != file_market.portfolio_config {
+                    info!(?new_market.portfolio_config, "New config");
+                    market.portfolio_config = new_market.portfolio_config;
+                    portfolio.config = new_market.portfolio_config;
+                    executor.config = new_market.portfolio_config;
+                }
+                if new_market.cex_param != file_market.cex_param {
+                    // The old feed stops on its next update, once it finds its receiver dropped
+                    info!(cex_param = ?new_market.cex_param, "Switching CEX feed");
+                    let tx;
+                    (tx, cex_rx) = watch::channel(CexData::default());
+                    generate_cex_feed(new_market.cex_param.clone(), tx);
+                }
+                if new_market.hedger != file_market.hedger {
+                    info!(hedger = ?new_market.hedger.as_ref().map(|hedger| &hedger.symbol), "Replacing hedger");
+                    match new_market.hedger.clone().map(|hedge_config| hedger::Hedger::new(hedge_config, x_dec)).transpose() {
+                        Ok(new_hedger) => hedger = new_hedger,
+                        Err(e) => error!(error = %e, "Failed to start the new hedger. Keeping the old one"),
+                    }
+                }
+                reconciler.config = new_market.reconcile;
+                market = MarketConfig { portfolio_config: market.portfolio_config, ..new_market.clone() };
+                file_market = new_market;
+            }
+        };
+
+        if block_executed > amm.last_block {
+            warn!(block_executed = block_executed, dex_block = amm.last_block, "DEX block not updated");
+            continue;
+        }
+
+        if last_discovery.elapsed() > DISCOVERY_INTERVAL {
+            last_discovery = Instant::now();
+            tracker.drain();
+            let held = executor.discover_positions().await;
+            discovered = held.keys().cloned().collect();
+            let untracked = held.keys().filter(|id| !tracker.tokens.contains_key(id)).cloned().collect::<Vec<u32>>();
+            if !untracked.is_empty() {
+                warn!(untracked = ?untracked, "Found bins missing from positions");
+                resync(&executor, &mut tracker, untracked, amm.last_block).await;
+            }
+        }
+        tracker.drain();
+        set_balances(&mut portfolio, &tracker);
+        if tracker.dirty.map_or(false, |block| amm.last_block >= block) {
+            portfolio.positions = get_positions(&executor, &amm, &tracker.tokens).await;
+            tracker.dirty = None;
+        }
+        // Only once the portfolio reflects exactly the AMM's block, so the chain can be read at that block
+        if reconciler.due(amm.last_block) && tracker.dirty.is_none() && tracker.last_block <= amm.last_block {
+            let mut ids = portfolio.positions.keys().cloned().collect::<Vec<u32>>();
+            ids.extend(discovered.iter().cloned());
+            ids.extend(around(amm.active_id, reconciler.config.scan_range));
+            ids.sort();
+            ids.dedup();
+            let snapshot = executor.snapshot(ids, amm.last_block).await;
+            if !reconciler.check(&portfolio, &snapshot).is_empty() {
+                let tokens = snapshot.bins.iter().map(|(id, (tokens, _, _, _))| (*id, *tokens)).collect();
+                tracker.resync(snapshot.x_balance, snapshot.y_balance, tokens, snapshot.block);
+            }
+        }
+
+        let cex_mid = (cex.bid_px + cex.ask_px) / 2.0;
+        let exposure = get_exposure(&portfolio, cex_mid, market.notional_px);
+        portfolio.config = market.portfolio_config;
+        portfolio.config.pause |= overrides.pause;
+        portfolio.config.reduce_only |= overrides.reduce_only;
+        {
+            let mut risk = risk.lock().unwrap();
+            risk.update(&market.name, &exposure);
+            match risk.breach(&market.name, &exposure, &portfolio.config) {
+                Some((_, BreachAction::ReduceOnly)) => portfolio.config.reduce_only = true,
+                Some((_, BreachAction::Pause)) => portfolio.config.pause = true,
+                None => (),
+            }
+        }
+        if reconciler.halted {
+            portfolio.config.pause = true;
+        }
+
+        let decision = metrics::DECISION.with_label_values(&[&market.name]).start_timer();
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(&sim::Frame::capture(&amm, amm.last_block, cex.bid_px, cex.ask_px, &portfolio));
+        }
+        let (action, id) = portfolio.on_state(cex.bid_px, cex.ask_px, &amm);
+        decision.observe_duration();
+        set_gauges(&market.name, &portfolio, cex_mid);
+        // A rejected action is dropped, the rest of the tick still runs
+        let action = action.filter(|action| risk.lock().unwrap().check(&market.name, action, &exposure, &portfolio.config).is_ok());
+        let mut changed = action.is_some();
+        executor.mark = Some(cex_mid);
+        let mut claim = match portfolio.claim_check_due() {
+            true => executor.claimable().await,
+            false => None,
+        };
+        if let Some(action) = action {
+            let executed = match claim.take() {
+                Some(claimable) => executor.execute_with_claim(action.clone(), id, claimable).await,
+                None => executor.execute(action.clone(), id).await,
+            };
+            if let Some(block) = executed {
+                block_executed = block;
+                if !tracker.wait_for_block(block).await {
+                    // Bins the action touched, and the ones around the active id
+                    let mut ids = action.bin_ids(id);
+                    ids.extend(around(amm.active_id, reconciler.config.scan_range));
+                    resync(&executor, &mut tracker, ids, block).await;
+                }
+                // Positions are recomputed once the AMM has caught up with the tx
+                set_balances(&mut portfolio, &tracker);
+            }
+        }
+        if let Some(claimable) = claim {
+            if let Some(block) = executor.claim(claimable).await {
+                block_executed = block;
+                tracker.wait_for_block(block).await;
+                set_balances(&mut portfolio, &tracker);
+                changed = true;
+            }
+        }
+        risk.lock().unwrap().update(&market.name, &get_exposure(&portfolio, cex_mid, market.notional_px));
+
+        if let Some(hedger) = hedger.as_mut() {
+            hedger.on_book(&cex);
+            let net_delta = portfolio.net_x_delta(&amm);
+            if let Some(fill) = tokio::task::block_in_place(|| hedger.on_delta(net_delta)) {
+                portfolio.apply_hedge(&fill, cex_mid);
+                changed = true;
+            }
+        }
+        if changed {
+            store.update(|state| portfolio.export(state));
+        }
+
+        info!(curid = id, block = amm.last_block, my_bins = ?portfolio.positions.iter().map(|(id, bin)| DisplayBin{ id: *id, x: bin.x as f64 / 10.0_f64.powi(x_dec as i32), y: bin.y as f64 / 10.0_f64.powi(y_dec as i32), tokens: bin.tokens}).collect::<Vec<DisplayBin>>());
+    }
+}
+
+// Bins within range of the active id, both ends included
+fn around(active_id: u32, range: u32) -> std::ops::RangeInclusive<u32> {
+    active_id.saturating_sub(range)..=active_id.saturating_add(range)
+}
+
+fn get_exposure(portfolio: &portfolio::Portfolio, cex_mid: f64, notional_px: f64) -> Exposure {
+    let (x_deployed, y_deployed) = portfolio.positions.values().fold((0, 0), |(x, y), bin| (x + bin.x, y + bin.y));
+    Exposure {
+        x_amt: x_deployed + portfolio.x_free,
+        y_amt: y_deployed + portfolio.y_free,
+        x_deployed,
+        y_deployed,
+        x_decimals: portfolio.x_decimals,
+        y_decimals: portfolio.y_decimals,
+        cex_mid: portfolio::Portfolio::dec(cex_mid),
+        notional_px: portfolio::Portfolio::dec(notional_px),
+    }
+}
+
+fn set_gauges(market: &str, portfolio: &portfolio::Portfolio, cex_mid: f64) {
+    let signals = portfolio.signals;
+    metrics::CEX_MID.with_label_values(&[market]).set(cex_mid);
+    metrics::DEX_BID.with_label_values(&[market]).set(signals.dex_bid);
+    metrics::DEX_ASK.with_label_values(&[market]).set(signals.dex_ask);
+    metrics::PX_SKEW.with_label_values(&[market]).set(signals.px_skew);
+    metrics::X_SKEW.with_label_values(&[market]).set(signals.x_skew);
+    metrics::DIRECTIONAL_SKEW.with_label_values(&[market]).set(signals.directional_skew);
+
+    let x_unit = 10.0_f64.powi(portfolio.x_decimals as i32);
+    let y_unit = 10.0_f64.powi(portfolio.y_decimals as i32);
+    let (x_deployed, y_deployed) = portfolio.positions.values().fold((0, 0), |(x, y), bin| (x + bin.x, y + bin.y));
+    metrics::DEPLOYED.with_label_values(&[market, "x"]).set(x_deployed as f64 / x_unit);
+    metrics::DEPLOYED.with_label_values(&[market, "y"]).set(y_deployed as f64 / y_unit);
+    metrics::FREE.with_label_values(&[market, "x"]).set(portfolio.x_free as f64 / x_unit);
+    metrics::FREE.with_label_values(&[market, "y"]).set(portfolio.y_free as f64 / y_unit);
+}
+
+fn set_balances(portfolio: &mut portfolio::Portfolio, tracker: &PositionTracker) {
+    portfolio.x_balance = tracker.x_balance;
+    portfolio.y_balance = tracker.y_balance;
+    portfolio.x_free = tracker.x_balance;
+    portfolio.y_free = tracker.y_balance;
+}
+
+// Reads balances, and LB tokens of every tracked bin plus `extra`, from the chain
+async fn resync<M: Middleware>(executor: &executor::Executor<M>, tracker: &mut PositionTracker, extra: Vec<u32>, block: u64) -> bool {
+    tracker.drain();
+    let (x_amt, y_amt) = executor.get_balances().await;
+    let mut ids = tracker.tokens.keys().cloned().collect::<Vec<u32>>();
+    ids.extend(extra);
+    ids.sort();
+    ids.dedup();
+    let tokens = executor.get_liq_tokens(ids).await;
+    tracker.resync(x_amt, y_amt, tokens, block)
+}
+
+// LB token balances to token amounts. Uses the AMM's view of each bin, and the chain for bins it doesn't track
+async fn get_positions<M: Middleware>(executor: &executor::Executor<M>, amm: &lb::LB, tokens: &HashMap<u32, u128>) -> HashMap<u32, portfolio::Bin> {
+    let untracked = tokens.keys().filter(|id| !amm.bins.contains_key(id) || !amm.supply.contains_key(id)).cloned().collect::<Vec<u32>>();
+    let chain_bins = match untracked.is_empty() {
+        true => HashMap::new(),
+        false => executor.get_bins(untracked).await,
+    };
+    tokens.iter().map(|(id, tokens)| {
+        let (bin_x, bin_y, total_tokens) = match chain_bins.get(id) {
+            Some((x, y, total_tokens)) => (*x, *y, *total_tokens),
+            None => {
+                let bin = amm.bins.get(id).unwrap();
+                (bin.x, bin.y, *amm.supply.get(id).unwrap())
+            }
+        };
+        (*id, portfolio::Bin {
+            id: *id,
+            x: ((bin_x * U256::from(*tokens)) / U256::from(total_tokens)).as_u128(),
+            y: ((bin_y * U256::from(*tokens)) / U256::from(total_tokens)).as_u128(),
+            tokens: *tokens,
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metrics_server_serves_market_gauges_and_decision_latency() {
+        let amm = lb::LB::new_empty(Uuid::new_v4(), Uuid::new_v4());
+        let mut portfolio = portfolio::Portfolio::new(&amm, 3 * 10u128.pow(18), 40 * 10u128.pow(6), 18, 6, portfolio::tests::config());
+        portfolio.positions.insert(1 << 23, portfolio::Bin { id: 1 << 23, x: 10u128.pow(18), y: 0, tokens: 1 });
+        set_gauges("metrics-test", &portfolio, 20.0);
+        metrics::DECISION.with_label_values(&["metrics-test"]).start_timer().observe_duration();
+
+        let addr = metrics::spawn_server("127.0.0.1:0".parse().unwrap()).unwrap();
+        let res = reqwest::get(format!("http://{}/metrics", addr)).await.unwrap();
+        assert!(res.status().is_success());
+        let body = res.text().await.unwrap();
+        assert!(body.contains(r#"quoter_cex_mid{market="metrics-test"} 20"#));
+        assert!(body.contains(r#"quoter_deployed{market="metrics-test",token="x"} 1"#));
+        assert!(body.contains(r#"quoter_free{market="metrics-test",token="y"} 40"#));
+        assert!(body.contains(r#"quoter_x_skew{market="metrics-test"}"#));
+        assert!(body.contains(r#"quoter_decision_seconds_count{market="metrics-test"} 1"#));
+
+        let res = reqwest::get(format!("http://{}/other", addr)).await.unwrap();
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+}
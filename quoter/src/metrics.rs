@@ -0,0 +1,81 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramVec, IntCounterVec, TextEncoder,
+    histogram_opts, opts, register_counter_vec, register_gauge_vec, register_histogram_vec, register_int_counter_vec,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::{info, error};
+
+lazy_static! {
+    // Prices, skews and balances as of the last on_state call. Balances are in whole tokens
+    pub static ref CEX_MID: GaugeVec = register_gauge_vec!("quoter_cex_mid", "Mid price of the CEX feed", &["market"]).unwrap();
+    pub static ref DEX_BID: GaugeVec = register_gauge_vec!("quoter_dex_bid", "Best bid of the pair", &["market"]).unwrap();
+    pub static ref DEX_ASK: GaugeVec = register_gauge_vec!("quoter_dex_ask", "Best ask of the pair", &["market"]).unwrap();
+    pub static ref PX_SKEW: GaugeVec = register_gauge_vec!("quoter_px_skew", "Skew from the DEX price relative to the CEX", &["market"]).unwrap();
+    pub static ref X_SKEW: GaugeVec = register_gauge_vec!("quoter_x_skew", "Share of the portfolio held in token x", &["market"]).unwrap();
+    pub static ref DIRECTIONAL_SKEW: GaugeVec = register_gauge_vec!("quoter_directional_skew", "Combined price and portfolio skew", &["market"]).unwrap();
+    pub static ref DEPLOYED: GaugeVec = register_gauge_vec!("quoter_deployed", "Tokens deployed as liquidity", &["market", "token"]).unwrap();
+    pub static ref FREE: GaugeVec = register_gauge_vec!("quoter_free", "Tokens held by the executor and not deployed", &["market", "token"]).unwrap();
+    pub static ref GAS_BALANCE: GaugeVec = register_gauge_vec!("quoter_gas_balance", "Native balance of the caller as of the last gas check", &["market"]).unwrap();
+    pub static ref WETH_RESERVE: GaugeVec = register_gauge_vec!("quoter_weth_reserve", "WETH held by the contract as of the last gas check", &["market"]).unwrap();
+    pub static ref FEE_PNL: GaugeVec = register_gauge_vec!("quoter_fee_pnl", "Claimed fees valued in token y at the CEX mid when claimed", &["market"]).unwrap();
+    pub static ref COST_PER_TRADE: GaugeVec = register_gauge_vec!("quoter_cost_per_trade", "Native gas spent over mined trades", &["market"]).unwrap();
+    pub static ref HEALTHY: GaugeVec = register_gauge_vec!("quoter_healthy", "1 while every liveness check passes", &["market"]).unwrap();
+    pub static ref FEED_AGE: GaugeVec = register_gauge_vec!("quoter_feed_age_seconds", "Seconds since the feed last updated", &["market", "feed"]).unwrap();
+
+    pub static ref TXS: IntCounterVec = register_int_counter_vec!("quoter_txs_total", "Txs by action and outcome", &["market", "action", "outcome"]).unwrap();
+    pub static ref REVERTS: IntCounterVec = register_int_counter_vec!("quoter_reverts_total", "Reverted txs by revert reason", &["market", "reason"]).unwrap();
+    pub static ref GAS_SPENT: CounterVec = register_counter_vec!("quoter_gas_spent_total", "Native gas paid by action", &["market", "action"]).unwrap();
+    pub static ref GAS_TOPPED_UP: CounterVec = register_counter_vec!("quoter_gas_topped_up_total", "WETH unwrapped into callers' gas", &["market"]).unwrap();
+    pub static ref RECONNECTS: IntCounterVec = register_int_counter_vec!("quoter_reconnects_total", "Feed and subscription reconnects", &["market", "feed"]).unwrap();
+
+    pub static ref TX_CONFIRMATION: HistogramVec = register_histogram_vec!(
+        "quoter_tx_confirmation_seconds",
+        "Time from sending a tx to its receipt",
+        &["market", "action"],
+        vec![0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0, 60.0]
+    ).unwrap();
+    pub static ref DECISION: HistogramVec = register_histogram_vec!(
+        "quoter_decision_seconds",
+        "Time spent in Portfolio::on_state",
+        &["market"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5]
+    ).unwrap();
+}
+
+// Serves every registered metric in the Prometheus text format on /metrics. Returns the bound address
+pub fn spawn_server(addr: SocketAddr) -> Option<SocketAddr> {
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(serve)) });
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server.serve(make_svc),
+        Err(e) => {
+            error!(addr = %addr, error = ?e, "Failed to bind metrics server");
+            return None;
+        }
+    };
+    let local_addr = server.local_addr();
+    info!(addr = %local_addr, "Serving metrics");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!(error = ?e, "Metrics server stopped");
+        }
+    });
+    Some(local_addr)
+}
+
+async fn serve(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        return Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap());
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(error = ?e, "Failed to encode metrics");
+        return Ok(Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap());
+    }
+    Ok(Response::builder().header(CONTENT_TYPE, encoder.format_type()).body(Body::from(buffer)).unwrap())
+}
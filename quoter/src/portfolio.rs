@@ -6,9 +6,70 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::cmp::{min, max};
 use amm::{AMM, lb};
 use tracing::{trace, debug, info, warn, error};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal_macros::dec;
 use crate::executor::*;
+use crate::pricing::{self, PricingModel, VolEstimator};
+use crate::hedger::HedgeFill;
+use crate::state::{self, MarketState};
 use std::time::{Instant, Duration};
 
+// Furthest a model quote can be placed from the active bin
+const MAX_BIN_SEARCH: u32 = 500;
+// How often pending fees are checked against the gas to claim them
+const CLAIM_CHECK_INTERVAL: Duration = Duration::from_secs(120);
+// Decimal places kept for prices and fractions of the portfolio
+const PRICE_DP: u32 = 18;
+
+// The pair as decisions read it. Implemented by the live AMM, and by sim::PairSim for replays and backtests
+pub trait Pair {
+    fn tokens(&self) -> (Uuid, Uuid);
+    fn bin_step(&self) -> u16;
+    fn active_id(&self) -> u32;
+    // Reserves (x, y) and LB token supply of a bin
+    fn reserves(&self, id: u32) -> Option<(u128, u128)>;
+    fn supply(&self, id: u32) -> Option<u128>;
+    // Token y received for selling x_unit of token x, and paid for buying it
+    fn bid_ask(&self, x_unit: U256) -> (U256, U256);
+    // Input needed to receive amount_out. Sells x for y when swap_for_y
+    fn amount_in(&self, amount_out: u128, swap_for_y: bool) -> u128;
+}
+
+impl Pair for lb::LB {
+    fn tokens(&self) -> (Uuid, Uuid) {
+        (self.token_x, self.token_y)
+    }
+
+    fn bin_step(&self) -> u16 {
+        self.fee.bin_step
+    }
+
+    fn active_id(&self) -> u32 {
+        self.active_id
+    }
+
+    fn reserves(&self, id: u32) -> Option<(u128, u128)> {
+        self.bins.get(&id).map(|bin| (bin.x.as_u128(), bin.y.as_u128()))
+    }
+
+    fn supply(&self, id: u32) -> Option<u128> {
+        self.supply.get(&id).copied()
+    }
+
+    fn bid_ask(&self, x_unit: U256) -> (U256, U256) {
+        self.get_bid_ask(self.token_y, self.token_x, x_unit).unwrap()
+    }
+
+    fn amount_in(&self, amount_out: u128, swap_for_y: bool) -> u128 {
+        let (token_in, token_out) = match swap_for_y {
+            true => (self.token_x, self.token_y),
+            false => (self.token_y, self.token_x),
+        };
+        self.get_amount_in(token_in, token_out, amount_out.into()).unwrap().as_u128()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Portfolio {
     // #[serde(skip)]
@@ -29,15 +90,32 @@ pub struct Portfolio {
 
     // Bins with active liquidity and corresponding token balances
     pub positions: HashMap<u32, Bin>,
+
+    // Token x held on the hedging venue, and quote paid/received for it
+    pub x_hedge: i128,
+    pub hedge_cash: Decimal,
+    // Inputs of the last on_state decision
+    pub signals: Signals,
     // pub max_bid: f64,
     // pub min_ask: f64,
     // pub pos_ids: Vec<u32>,
     
     pub config: PortfolioConfig,
     bin_step: u16,
+    // Last time pending fees were checked for a claim
     last_fee_claim: Instant,
     last_gas_check: Instant,
     last_rebalance: Instant,
+    vol: VolEstimator,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Signals {
+    pub dex_bid: f64,
+    pub dex_ask: f64,
+    pub px_skew: f64,
+    pub x_skew: f64,
+    pub directional_skew: f64,
 }
 
 #[derive(PartialEq, Copy, Clone, Debug, Serialize, Deserialize)]
@@ -51,7 +129,8 @@ pub struct Bin {
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct PortfolioConfig {
     // Represents the minimum balance of token to stay delta neutral
-    // Used when a token is borrowed
+    // Used when a token is borrowed. Skews, deployable amounts and rebalances target holding exactly this much.
+    // With both set, each is held and whatever value is left over is split evenly
     pub token_x_delta: Option<u128>,
     pub token_y_delta: Option<u128>,
 
@@ -78,6 +157,12 @@ pub struct PortfolioConfig {
     // Is > max_skew
     pub taker_scaling_factor: f64,
 
+    // Max absolute balance of each token, deployed or free. Enforced by the risk module
+    #[serde(default)]
+    pub max_x_inventory: Option<u128>,
+    #[serde(default)]
+    pub max_y_inventory: Option<u128>,
+
     // Do not post new orders. Only cancel or close positions
     pub reduce_only: bool,
     // Pause
@@ -96,34 +181,74 @@ pub struct PortfolioConfig {
 
     pub take_gas_price_scaling: u64,
     pub gas_constant: u64,
-    
+
+    // Model used to decide which bins to provide
+    #[serde(default)]
+    pub pricing_model: PricingModel,
+
+    // Claim once pending fees are worth this many times the gas to claim them
+    #[serde(default = "default_claim_gas_multiple")]
+    pub claim_gas_multiple: f64,
+    // Token y per native token, to value gas when neither token is the wrapped native token
+    #[serde(default)]
+    pub native_px: Option<f64>,
+}
+
+fn default_claim_gas_multiple() -> f64 {
+    3.0
+}
+
+impl PortfolioConfig {
+    // Every invalid field, as `field: reason`
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &str, reason: &str| if !ok {
+            errors.push(format!("{}: {}", field, reason));
+        };
+        check(self.token_x_dust > 0, "token_x_dust", "must be > 0");
+        check(self.token_y_dust > 0, "token_y_dust", "must be > 0");
+        check((0.0..1.0).contains(&self.token_x_reserve), "token_x_reserve", "must be in [0, 1)");
+        check((0.0..1.0).contains(&self.token_y_reserve), "token_y_reserve", "must be in [0, 1)");
+        check(self.taker_profit_bps < 10000, "taker_profit_bps", "must be < 10000");
+        check(self.maker_loss_bps < 10000, "maker_loss_bps", "must be < 10000");
+        check(self.tx_limit_5min > 0, "tx_limit_5min", "must be > 0");
+        check(self.max_skew > 0.5 && self.max_skew <= 1.0, "max_skew", "must be in (0.5, 1]");
+        check(self.taker_scaling_factor > 0.5, "taker_scaling_factor", "must be > 0.5");
+        check(self.px_skew_factor >= 0.0 && self.portfolio_skew_factor >= 0.0, "px_skew_factor", "skew factors must be >= 0");
+        check(self.px_skew_factor + self.portfolio_skew_factor > 0.0, "portfolio_skew_factor", "px_skew_factor + portfolio_skew_factor must be > 0");
+        check(self.px_scaling_factor.is_finite() && self.px_scaling_factor > 0.0, "px_scaling_factor", "must be > 0");
+        check(self.rebalance_interval > 0, "rebalance_interval", "must be > 0");
+        check(self.take_gas_price_scaling > 0, "take_gas_price_scaling", "must be > 0");
+        check(self.gas_constant > 0, "gas_constant", "must be > 0");
+        check(self.claim_gas_multiple.is_finite() && self.claim_gas_multiple >= 0.0, "claim_gas_multiple", "must be >= 0");
+        check(self.native_px.map_or(true, |px| px > 0.0), "native_px", "must be > 0");
+        if let PricingModel::AvellanedaStoikov{risk_aversion, order_intensity, horizon_secs, vol_halflife_secs, bins_per_side, ..} = self.pricing_model {
+            check(risk_aversion > 0.0, "pricing_model.risk_aversion", "must be > 0");
+            check(order_intensity > 0.0, "pricing_model.order_intensity", "must be > 0");
+            check(horizon_secs > 0.0, "pricing_model.horizon_secs", "must be > 0");
+            check(vol_halflife_secs > 0.0, "pricing_model.vol_halflife_secs", "must be > 0");
+            check(bins_per_side > 0, "pricing_model.bins_per_side", "must be > 0");
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
 }
 
 impl Portfolio {
     pub fn new(
         // amm: Arc<RwLock<lb::LB>>,
-        amm: &lb::LB,
+        amm: &impl Pair,
         x_balance: u128,
         y_balance: u128,
         x_decimals: usize,
         y_decimals: usize,
         mut config: PortfolioConfig,
     ) -> Self {
-        assert!(config.token_x_reserve < 1.0);
-        assert!(config.token_y_reserve < 1.0);
-        assert!(config.max_skew > 0.5, "max_skew must be > 0.5");
-        assert!(config.taker_scaling_factor > 0.5, "taker_scaling_factor must be > 0.5");
-        assert!(config.taker_profit_bps < 10000, "taker_profit_bps must be < 10000");
-        assert!(config.maker_loss_bps < 10000, "maker_loss_bps must be < 10000");
-        let token_x;
-        let token_y;
-        let bin_step;
-        {
-            // let unlocked = amm.read().unwrap();
-            token_x = amm.token_x;
-            token_y = amm.token_y;
-            bin_step = amm.fee.bin_step;
-        }
+        // config has been through PortfolioConfig::validate when it was loaded
+        let (token_x, token_y) = amm.tokens();
+        let bin_step = amm.bin_step();
         
         Self {
             token_x,
@@ -137,6 +262,9 @@ impl Portfolio {
             x_free: x_balance,
             y_free: y_balance,
             positions: HashMap::new(),
+            x_hedge: 0,
+            hedge_cash: Decimal::ZERO,
+            signals: Signals::default(),
             // pos_ids: Vec::new(),
             // max_bid: f64::NAN,
             // min_ask: f64::NAN,
@@ -144,37 +272,53 @@ impl Portfolio {
             last_fee_claim: Instant::now() - Duration::from_secs(60*60),
             last_gas_check: Instant::now() - Duration::from_secs(60*60),
             last_rebalance: Instant::now(),
+            vol: VolEstimator::default(),
         }
     }
 
-    pub fn on_state(&mut self, cex_bid: f64, cex_ask: f64, amm: &lb::LB) -> (Option<Execute>, u32) {
+    pub fn on_state(&mut self, cex_bid: f64, cex_ask: f64, amm: &impl Pair) -> (Option<Execute>, u32) {
         if self.config.pause {
             return (None, 0);
         }
+        if !(cex_bid.is_finite() && cex_ask.is_finite() && cex_bid > 0.0 && cex_ask > 0.0) {
+            warn!(cex_bid = cex_bid, cex_ask = cex_ask, "No usable CEX price, not quoting");
+            return (None, 0);
+        }
 
-        let max_bid = cex_bid * (10000 + self.config.maker_loss_bps) as f64 / 10000.0;
-        let min_ask = cex_ask * (10000 - self.config.maker_loss_bps) as f64 / 10000.0;
+        // Prices are in y per x. Fractions of the portfolio are exact decimals applied to token amounts, rounding down
+        let (dec_bid, dec_ask) = (Self::dec(cex_bid), Self::dec(cex_ask));
+        let thresholds = (
+            dec_bid.checked_mul(Self::bps(10000 + self.config.maker_loss_bps)),
+            dec_ask.checked_mul(Self::bps(10000 - self.config.maker_loss_bps)),
+            dec_bid.checked_mul(Self::bps(10000 - self.config.taker_profit_bps)),
+            dec_ask.checked_mul(Self::bps(10000 + self.config.taker_profit_bps)),
+        );
+        let (max_bid, min_ask, bid_threshold, ask_threshold) = match thresholds {
+            (Some(max_bid), Some(min_ask), Some(bid_threshold), Some(ask_threshold)) => (max_bid, min_ask, bid_threshold, ask_threshold),
+            _ => return Self::overflow("thresholds", 0),
+        };
         // let max_bid = cex_bid;
         // let min_ask = cex_ask;
-        let bid_threshold = cex_bid * (10000 - self.config.taker_profit_bps) as f64 / 10000.0;
-        let ask_threshold = cex_ask * (10000 + self.config.taker_profit_bps) as f64 / 10000.0;
         let (active_id, active_bin, cur_bid, cur_ask) = {
             // let amm = self.amm.read().unwrap();
-            let active_id = amm.active_id;
-            let active_bin = amm.bins.get(&active_id).unwrap().clone();
-            let (cur_bid, cur_ask) = amm.get_bid_ask(self.token_y, self.token_x, U256::exp10(self.x_decimals)).unwrap();
+            let active_id = amm.active_id();
+            let active_bin = amm.reserves(active_id).unwrap();
+            let (cur_bid, cur_ask) = amm.bid_ask(U256::exp10(self.x_decimals));
 
             (active_id, active_bin, cur_bid, cur_ask)
         };
-        let cur_bid = cur_bid.as_u128() as f64 / 10.0_f64.powi(self.y_decimals as i32);
-        let cur_ask = cur_ask.as_u128() as f64 / 10.0_f64.powi(self.y_decimals as i32);
+        let cur_bid = Self::to_dec(cur_bid, self.y_decimals as u32);
+        let cur_ask = Self::to_dec(cur_ask, self.y_decimals as u32);
         
         
         let px_128 = lb::Bin::getPriceFromId(active_id.into(), self.bin_step.into());
         let cur_mid = self.get_fpx(active_id);
         let cex_mid = (cex_bid + cex_ask) / 2.0;
-        let px_skew = self.config.px_scaling_factor * ((cur_mid / cex_mid) - 1.0) + 0.5; // If > 0.5, we have too much token x and we want to dump. If <0.5 token x is underpriced and we want to buy
-        let px_skew = 0.0_f64.max(px_skew).min(1.0);
+        if let PricingModel::AvellanedaStoikov{vol_halflife_secs, ..} = self.config.pricing_model {
+            self.vol.update(cex_mid, vol_halflife_secs);
+        }
+        let px_skew = Self::dec(self.config.px_scaling_factor).saturating_mul(cur_mid.checked_div(Self::dec(cex_mid)).unwrap_or(Decimal::MAX) - Decimal::ONE).saturating_add(dec!(0.5)); // If > 0.5, we have too much token x and we want to dump. If <0.5 token x is underpriced and we want to buy
+        let px_skew = Self::clamp(px_skew);
         let mut x_deployed = 0;
         let mut y_deployed = 0;
         let mut positions = Vec::new();
@@ -188,98 +332,145 @@ impl Portfolio {
         // X balance in terms of Y. Used for portfolio skew calculation
         let x_amt = x_deployed + self.x_free;
         let y_amt = y_deployed + self.y_free;
-        let implied_x_value = Self::x_in_terms_of_y(px_128, x_amt.into()).as_u128();
-        let x_skew = implied_x_value  as f64 / (implied_x_value as f64 + y_amt as f64) ;
+        let implied_x_value = Self::x_in_terms_of_y(px_128, x_amt);
+        // Hedges offset the DEX inventory when skewing, but can't be deployed
+        let hedged_x_value = Self::x_in_terms_of_y(px_128, self.hedged(x_amt));
+        let total_value = match implied_x_value.checked_add(y_amt) {
+            Some(total_value) => total_value,
+            None => return Self::overflow("total_value", active_id),
+        };
 
-        let directional_skew = (self.config.px_skew_factor * px_skew) + (self.config.portfolio_skew_factor * x_skew);
-        let directional_skew = directional_skew / (self.config.px_skew_factor + self.config.portfolio_skew_factor);
+        // Skews are measured against the delta neutral split, so 0.5 is always neutral
+        let x_target_value = self.x_target_value(px_128, total_value);
+        let skew_offset = match total_value {
+            0 => Decimal::ZERO,
+            _ => Self::ratio(x_target_value, total_value) - dec!(0.5),
+        };
+        let x_skew = Self::ratio(hedged_x_value, hedged_x_value.saturating_add(y_amt));
+        let x_skew = Self::clamp(x_skew - skew_offset);
 
-        let total_value = implied_x_value.checked_add(y_amt).unwrap();
+        let (px_skew_factor, portfolio_skew_factor) = (Self::dec(self.config.px_skew_factor), Self::dec(self.config.portfolio_skew_factor));
+        let directional_skew = px_skew_factor.checked_mul(px_skew)
+            .zip(portfolio_skew_factor.checked_mul(x_skew))
+            .and_then(|(px_skew, x_skew)| px_skew.checked_add(x_skew))
+            .zip(px_skew_factor.checked_add(portfolio_skew_factor))
+            .and_then(|(skew, factors)| skew.checked_div(factors));
+        let directional_skew = match directional_skew {
+            Some(directional_skew) => directional_skew,
+            None => return Self::overflow("directional_skew", active_id),
+        };
+        let x_frac = Self::clamp(directional_skew + skew_offset);
 
-        let mut x_deployable_in_y = (min(implied_x_value, (total_value as f64 * directional_skew) as u128) as f64 * (1.0 - self.config.token_x_reserve)) as u128;
-        let mut y_deployable = (min(y_amt, (total_value as f64 * (1.0 - directional_skew)) as u128) as f64 * (1.0 - self.config.token_y_reserve)) as u128;
-        let mut x_deployable = Self::y_in_terms_of_x(px_128, x_deployable_in_y.into()).as_u128();
+        let (max_skew, taker_scaling_factor) = (Self::dec(self.config.max_skew), Self::dec(self.config.taker_scaling_factor));
+        let (x_keep, y_keep) = (Decimal::ONE - Self::dec(self.config.token_x_reserve), Decimal::ONE - Self::dec(self.config.token_y_reserve));
+        let mut x_deployable_in_y = Self::frac_of(min(implied_x_value, Self::frac_of(total_value, x_frac)), x_keep);
+        let mut y_deployable = Self::frac_of(min(y_amt, Self::frac_of(total_value, Decimal::ONE - x_frac)), y_keep);
+        let mut x_deployable = Self::y_in_terms_of_x(px_128, x_deployable_in_y);
         info!(
             active_id = active_id, 
-            cur_bid = cur_bid,
-            cur_mid = cur_mid, 
-            cur_ask = cur_ask,
+            cur_bid = %cur_bid,
+            cur_mid = %cur_mid, 
+            cur_ask = %cur_ask,
 
             cex_bid = cex_bid,
             cex_mid = cex_mid, 
             cex_ask = cex_ask,
 
-            max_bid = max_bid,
-            min_ask = min_ask,
-            bid_threshold = bid_threshold,
-            ask_threshold = ask_threshold,
+            max_bid = %max_bid,
+            min_ask = %min_ask,
+            bid_threshold = %bid_threshold,
+            ask_threshold = %ask_threshold,
 
-            px_skew = px_skew,
+            px_skew = %px_skew,
             y_amt = y_amt, 
             x_amt = x_amt,
             x_value = implied_x_value, 
-            x_skew = x_skew,
-            directional_skew = directional_skew,
+            x_target_value = x_target_value,
+            x_skew = %x_skew,
+            directional_skew = %directional_skew,
             x_deployable = x_deployable,
             x_deployable_in_y = x_deployable_in_y,
             y_deployable = y_deployable,
         );
+        self.signals = Signals {
+            dex_bid: cur_bid.to_f64().unwrap_or_default(),
+            dex_ask: cur_ask.to_f64().unwrap_or_default(),
+            px_skew: px_skew.to_f64().unwrap_or_default(),
+            x_skew: x_skew.to_f64().unwrap_or_default(),
+            directional_skew: directional_skew.to_f64().unwrap_or_default(),
+        };
 
         let mut position_wanted = HashMap::new();
         if cur_bid < max_bid && cur_ask > min_ask {
-            // Only add to active if the price is good
-            let (active_x,active_y) = Self::get_ratio(x_deployable, y_deployable, active_bin.x.as_u128(), active_bin.y.as_u128());
-            if let Some(my_active) = self.positions.get(&active_id) {
-                info!(
-                    active_bin= ?active_bin,
-                    my_liquidity = my_active.tokens as f64 / *amm.supply.get(&active_id).unwrap() as f64,
-                );
-                position_wanted.insert(active_id, (active_x, active_y));
-                x_deployable -= active_x;
-                y_deployable -= active_y;
-            } else if cur_bid < max_bid * 0.9999 && cur_ask > min_ask * 1.0001 {
-                // Add additional 1bp threshold if we are not already in the active bin
-                position_wanted.insert(active_id, (active_x, active_y));
-                x_deployable -= active_x;
-                y_deployable -= active_y;
+            match self.config.pricing_model {
+                PricingModel::Heuristic => {
+                    // Only add to active if the price is good
+                    let (active_x,active_y) = Self::get_ratio(x_deployable, y_deployable, active_bin.0, active_bin.1);
+                    let (entry_bid, entry_ask) = match (max_bid.checked_mul(dec!(0.9999)), min_ask.checked_mul(dec!(1.0001))) {
+                        (Some(entry_bid), Some(entry_ask)) => (entry_bid, entry_ask),
+                        _ => return Self::overflow("entry thresholds", active_id),
+                    };
+                    if let Some(my_active) = self.positions.get(&active_id) {
+                        info!(
+                            active_bin= ?active_bin,
+                            my_liquidity = %Self::ratio(my_active.tokens, amm.supply(active_id).unwrap()),
+                        );
+                        position_wanted.insert(active_id, (active_x, active_y));
+                        x_deployable -= active_x;
+                        y_deployable -= active_y;
+                    } else if cur_bid < entry_bid && cur_ask > entry_ask {
+                        // Add additional 1bp threshold if we are not already in the active bin
+                        position_wanted.insert(active_id, (active_x, active_y));
+                        x_deployable -= active_x;
+                        y_deployable -= active_y;
+                    }
+                },
+                PricingModel::AvellanedaStoikov{..} => {
+                    // Inventory is handled by the reservation price, so deploy everything outside of reserves
+                    position_wanted = self.get_model_positions(active_id, cex_mid, x_skew.to_f64().unwrap_or_default(), x_amt, y_amt);
+                },
             }
-            
-            
-        } else if cur_ask < bid_threshold && x_skew < self.config.max_skew {
+        } else if cur_ask < bid_threshold && x_skew < max_skew {
             // We are underpriced, we want to buy
-            let scaling = (self.config.taker_scaling_factor * ((bid_threshold / cur_ask) - 1.0)) + 0.95;
-            let target = (total_value as f64 * 1.0_f64.min(self.config.max_skew * scaling).max(x_skew)) as u128;
+            let scaling = match Self::taker_scaling(taker_scaling_factor, bid_threshold.checked_div(cur_ask)) {
+                Some(scaling) => scaling,
+                None => return Self::overflow("taker scaling", active_id),
+            };
+            let target = Self::frac_of(total_value, Self::clamp(Decimal::ONE.min(max_skew.saturating_mul(scaling)).max(x_skew) + skew_offset));
             let mut x_out = match target > implied_x_value {
-                true => Self::y_in_terms_of_x(px_128, (target - implied_x_value).into()).as_u128(),
+                true => Self::y_in_terms_of_x(px_128, (target - implied_x_value)),
                 false => 0,
             };
             if let Some(bin) = self.positions.get(&active_id) {
                 // Pull liquidity and send taker order
-                x_out = min(x_out, active_bin.x.as_u128() - bin.x);
+                x_out = min(x_out, active_bin.0 - bin.x);
             } else {
                 // Send taker order only
-                x_out = min(x_out, active_bin.x.as_u128());
+                x_out = min(x_out, active_bin.0);
             }
-            info!(x_out = x_out, scaling = scaling, "DEX underpriced");
+            info!(x_out = x_out, scaling = %scaling, "DEX underpriced");
             if let Some(order) = self.make_take(amm, x_out, false) {
                 return (Some(order), active_id)
             }
-        } else if cur_bid > ask_threshold && x_skew > 1.0 - self.config.max_skew {
+        } else if cur_bid > ask_threshold && x_skew > Decimal::ONE - max_skew {
             // We are overpriced, we want to dump
-            let scaling = (self.config.taker_scaling_factor * ((cur_bid / ask_threshold) - 1.0)) + 0.95;
-            let target = (total_value as f64 * 1.0_f64.min(self.config.max_skew * scaling).max(1.0 - x_skew)) as u128;
+            let scaling = match Self::taker_scaling(taker_scaling_factor, cur_bid.checked_div(ask_threshold)) {
+                Some(scaling) => scaling,
+                None => return Self::overflow("taker scaling", active_id),
+            };
+            let target = Self::frac_of(total_value, Self::clamp(Decimal::ONE.min(max_skew.saturating_mul(scaling)).max(Decimal::ONE - x_skew) - skew_offset));
             let mut y_out = match target > y_amt {
                 true => (target - y_amt),
                 false => 0,
             };
             if let Some(bin) = self.positions.get(&active_id) {
                 // Pull liquidity and send taker order
-                y_out = min(y_out, active_bin.y.as_u128() - bin.y);
+                y_out = min(y_out, active_bin.1 - bin.y);
             } else {
                 // Send taker order only
-                y_out = min(y_out, active_bin.y.as_u128());
+                y_out = min(y_out, active_bin.1);
             }
-            info!(y_out = y_out, scaling = scaling, "DEX overpriced");
+            info!(y_out = y_out, scaling = %scaling, "DEX overpriced");
             if let Some(order) = self.make_take(amm, y_out, true) {
                 return (Some(order), active_id)
             }
@@ -287,35 +478,43 @@ impl Portfolio {
         // Too much delta. Rebalance portfolio.
         if self.last_rebalance.elapsed() > std::time::Duration::from_secs(60 * self.config.rebalance_interval) {
             self.last_rebalance = Instant::now();
-            if directional_skew > self.config.max_skew {
+            if directional_skew > max_skew {
                 // Too much x, sell some
                 // Scaling should always be > 1
-                let scaling = directional_skew / self.config.max_skew;
-                let mut y_out = ((total_value as f64 * ((1.0 - self.config.max_skew) * scaling).min(0.5)) as u128 - y_amt);
+                let scaling = match directional_skew.checked_div(max_skew) {
+                    Some(scaling) => scaling,
+                    None => return Self::overflow("rebalance scaling", active_id),
+                };
+                let y_frac = Self::clamp((Decimal::ONE - max_skew).saturating_mul(scaling).min(dec!(0.5)) - skew_offset);
+                let mut y_out = Self::frac_of(total_value, y_frac).saturating_sub(y_amt);
                 if let Some(bin) = self.positions.get(&active_id) {
                     // Pull liquidity and send taker order
-                    y_out = min(y_out, active_bin.y.as_u128() - bin.y);
+                    y_out = min(y_out, active_bin.1 - bin.y);
                 } else {
                     // Send taker order only
-                    y_out = min(y_out, active_bin.y.as_u128());
+                    y_out = min(y_out, active_bin.1);
                 }
-                info!(y_out = y_out, scaling = scaling, "Too much X, rebalancing");
+                info!(y_out = y_out, scaling = %scaling, "Too much X, rebalancing");
                 if let Some(order) = self.make_take(amm, y_out, true) {
                     return (Some(order), active_id)
                 }
-            } else if directional_skew < 1.0 - self.config.max_skew {
+            } else if directional_skew < Decimal::ONE - max_skew {
                 // Too much y, buy some
                 // Scaling should always be > 1
-                let scaling = (1.0 - directional_skew) / self.config.max_skew;
-                let mut x_out = Self::y_in_terms_of_x(px_128, (((total_value as f64 * ((1.0 - self.config.max_skew) * scaling).min(0.5)) as u128 - implied_x_value)).into()).as_u128();
+                let scaling = match (Decimal::ONE - directional_skew).checked_div(max_skew) {
+                    Some(scaling) => scaling,
+                    None => return Self::overflow("rebalance scaling", active_id),
+                };
+                let x_frac = Self::clamp((Decimal::ONE - max_skew).saturating_mul(scaling).min(dec!(0.5)) + skew_offset);
+                let mut x_out = Self::y_in_terms_of_x(px_128, Self::frac_of(total_value, x_frac).saturating_sub(implied_x_value));
                 if let Some(bin) = self.positions.get(&active_id) {
                     // Pull liquidity and send taker order
-                    x_out = min(x_out, active_bin.x.as_u128() - bin.x);
+                    x_out = min(x_out, active_bin.0 - bin.x);
                 } else {
                     // Send taker order only
-                    x_out = min(x_out, active_bin.x.as_u128());
+                    x_out = min(x_out, active_bin.0);
                 }
-                info!(x_out = x_out, scaling = scaling, "Too much Y, rebalancing");
+                info!(x_out = x_out, scaling = %scaling, "Too much Y, rebalancing");
                 if let Some(order) = self.make_take(amm, x_out, false) {
                     return (Some(order), active_id)
                 }
@@ -354,12 +553,134 @@ impl Portfolio {
 
     }
 
-    fn make_take(&self, amm: &lb::LB, amt_out: u128, swap_for_y: bool) -> Option<Execute> {
+    // Ladder of bins on each side of the Avellaneda-Stoikov reservation price.
+    // Never provides to the active bin, since its composition buys and sells at the same price.
+    fn get_model_positions(&self, active_id: u32, cex_mid: f64, x_skew: f64, x_amt: u128, y_amt: u128) -> HashMap<u32, (u128, u128)> {
+        let mut position_wanted = HashMap::new();
+        let (risk_aversion, order_intensity, horizon_secs, volatility, bins_per_side) = match self.config.pricing_model {
+            PricingModel::AvellanedaStoikov{risk_aversion, order_intensity, horizon_secs, volatility, bins_per_side, ..} => {
+                (risk_aversion, order_intensity, horizon_secs, volatility, bins_per_side as usize)
+            },
+            PricingModel::Heuristic => return position_wanted,
+        };
+        let sigma = volatility.unwrap_or(self.vol.sigma());
+        let inventory = 2.0 * (x_skew - 0.5);
+        let quote = pricing::avellaneda_stoikov(cex_mid, inventory, sigma, risk_aversion, order_intensity, horizon_secs);
+
+        let ask_ids = ((active_id + 1)..=(active_id + MAX_BIN_SEARCH))
+            .filter(|id| self.get_fpx(*id) >= Self::dec(quote.ask()))
+            .take(bins_per_side)
+            .collect::<Vec<u32>>();
+        let bid_ids = (active_id.saturating_sub(MAX_BIN_SEARCH)..active_id).rev()
+            .filter(|id| self.get_fpx(*id) <= Self::dec(quote.bid()))
+            .take(bins_per_side)
+            .collect::<Vec<u32>>();
+        info!(
+            sigma = sigma,
+            inventory = inventory,
+            reservation_px = quote.reservation_px,
+            half_spread = quote.half_spread,
+            model_bid = quote.bid(),
+            model_ask = quote.ask(),
+            ask_ids = ?ask_ids,
+            bid_ids = ?bid_ids,
+            "Avellaneda-Stoikov quote"
+        );
+
+        let x_deployable = Self::frac_of(x_amt, Decimal::ONE - Self::dec(self.config.token_x_reserve));
+        let y_deployable = Self::frac_of(y_amt, Decimal::ONE - Self::dec(self.config.token_y_reserve));
+        for id in ask_ids.iter() {
+            position_wanted.insert(*id, (x_deployable / ask_ids.len() as u128, 0));
+        }
+        for id in bid_ids.iter() {
+            position_wanted.insert(*id, (0, y_deployable / bid_ids.len() as u128));
+        }
+        position_wanted
+    }
+
+    pub fn restore(&mut self, saved: &MarketState) {
+        if let Some(ts) = saved.last_fee_claim {
+            self.last_fee_claim = state::from_millis(ts);
+        }
+        if let Some(ts) = saved.last_gas_check {
+            self.last_gas_check = state::from_millis(ts);
+        }
+        if let Some(ts) = saved.last_rebalance {
+            self.last_rebalance = state::from_millis(ts);
+        }
+        self.x_hedge = saved.x_hedge;
+        self.hedge_cash = saved.hedge_cash;
+        info!(x_hedge = self.x_hedge, hedge_cash = %self.hedge_cash, "Restored portfolio state");
+    }
+
+    pub fn export(&self, state: &mut MarketState) {
+        state.last_fee_claim = Some(state::to_millis(self.last_fee_claim));
+        state.last_gas_check = Some(state::to_millis(self.last_gas_check));
+        state.last_rebalance = Some(state::to_millis(self.last_rebalance));
+        state.x_hedge = self.x_hedge;
+        state.hedge_cash = self.hedge_cash;
+    }
+
+    // Whether to check pending fees for a claim
+    // Ages every timer as if elapsed had passed, so replays and backtests see recorded time rather than wall time
+    pub fn advance(&mut self, elapsed: Duration) {
+        for instant in [&mut self.last_fee_claim, &mut self.last_gas_check, &mut self.last_rebalance] {
+            *instant = instant.checked_sub(elapsed).unwrap_or(*instant);
+        }
+        self.vol.advance(elapsed);
+    }
+
+    pub fn claim_check_due(&mut self) -> bool {
+        if self.last_fee_claim.elapsed() < CLAIM_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_fee_claim = Instant::now();
+        true
+    }
+
+    // Token x held across the DEX and the hedging venue, in excess of the delta neutral amount
+    pub fn net_x_delta(&self, amm: &impl Pair) -> i128 {
+        let px_128 = lb::Bin::getPriceFromId(amm.active_id().into(), self.bin_step.into());
+        let (x_deployed, y_deployed) = self.positions.values().fold((0, 0), |(x, y), bin| (x + bin.x, y + bin.y));
+        let x_amt = x_deployed + self.x_free;
+        let y_amt = y_deployed + self.y_free;
+        let total_value = Self::x_in_terms_of_y(px_128, x_amt).saturating_add(y_amt);
+        let x_target = Self::y_in_terms_of_x(px_128, self.x_target_value(px_128, total_value));
+        x_amt as i128 + self.x_hedge - x_target as i128
+    }
+
+    pub fn apply_hedge(&mut self, fill: &HedgeFill, hedge_mid: f64) {
+        self.x_hedge += fill.x_delta;
+        self.hedge_cash = self.hedge_cash.saturating_add(fill.quote_delta);
+        let x_hedge = Self::to_dec(self.x_hedge.unsigned_abs().into(), self.x_decimals as u32);
+        let x_hedge = if self.x_hedge < 0 { -x_hedge } else { x_hedge };
+        let hedge_pnl = self.hedge_cash.saturating_add(x_hedge.saturating_mul(Self::dec(hedge_mid)));
+        info!(x_hedge = self.x_hedge, hedge_cash = %self.hedge_cash, hedge_pnl = %hedge_pnl, "Hedge position");
+    }
+
+    // Value of token x, in y, that leaves the portfolio delta neutral.
+    // Borrowed tokens are owed back, so holding exactly the borrowed amount is neutral.
+    // Validation rejects setting both. Without borrows this is a 50/50 value split.
+    fn x_target_value(&self, px_128: U256, total_value: u128) -> u128 {
+        let target = match (self.config.token_x_delta, self.config.token_y_delta) {
+            (Some(x_delta), None) => Self::x_in_terms_of_y(px_128, x_delta),
+            (None, Some(y_delta)) => total_value.saturating_sub(y_delta),
+            (Some(x_delta), Some(y_delta)) => Self::x_in_terms_of_y(px_128, x_delta).saturating_add(total_value).saturating_sub(y_delta) / 2,
+            (None, None) => total_value / 2,
+        };
+        min(target, total_value)
+    }
+
+    fn hedged(&self, x_amt: u128) -> u128 {
+        max(0, x_amt as i128 + self.x_hedge) as u128
+    }
+
+    fn make_take(&self, amm: &impl Pair, amt_out: u128, swap_for_y: bool) -> Option<Execute> {
         if swap_for_y {
             if amt_out > self.config.token_y_dust {
                 info!(y_out = amt_out, "Sending taker order to sell");
                 
-                let x_in = amm.get_amount_in(self.token_x, self.token_y, amt_out.into()).unwrap().as_u128();
+                let x_in = amm.amount_in(amt_out, true);
                 let y_out = amt_out - (2 * amt_out * self.config.taker_profit_bps as u128 / 10000);
                 if self.positions.is_empty() {
                     return Some(
@@ -367,14 +688,14 @@ impl Portfolio {
                     )
                 } else {
                     return Some(
-                        Execute::CancelNTake { amt_in: x_in, amt_out: y_out, swap_for_y: true, orders: self.positions.iter().map(|(id, bin)| {(Tick::Exact(*id), bin.tokens)}).collect() }
+                        Execute::CancelNTake { amt_in: x_in, amt_out: y_out, swap_for_y: true, orders: self.positions.iter().map(|(id, bin)| {(Tick::Exact(*id), Amount::Tokens(bin.tokens))}).collect() }
                     )
                 }
             }
         } else {
             if amt_out > self.config.token_x_dust {
                 info!(x_out = amt_out, "Sending taker order to buy");
-                let y_in = amm.get_amount_in(self.token_y, self.token_x, amt_out.into()).unwrap().as_u128();
+                let y_in = amm.amount_in(amt_out, false);
                 let x_out = amt_out - (2 * amt_out * self.config.taker_profit_bps as u128 / 10000);
                 if self.positions.is_empty() {
                     return Some(
@@ -382,7 +703,7 @@ impl Portfolio {
                     )
                 } else {
                     return Some(
-                        Execute::CancelNTake { amt_in: y_in, amt_out: x_out, swap_for_y: false, orders: self.positions.iter().map(|(id, bin)| {(Tick::Exact(*id), bin.tokens)}).collect() }
+                        Execute::CancelNTake { amt_in: y_in, amt_out: x_out, swap_for_y: false, orders: self.positions.iter().map(|(id, bin)| {(Tick::Exact(*id), Amount::Tokens(bin.tokens))}).collect() }
                     )
                 }
             }
@@ -442,7 +763,7 @@ impl Portfolio {
             (true, true) => {
                 return Some(
                     Execute::Move{
-                        from: to_cancel.into_iter().map(|(tick, x, y, tokens)| (tick, tokens)).collect(),
+                        from: to_cancel.into_iter().map(|(tick, x, y, tokens)| (tick, Amount::Tokens(tokens))).collect(),
                         to: to_add.into_iter().map(|(tick, x, y)| (tick, x, y)).collect(),
                     }
                 )
@@ -457,15 +778,11 @@ impl Portfolio {
             (false, true) => {
                 return Some(
                     Execute::Cancel(
-                        to_cancel.into_iter().map(|(tick, x, y, tokens)| (tick, tokens)).collect(),
+                        to_cancel.into_iter().map(|(tick, x, y, tokens)| (tick, Amount::Tokens(tokens))).collect(),
                     )
                 )
             },
             (false, false) => {
-                if self.last_fee_claim.elapsed() > std::time::Duration::from_secs(60*60) {
-                    self.last_fee_claim = Instant::now();
-                    return Some(Execute::Claim)
-                }
                 if self.last_gas_check.elapsed() > std::time::Duration::from_secs(60*10) {
                     self.last_gas_check = Instant::now();
                     return Some(Execute::CheckGas)
@@ -475,21 +792,92 @@ impl Portfolio {
         None
     }
 
-    fn x_in_terms_of_y(px_128: U256, x_amt: U256) -> U256 {
-        (px_128 * x_amt) >> 128
+    // Amounts are floored, so value and deployable amounts never exceed what is held.
+    // Saturates at u128::MAX rather than wrapping
+    pub(crate) fn x_in_terms_of_y(px_128: U256, x_amt: u128) -> u128 {
+        Self::saturate(px_128.full_mul(x_amt.into()) >> 128)
+    }
+    pub(crate) fn y_in_terms_of_x(px_128: U256, y_amt: u128) -> u128 {
+        match px_128.is_zero() {
+            true => u128::MAX,
+            false => Self::saturate((U256::from(y_amt) << 128).div_mod(px_128).0.into()),
+        }
+    }
+
+    // Price of a bin in whole y per whole x, from the same 128.128 price the pair uses. Rounds down
+    fn get_fpx(&self, active_id: u32) -> Decimal {
+        let px_128 = lb::Bin::getPriceFromId(active_id.into(), self.bin_step.into());
+        let scaled = px_128.full_mul(U256::exp10(self.x_decimals + PRICE_DP as usize)) >> 128;
+        let px = scaled / U512::from(U256::exp10(self.y_decimals));
+        match U256::try_from(px) {
+            Ok(px) => Self::to_dec(px, PRICE_DP),
+            Err(_) => Decimal::MAX,
+        }
+    }
+
+    // Amount with the given decimals as a decimal, dropping digits it can't hold
+    pub(crate) fn to_dec(mut amount: U256, mut scale: u32) -> Decimal {
+        let max_mantissa = U256::from(Decimal::MAX.mantissa() as u128);
+        while amount > max_mantissa && scale > 0 {
+            amount /= 10;
+            scale -= 1;
+        }
+        match amount > max_mantissa {
+            true => Decimal::MAX,
+            false => Decimal::from_i128_with_scale(amount.as_u128() as i128, scale),
+        }
+    }
+
+    // Decimal math that overflowed or divided by zero skips the tick rather than quoting on a bad number
+    fn overflow(what: &str, active_id: u32) -> (Option<Execute>, u32) {
+        warn!(what = what, "Decimal overflow, not quoting");
+        (None, active_id)
+    }
+
+    // How far past the taker threshold the DEX price is, as a multiple of max_skew
+    fn taker_scaling(taker_scaling_factor: Decimal, px_ratio: Option<Decimal>) -> Option<Decimal> {
+        taker_scaling_factor.checked_mul(px_ratio? - Decimal::ONE)?.checked_add(dec!(0.95))
+    }
+
+    // Config and feed values are floats. Non finite values count as zero
+    pub(crate) fn dec(x: f64) -> Decimal {
+        Decimal::from_f64(x).unwrap_or_default()
+    }
+
+    fn bps(bps: usize) -> Decimal {
+        Decimal::new(bps as i64, 4)
+    }
+
+    fn clamp(frac: Decimal) -> Decimal {
+        frac.max(Decimal::ZERO).min(Decimal::ONE)
+    }
+
+    // num / den, rounded down to PRICE_DP places. Zero when den is zero
+    fn ratio(num: u128, den: u128) -> Decimal {
+        if den == 0 {
+            return Decimal::ZERO;
+        }
+        let scaled = U256::from(num).full_mul(U256::exp10(PRICE_DP as usize)) / U512::from(den);
+        match U256::try_from(scaled) {
+            Ok(scaled) => Self::to_dec(scaled, PRICE_DP),
+            Err(_) => Decimal::MAX,
+        }
     }
-    fn y_in_terms_of_x(px_128: U256, y_amt: U256) -> U256 {
-        (y_amt << 128).div_mod(px_128).0
+
+    // amount * frac, rounded down. frac is exact, so no precision is lost on 18 decimal amounts
+    fn frac_of(amount: u128, frac: Decimal) -> u128 {
+        if frac.is_sign_negative() {
+            return 0;
+        }
+        let product = U256::from(amount).full_mul(U256::from(frac.mantissa() as u128)) / U512::from(U256::exp10(frac.scale() as usize));
+        Self::saturate(product)
     }
 
-    fn get_fpx(&self, active_id: u32) -> f64 {
-        let decimal_diff = self.x_decimals - self.y_decimals;
-        let base = (10000 + self.bin_step) as f64 / 10000.0;
-        // info!(base = base, active_id = active_id, decimal_diff = decimal_diff, base_pow = base.powf((active_id-(1<<23)) as f64), dec_offset = 10u128.pow(decimal_diff as u32));
-        if active_id < (1<<23) {
-            return 10u128.pow(decimal_diff as u32) as f64 / base.powf(((1<<23) - active_id) as f64)
+    fn saturate(amount: U512) -> u128 {
+        match U256::try_from(amount) {
+            Ok(amount) if amount <= U256::from(u128::MAX) => amount.as_u128(),
+            _ => u128::MAX,
         }
-        return base.powf((active_id - (1<<23)) as f64) * 10u128.pow(decimal_diff as u32) as f64
     }
 
     fn get_ratio(max_x: u128, max_y: u128, cur_x: u128, cur_y: u128) -> (u128, u128) {
@@ -519,4 +907,139 @@ impl Portfolio {
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    pub(crate) fn config() -> PortfolioConfig {
+        PortfolioConfig {
+            token_x_delta: None,
+            token_y_delta: None,
+            token_x_dust: 1_000_000_000_000,
+            token_y_dust: 1_000,
+            token_x_reserve: 0.1,
+            token_y_reserve: 0.1,
+            taker_profit_bps: 20,
+            maker_loss_bps: 5,
+            tx_limit_5min: 10,
+            max_skew: 0.8,
+            taker_scaling_factor: 1.0,
+            max_x_inventory: None,
+            max_y_inventory: None,
+            reduce_only: false,
+            pause: false,
+            min_gas: 100_000_000_000_000_000,
+            px_skew_factor: 1.0,
+            portfolio_skew_factor: 1.0,
+            px_scaling_factor: 10.0,
+            rebalance_interval: 60,
+            take_gas_price_scaling: 2,
+            gas_constant: 100_000,
+            pricing_model: PricingModel::Heuristic,
+            claim_gas_multiple: default_claim_gas_multiple(),
+            native_px: None,
+        }
+    }
+
+    #[test]
+    fn test_config_is_valid() {
+        assert_eq!(config().validate(), Ok(()));
+    }
+
+    // Bin steps in use on LB pairs, and the bin where a unit of x is worth a unit of y
+    const BIN_STEPS: [u16; 7] = [1, 2, 5, 10, 20, 25, 100];
+    const ID_ONE: u32 = 1 << 23;
+
+    fn portfolio(bin_step: u16, x_decimals: usize, y_decimals: usize) -> Portfolio {
+        let amm = lb::LB::new_empty(Uuid::new_v4(), Uuid::new_v4());
+        let mut portfolio = Portfolio::new(&amm, 0, 0, x_decimals, y_decimals, config());
+        portfolio.bin_step = bin_step;
+        portfolio
+    }
+
+    // Deterministic pseudo random numbers, so failures reproduce
+    fn samples(seed: u64, n: usize) -> Vec<u64> {
+        let mut state = seed;
+        (0..n).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            state >> 11
+        }).collect()
+    }
+
+    // Ids up to 2000 bins either side of ID_ONE, ends included
+    fn ids(seed: u64) -> Vec<u32> {
+        let mut ids = samples(seed, 100).into_iter().map(|sample| ID_ONE - 2000 + (sample % 4001) as u32).collect::<Vec<u32>>();
+        ids.extend([ID_ONE - 2000, ID_ONE - 1, ID_ONE, ID_ONE + 1, ID_ONE + 2000]);
+        ids
+    }
+
+    fn to_f64(px_128: U256) -> f64 {
+        px_128.0.iter().rev().fold(0.0, |acc, limb| acc * 2.0_f64.powi(64) + *limb as f64)
+    }
+
+    #[test]
+    fn both_deltas_are_held_and_the_rest_split_evenly() {
+        let mut portfolio = portfolio(10, 18, 18);
+        portfolio.config.token_x_delta = Some(100);
+        portfolio.config.token_y_delta = Some(300);
+        assert_eq!(portfolio.config.validate(), Ok(()));
+        // At a price of 1, 100 of x and 300 of y are held and the other 600 split
+        let px_128 = U256::one() << 128;
+        assert_eq!(portfolio.x_target_value(px_128, 1000), 400);
+        // At 2, the x delta is worth 200
+        assert_eq!(portfolio.x_target_value(px_128 * 2, 1000), 450);
+        // Short of both deltas, the shortfall is split too
+        assert_eq!(portfolio.x_target_value(px_128, 300), 50);
+        assert_eq!(portfolio.x_target_value(px_128, 100), 0);
+    }
+
+    #[test]
+    fn fpx_is_the_lb_price_in_whole_tokens() {
+        for (x_decimals, y_decimals) in [(18, 18), (18, 6)] {
+            for bin_step in BIN_STEPS {
+                let portfolio = portfolio(bin_step, x_decimals, y_decimals);
+                let decimals = 10.0_f64.powi(x_decimals as i32 - y_decimals as i32);
+                for id in ids(bin_step as u64) {
+                    let px_128 = lb::Bin::getPriceFromId(id.into(), bin_step.into());
+                    let fpx = portfolio.get_fpx(id).to_f64().unwrap();
+                    // Rounded down to PRICE_DP places
+                    assert_relative_eq!(fpx, to_f64(px_128) / 2.0_f64.powi(128) * decimals, epsilon = 1e-18, max_relative = 1e-12);
+                    // (1 + bin_step / 10000) ^ (id - 2^23)
+                    let analytic = (1.0 + bin_step as f64 / 10000.0).powi(id as i32 - ID_ONE as i32) * decimals;
+                    assert_relative_eq!(fpx, analytic, epsilon = 1e-18, max_relative = 1e-9);
+                    assert!(portfolio.get_fpx(id + 1) > portfolio.get_fpx(id), "id {} bin_step {}", id, bin_step);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn conversions_round_down_at_the_lb_price() {
+        for bin_step in BIN_STEPS {
+            let amounts = samples(bin_step as u64 + 100, 105).into_iter().map(|sample| sample as u128 * 1_000_000_007);
+            for (id, amount) in ids(bin_step as u64).into_iter().zip(amounts) {
+                let px_128 = lb::Bin::getPriceFromId(id.into(), bin_step.into());
+                let px = U512::from(px_128);
+
+                // y <= amount * px < y + 1
+                let y = Portfolio::x_in_terms_of_y(px_128, amount);
+                let value = px * U512::from(amount);
+                assert!(U512::from(y) << 128 <= value && value < U512::from(y + 1) << 128, "id {} bin_step {}", id, bin_step);
+                if y > 1_000_000_000_000 {
+                    assert_relative_eq!(y as f64, amount as f64 * to_f64(px_128) / 2.0_f64.powi(128), max_relative = 1e-12);
+                }
+
+                // x * px <= amount < (x + 1) * px
+                let x = Portfolio::y_in_terms_of_x(px_128, amount);
+                let amount_128 = U512::from(amount) << 128;
+                assert!(px * U512::from(x) <= amount_128 && amount_128 < px * U512::from(x + 1), "id {} bin_step {}", id, bin_step);
+
+                // Converting there and back never makes tokens up
+                assert!(Portfolio::y_in_terms_of_x(px_128, y) <= amount);
+                assert!(Portfolio::x_in_terms_of_y(px_128, x) <= amount);
+            }
+        }
+    }
+}
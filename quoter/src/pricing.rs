@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum PricingModel {
+    // Blend of px_skew and x_skew weighted by px_skew_factor/portfolio_skew_factor
+    Heuristic,
+    // Reservation price and optimal half spread from inventory, volatility and risk aversion
+    AvellanedaStoikov {
+        // Risk aversion (gamma), per unit of y price. Larger values skew the reservation price harder against inventory
+        risk_aversion: f64,
+        // Order arrival decay (kappa), per unit of y price. Larger values mean a deeper book and tighter spreads
+        order_intensity: f64,
+        // Remaining horizon (T - t) in seconds
+        horizon_secs: f64,
+        // Volatility of log price per sqrt(second). Estimated from the CEX mid if None
+        volatility: Option<f64>,
+        // Half life of the volatility estimate in seconds
+        vol_halflife_secs: f64,
+        // Number of bins to provide on each side of the reservation price
+        bins_per_side: u32,
+    },
+}
+
+impl Default for PricingModel {
+    fn default() -> Self {
+        PricingModel::Heuristic
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Quote {
+    pub reservation_px: f64,
+    // Fraction of the reservation price
+    pub half_spread: f64,
+}
+
+impl Quote {
+    pub fn bid(&self) -> f64 {
+        self.reservation_px * (1.0 - self.half_spread)
+    }
+
+    pub fn ask(&self) -> f64 {
+        self.reservation_px * (1.0 + self.half_spread)
+    }
+}
+
+// Prices are quoted in y per x. sigma is the volatility of log price, so it is scaled by mid to work in price units
+// like gamma and kappa, and the half spread is divided by mid to make it relative.
+// inventory is normalised to [-1, 1], where 1 is a portfolio entirely in x.
+pub fn avellaneda_stoikov(mid: f64, inventory: f64, sigma: f64, risk_aversion: f64, order_intensity: f64, horizon_secs: f64) -> Quote {
+    let px_sigma = sigma * mid;
+    let variance = px_sigma * px_sigma * horizon_secs;
+    let reservation_px = mid - inventory * risk_aversion * variance;
+    let half_spread = (risk_aversion * variance / 2.0) + ((1.0 + risk_aversion / order_intensity).ln() / risk_aversion);
+    Quote {
+        reservation_px,
+        half_spread: half_spread / mid,
+    }
+}
+
+// EWMA of squared log returns, normalised per second
+#[derive(Clone, Debug, Default)]
+pub struct VolEstimator {
+    last: Option<(Instant, f64)>,
+    variance: f64,
+}
+
+impl VolEstimator {
+    pub fn update(&mut self, mid: f64, halflife_secs: f64) -> f64 {
+        if !mid.is_finite() || mid <= 0.0 {
+            return self.sigma();
+        }
+        if let Some((ts, last_mid)) = self.last {
+            let dt = ts.elapsed().as_secs_f64();
+            if dt <= 0.0 {
+                return self.sigma();
+            }
+            let ret = (mid / last_mid).ln();
+            let alpha = 1.0 - (-dt * std::f64::consts::LN_2 / halflife_secs).exp();
+            self.variance = (1.0 - alpha) * self.variance + alpha * ret * ret / dt;
+        }
+        self.last = Some((Instant::now(), mid));
+        self.sigma()
+    }
+
+    pub fn sigma(&self) -> f64 {
+        self.variance.sqrt()
+    }
+
+    // Ages the last sample as if elapsed had passed. Used to run recorded feeds faster than they happened
+    pub fn advance(&mut self, elapsed: Duration) {
+        if let Some((ts, mid)) = self.last {
+            self.last = Some((ts.checked_sub(elapsed).unwrap_or(ts), mid));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn avellaneda_stoikov_matches_hand_computed_quote() {
+        // sigma_px = 0.001 * 100 = 0.1, variance = 0.1^2 * 60 = 0.6
+        // reservation = 100 - 0.5 * 0.1 * 0.6 = 99.97
+        // half spread = (0.1 * 0.6 / 2 + ln(1 + 0.1 / 1.5) / 0.1) / 100 = 0.0067538521
+        let quote = avellaneda_stoikov(100.0, 0.5, 0.001, 0.1, 1.5, 60.0);
+        assert_relative_eq!(quote.reservation_px, 99.97, epsilon = 1e-9);
+        assert_relative_eq!(quote.half_spread, 0.006753852113757116, epsilon = 1e-12);
+        assert_relative_eq!(quote.bid(), 99.2948174041877, epsilon = 1e-9);
+        assert_relative_eq!(quote.ask(), 100.6451825958123, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn avellaneda_stoikov_spread_is_relative_to_mid() {
+        // The same market quoted in different units gives the same relative spread
+        // when gamma and kappa are rescaled with the price
+        let quote = avellaneda_stoikov(100.0, 0.0, 0.001, 0.1, 1.5, 60.0);
+        let scaled = avellaneda_stoikov(10000.0, 0.0, 0.001, 0.001, 0.015, 60.0);
+        assert_relative_eq!(quote.half_spread, scaled.half_spread, epsilon = 1e-12);
+        assert_relative_eq!(quote.reservation_px, 100.0);
+        assert!(quote.half_spread < 0.01);
+    }
+}
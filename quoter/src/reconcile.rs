@@ -0,0 +1,157 @@
+use ethers::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn, error};
+
+use crate::executor::Snapshot;
+use crate::portfolio::Portfolio;
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ReconcileConfig {
+    // Blocks between checks of the portfolio against the chain
+    pub interval_blocks: u64,
+    // Stop quoting while drift is above the dust thresholds
+    pub halt_on_drift: bool,
+    // Bins either side of the active id checked along with the tracked ones, inclusive.
+    // Bins further out are picked up by the periodic discovery of the whole pair
+    #[serde(default = "default_scan_range")]
+    pub scan_range: u32,
+}
+
+fn default_scan_range() -> u32 {
+    10
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        Self {
+            interval_blocks: 100,
+            halt_on_drift: true,
+            scan_range: default_scan_range(),
+        }
+    }
+}
+
+// (portfolio, chain) for every value that differs
+#[derive(PartialEq, Clone, Debug, Default, Serialize)]
+pub struct Drift {
+    pub block: u64,
+    pub x_balance: Option<(u128, u128)>,
+    pub y_balance: Option<(u128, u128)>,
+    pub bins: Vec<BinDrift>,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize)]
+pub struct BinDrift {
+    pub id: u32,
+    pub tokens: (u128, u128),
+    pub x: (u128, u128),
+    pub y: (u128, u128),
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.x_balance.is_none() && self.y_balance.is_none() && self.bins.is_empty()
+    }
+
+    // Total absolute difference in token x and y, free and deployed
+    pub fn amounts(&self) -> (u128, u128) {
+        let abs_diff = |(a, b): (u128, u128)| a.abs_diff(b);
+        self.bins.iter().fold(
+            (self.x_balance.map_or(0, abs_diff), self.y_balance.map_or(0, abs_diff)),
+            |(x, y), bin| (x + abs_diff(bin.x), y + abs_diff(bin.y)),
+        )
+    }
+}
+
+// Compares the portfolio against a snapshot of the chain taken at the block the portfolio was computed for
+pub fn diff(portfolio: &Portfolio, snapshot: &Snapshot) -> Drift {
+    let mut drift = Drift {
+        block: snapshot.block,
+        x_balance: (portfolio.x_balance != snapshot.x_balance).then(|| (portfolio.x_balance, snapshot.x_balance)),
+        y_balance: (portfolio.y_balance != snapshot.y_balance).then(|| (portfolio.y_balance, snapshot.y_balance)),
+        bins: Vec::new(),
+    };
+    let mut ids = portfolio.positions.keys().chain(snapshot.bins.keys()).cloned().collect::<Vec<u32>>();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        let (tokens, x, y) = portfolio.positions.get(&id).map_or((0, 0, 0), |bin| (bin.tokens, bin.x, bin.y));
+        let (chain_tokens, chain_x, chain_y) = match snapshot.bins.get(&id) {
+            Some((tokens, reserve_x, reserve_y, supply)) if *supply != 0 => (
+                *tokens,
+                (*reserve_x * U256::from(*tokens) / U256::from(*supply)).as_u128(),
+                (*reserve_y * U256::from(*tokens) / U256::from(*supply)).as_u128(),
+            ),
+            _ => (0, 0, 0),
+        };
+        if (tokens, x, y) != (chain_tokens, chain_x, chain_y) {
+            drift.bins.push(BinDrift {
+                id,
+                tokens: (tokens, chain_tokens),
+                x: (x, chain_x),
+                y: (y, chain_y),
+            });
+        }
+    }
+    drift
+}
+
+#[derive(Clone, Debug)]
+pub struct Reconciler {
+    pub config: ReconcileConfig,
+    // Set while the last check found drift above dust
+    pub halted: bool,
+    last_block: u64,
+}
+
+impl Reconciler {
+    pub fn new(config: ReconcileConfig, block: u64) -> Self {
+        Self {
+            config,
+            halted: false,
+            last_block: block,
+        }
+    }
+
+    pub fn due(&self, block: u64) -> bool {
+        block >= self.last_block + self.config.interval_blocks
+    }
+
+    pub fn check(&mut self, portfolio: &Portfolio, snapshot: &Snapshot) -> Drift {
+        self.last_block = snapshot.block;
+        let drift = diff(portfolio, snapshot);
+        if drift.is_empty() {
+            debug!(block = snapshot.block, "Portfolio matches chain");
+            if self.halted {
+                info!(block = snapshot.block, "Drift cleared. Resuming quoting");
+                self.halted = false;
+            }
+            return drift;
+        }
+
+        let (x_drift, y_drift) = drift.amounts();
+        let over_dust = x_drift > portfolio.config.token_x_dust || y_drift > portfolio.config.token_y_dust;
+        if over_dust && self.config.halt_on_drift {
+            error!(
+                drift = %serde_json::to_string(&drift).unwrap(),
+                x_drift = x_drift,
+                y_drift = y_drift,
+                "Portfolio drifted from chain. Halting quoting"
+            );
+            self.halted = true;
+        } else {
+            warn!(
+                drift = %serde_json::to_string(&drift).unwrap(),
+                x_drift = x_drift,
+                y_drift = y_drift,
+                over_dust = over_dust,
+                "Portfolio drifted from chain"
+            );
+            if self.halted && !over_dust {
+                info!(block = snapshot.block, "Drift below dust. Resuming quoting");
+                self.halted = false;
+            }
+        }
+        drift
+    }
+}
@@ -0,0 +1,136 @@
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn, error};
+
+use crate::{config, Config};
+
+// Editors write in several steps, so wait this long after the last event before reading the file
+const SETTLE: Duration = Duration::from_millis(200);
+
+// Sections of a new config that differ from the running one
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Changes {
+    pub rpc: bool,
+    pub health: bool,
+    pub risk: bool,
+    pub treasury: bool,
+    pub markets: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+}
+
+// Diffs a new config against the running one. Fails with every change that only takes effect on restart,
+// in which case none of the new config is applied.
+pub fn diff(old: &Config, new: &Config) -> Result<Changes, Vec<String>> {
+    let mut rejected = Vec::new();
+    let mut fixed = |field: &str, same: bool| {
+        if !same {
+            rejected.push(format!("{}: can't be changed without a restart", field));
+        }
+    };
+    fixed("weth", old.weth == new.weth);
+    fixed("signer", old.signer == new.signer && old.owner_key == new.owner_key);
+    fixed("callers", old.callers == new.callers);
+    fixed("kill_sweep", old.kill_sweep == new.kill_sweep);
+    fixed("exit_policy", old.exit_policy == new.exit_policy);
+    fixed("state_dir", old.state_dir == new.state_dir);
+    fixed("record_dir", old.record_dir == new.record_dir);
+    fixed("admin", old.admin == new.admin);
+    fixed("metrics_addr", old.metrics_addr == new.metrics_addr);
+
+    let old_markets = old.markets();
+    let new_markets = new.markets();
+    let names = |markets: &[crate::market::MarketConfig]| markets.iter().map(|market| market.name.clone()).collect::<HashSet<String>>();
+    fixed("markets", names(&old_markets) == names(&new_markets));
+
+    let mut markets = Vec::new();
+    for new_market in new_markets.iter() {
+        let old_market = match old_markets.iter().find(|market| market.name == new_market.name) {
+            Some(market) => market,
+            None => continue,
+        };
+        fixed(&format!("{}.executor_address", new_market.name), old_market.executor_address == new_market.executor_address);
+        if old_market != new_market {
+            markets.push(new_market.name.clone());
+        }
+    }
+    if !rejected.is_empty() {
+        return Err(rejected);
+    }
+    Ok(Changes {
+        rpc: old.wsrpc != new.wsrpc || old.archiverpc != new.archiverpc,
+        health: old.heartbeat != new.heartbeat || old.health != new.health,
+        risk: old.risk != new.risk,
+        treasury: old.treasury != new.treasury,
+        markets,
+    })
+}
+
+// Watches the config file and publishes every new config that loads, validates and can be applied live.
+// Markets pick up their own sections, run() reconnects providers and applies risk and treasury limits, and the health
+// monitor reads its section on every check.
+pub fn spawn(path: String, config: Config) -> watch::Receiver<Arc<Config>> {
+    let (tx, rx) = watch::channel(Arc::new(config));
+    tokio::spawn(async move {
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let file = PathBuf::from(&path);
+        let file_name = file.file_name().map(|name| name.to_os_string());
+        let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+            match res {
+                Ok(event) => {
+                    let ours = event.paths.iter().any(|changed| changed.file_name().map(|name| name.to_os_string()) == file_name);
+                    if ours && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                        event_tx.send(()).ok();
+                    }
+                },
+                Err(e) => warn!(error = ?e, "Config watch error"),
+            }
+        });
+        // Watch the directory, since editors often replace the file rather than write to it
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+        let _watcher = match watcher.and_then(|mut watcher| watcher.watch(&dir, RecursiveMode::NonRecursive).map(|_| watcher)) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!(path = %path, error = ?e, "Failed to watch config. Hot reload is off");
+                // Keep the sender so receivers wait instead of seeing a closed channel
+                tx.closed().await;
+                return;
+            }
+        };
+        info!(path = %path, "Watching config");
+
+        while event_rx.recv().await.is_some() {
+            tokio::time::sleep(SETTLE).await;
+            while event_rx.try_recv().is_ok() {}
+
+            let new_config = match config::load(&path) {
+                Ok(new_config) => new_config,
+                Err(e) => {
+                    error!(error = %e, "Rejected new config");
+                    continue;
+                }
+            };
+            let current = tx.borrow().clone();
+            match diff(&current, &new_config) {
+                Ok(changes) if changes.is_empty() => (),
+                Ok(changes) => {
+                    info!(changes = ?changes, "Reloaded config");
+                    tx.send_replace(Arc::new(new_config));
+                },
+                Err(rejected) => error!(rejected = ?rejected, "Rejected new config. Restart to apply these changes"),
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,267 @@
+use chrono::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::executor::Execute;
+use crate::portfolio::{Portfolio, PortfolioConfig};
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum BreachAction {
+    ReduceOnly,
+    Pause,
+}
+
+// Limits shared by every market. Notionals are in the unit of each market's notional_px
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RiskConfig {
+    // Max liquidity deployed across every market
+    pub max_deployed_notional: Option<f64>,
+    // Max value a single tx can send into a pair
+    pub max_tx_notional: Option<f64>,
+    // Max loss of DEX inventory value since the start of the UTC day, marked at the CEX mid
+    pub max_daily_drawdown: Option<f64>,
+    // What to do once a drawdown, deployed notional or inventory limit is breached
+    pub on_breach: BreachAction,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Limit {
+    MaxDeployedNotional,
+    MaxTxNotional,
+    MaxXInventory,
+    MaxYInventory,
+    DailyDrawdown,
+}
+
+// Token balances of a market and how to value them
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Exposure {
+    pub x_amt: u128,
+    pub y_amt: u128,
+    pub x_deployed: u128,
+    pub y_deployed: u128,
+    pub x_decimals: usize,
+    pub y_decimals: usize,
+    pub cex_mid: Decimal,
+    pub notional_px: Decimal,
+}
+
+impl Exposure {
+    pub fn notional(&self, x: u128, y: u128) -> Decimal {
+        let x = Portfolio::to_dec(x.into(), self.x_decimals as u32);
+        let y = Portfolio::to_dec(y.into(), self.y_decimals as u32);
+        x.saturating_mul(self.cex_mid).saturating_add(y).saturating_mul(self.notional_px)
+    }
+
+    pub fn deployed(&self) -> Decimal {
+        self.notional(self.x_deployed, self.y_deployed)
+    }
+
+    pub fn equity(&self) -> Decimal {
+        self.notional(self.x_amt, self.y_amt)
+    }
+}
+
+fn sum<'a, I: Iterator<Item = &'a Decimal>>(values: I) -> Decimal {
+    values.fold(Decimal::ZERO, |sum, value| sum.saturating_add(*value))
+}
+
+// Sits between Portfolio::on_state and Executor::execute. Shared by every market in the process
+#[derive(Clone, Debug)]
+pub struct RiskManager {
+    pub config: Option<RiskConfig>,
+    deployed: HashMap<String, Decimal>,
+    equity: HashMap<String, Decimal>,
+    day: NaiveDate,
+    day_start_equity: HashMap<String, Decimal>,
+    breached: HashMap<String, Limit>,
+}
+
+impl RiskManager {
+    pub fn new(config: Option<RiskConfig>) -> Self {
+        Self {
+            config,
+            deployed: HashMap::new(),
+            equity: HashMap::new(),
+            day: Utc::now().date_naive(),
+            day_start_equity: HashMap::new(),
+            breached: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, market: &str, exposure: &Exposure) {
+        let today = Utc::now().date_naive();
+        if today != self.day {
+            info!(day = %today, equity = ?self.equity, "New risk day");
+            self.day = today;
+            self.day_start_equity = self.equity.clone();
+        }
+        let equity = exposure.equity();
+        self.deployed.insert(market.to_string(), exposure.deployed());
+        self.equity.insert(market.to_string(), equity);
+        self.day_start_equity.entry(market.to_string()).or_insert(equity);
+    }
+
+    pub fn total_deployed(&self) -> Decimal {
+        sum(self.deployed.values())
+    }
+
+    pub fn drawdown(&self) -> Decimal {
+        let start = sum(self.day_start_equity.iter()
+            .filter(|(market, _)| self.equity.contains_key(*market))
+            .map(|(_, equity)| equity));
+        start.saturating_sub(sum(self.equity.values()))
+    }
+
+    // Limit the market is currently breaching, and what to do about it
+    pub fn breach(&mut self, market: &str, exposure: &Exposure, portfolio_config: &PortfolioConfig) -> Option<(Limit, BreachAction)> {
+        let config = self.config?;
+        let limit = if config.max_daily_drawdown.map_or(false, |max| self.drawdown() > Portfolio::dec(max)) {
+            Some(Limit::DailyDrawdown)
+        } else if config.max_deployed_notional.map_or(false, |max| self.total_deployed() > Portfolio::dec(max)) {
+            Some(Limit::MaxDeployedNotional)
+        } else if portfolio_config.max_x_inventory.map_or(false, |max| exposure.x_amt > max) {
+            Some(Limit::MaxXInventory)
+        } else if portfolio_config.max_y_inventory.map_or(false, |max| exposure.y_amt > max) {
+            Some(Limit::MaxYInventory)
+        } else {
+            None
+        };
+        match limit {
+            Some(limit) => {
+                if self.breached.insert(market.to_string(), limit) != Some(limit) {
+                    warn!(
+                        market = market,
+                        limit = ?limit,
+                        on_breach = ?config.on_breach,
+                        drawdown = %self.drawdown(),
+                        deployed = %self.total_deployed(),
+                        x_amt = exposure.x_amt,
+                        y_amt = exposure.y_amt,
+                        "Risk limit breached"
+                    );
+                }
+                Some((limit, config.on_breach))
+            },
+            None => {
+                if let Some(limit) = self.breached.remove(market) {
+                    info!(market = market, limit = ?limit, "Risk limit cleared");
+                }
+                None
+            }
+        }
+    }
+
+    // Rejects actions that would take the market past a limit
+    pub fn check(&self, market: &str, action: &Execute, exposure: &Exposure, portfolio_config: &PortfolioConfig) -> Result<(), Limit> {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        // Each action of a batch on its own, then what they deploy together
+        if let Execute::Batch(actions) = action {
+            for action in actions.iter() {
+                self.check(market, action, exposure, portfolio_config)?;
+            }
+        }
+        let (add_x, add_y) = action.added();
+        let (in_x, in_y, out_x, out_y) = match action {
+            Execute::Take{amt_in, amt_out, swap_for_y} | Execute::CancelNTake{amt_in, amt_out, swap_for_y, ..} => {
+                match swap_for_y {
+                    true => (*amt_in, 0, 0, *amt_out),
+                    false => (0, *amt_in, *amt_out, 0),
+                }
+            },
+            _ => (add_x, add_y, 0, 0),
+        };
+
+        let tx_notional = exposure.notional(in_x, in_y);
+        if let Some(max) = config.max_tx_notional.map(Portfolio::dec) {
+            if tx_notional > max {
+                return Self::reject(market, action, Limit::MaxTxNotional, tx_notional, max);
+            }
+        }
+        if let Some(max) = config.max_deployed_notional.map(Portfolio::dec) {
+            let deployed = self.total_deployed().saturating_add(exposure.notional(add_x, add_y));
+            if add_x + add_y > 0 && deployed > max {
+                return Self::reject(market, action, Limit::MaxDeployedNotional, deployed, max);
+            }
+        }
+        if let Some(max) = portfolio_config.max_x_inventory {
+            if out_x > 0 && exposure.x_amt + out_x > max {
+                return Self::reject(market, action, Limit::MaxXInventory, Portfolio::to_dec((exposure.x_amt + out_x).into(), 0), Portfolio::to_dec(max.into(), 0));
+            }
+        }
+        if let Some(max) = portfolio_config.max_y_inventory {
+            if out_y > 0 && exposure.y_amt + out_y > max {
+                return Self::reject(market, action, Limit::MaxYInventory, Portfolio::to_dec((exposure.y_amt + out_y).into(), 0), Portfolio::to_dec(max.into(), 0));
+            }
+        }
+        Ok(())
+    }
+
+    fn reject(market: &str, action: &Execute, limit: Limit, value: Decimal, max: Decimal) -> Result<(), Limit> {
+        warn!(market = market, limit = ?limit, value = %value, max = %max, action = ?action, "Risk limit rejected action");
+        Err(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    use crate::executor::Tick;
+    use crate::portfolio;
+
+    // 10 x at 2000 and 10000 y
+    fn exposure() -> Exposure {
+        Exposure {
+            x_amt: 10 * 10u128.pow(18),
+            y_amt: 10_000 * 10u128.pow(6),
+            x_deployed: 0,
+            y_deployed: 0,
+            x_decimals: 18,
+            y_decimals: 6,
+            cex_mid: dec!(2000),
+            notional_px: Decimal::ONE,
+        }
+    }
+
+    fn risk(max_tx_notional: f64, max_deployed_notional: f64) -> RiskManager {
+        RiskManager::new(Some(RiskConfig {
+            max_deployed_notional: Some(max_deployed_notional),
+            max_tx_notional: Some(max_tx_notional),
+            max_daily_drawdown: None,
+            on_breach: BreachAction::ReduceOnly,
+        }))
+    }
+
+    // Half an x each, worth 1000
+    fn make(id: u32) -> Execute {
+        Execute::Make(vec![(Tick::Exact(id), 5 * 10u128.pow(17), 0)])
+    }
+
+    #[test]
+    fn batch_is_checked_as_one_tx() {
+        let config = portfolio::tests::config();
+        let batch = Execute::Batch(vec![make(1), make(2)]);
+        let strict = risk(1500.0, 10_000.0);
+        assert_eq!(strict.check("risk", &make(1), &exposure(), &config), Ok(()));
+        assert_eq!(strict.check("risk", &batch, &exposure(), &config), Err(Limit::MaxTxNotional));
+        let loose = risk(2500.0, 10_000.0);
+        assert_eq!(loose.check("risk", &batch, &exposure(), &config), Ok(()));
+    }
+
+    #[test]
+    fn batch_deploys_what_its_actions_add_together() {
+        let config = portfolio::tests::config();
+        let batch = Execute::Batch(vec![make(1), make(2)]);
+        let mut risk = risk(10_000.0, 1500.0);
+        risk.update("risk", &exposure());
+        assert_eq!(risk.check("risk", &make(1), &exposure(), &config), Ok(()));
+        assert_eq!(risk.check("risk", &batch, &exposure(), &config), Err(Limit::MaxDeployedNotional));
+    }
+}
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+// What each market does with its positions on SIGINT/SIGTERM
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ExitPolicy {
+    // Leave liquidity in the bins
+    Leave,
+    // Pull every bin and collect fees
+    CancelAll,
+    // Pull every bin, collect fees and sweep tokens and AVAX to the owner
+    CancelAllAndSweep,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        ExitPolicy::Leave
+    }
+}
+
+// Sends the exit policy to every market on the first SIGINT/SIGTERM. A second signal exits immediately
+pub fn spawn_signal_handler(tx: watch::Sender<Option<ExitPolicy>>, policy: ExitPolicy) {
+    tokio::spawn(async move {
+        let mut sigint = signal(SignalKind::interrupt()).unwrap();
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = sigint.recv() => warn!(policy = ?policy, "SIGINT received. Shutting down"),
+            _ = sigterm.recv() => warn!(policy = ?policy, "SIGTERM received. Shutting down"),
+        }
+        tx.send(Some(policy)).ok();
+        tokio::select! {
+            _ = sigint.recv() => (),
+            _ = sigterm.recv() => (),
+        }
+        error!("Second signal received. Exiting without cleanup");
+        std::process::exit(1);
+    });
+}
@@ -0,0 +1,403 @@
+use async_trait::async_trait;
+use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip712::Eip712;
+use hyper::Method;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs;
+use thiserror::Error;
+use tracing::info;
+
+// Where the owner key comes from. Keeps the key out of the config file, which is hot reloaded and shared
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum SignerSource {
+    // Encrypted JSON keystore. The passphrase is read from passphrase_env when set, otherwise prompted for
+    Keystore{path: String, #[serde(default)] passphrase_env: Option<String>},
+    // Hex private key in an env var
+    Env{var: String},
+    // Hex private key in a file only its owner can read or write (mode 600 or stricter)
+    File{path: String},
+    // Signs over HTTP without the key ever reaching this process. See RemoteSigner for the protocol
+    Remote{url: String, #[serde(default)] token_env: Option<String>},
+}
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("wallet error: {0}")]
+    Wallet(#[from] WalletError),
+    #[error("env var {0} is not set")]
+    MissingEnv(String),
+    #[error("failed to read {path}: {source}")]
+    Read{path: String, source: std::io::Error},
+    #[error("{path} must only be accessible by its owner, mode is {mode:o}")]
+    Permissions{path: String, mode: u32},
+    #[error("failed to read passphrase: {0}")]
+    Prompt(std::io::Error),
+    #[error("remote signer: {0}")]
+    Remote(String),
+}
+
+// Every signer source behind one type, so the SignerMiddleware wiring doesn't change with the source
+#[derive(Clone, Debug)]
+pub enum AnySigner {
+    Local(LocalWallet),
+    Remote(RemoteSigner),
+}
+
+impl SignerSource {
+    pub async fn load(&self, chain_id: u64) -> Result<AnySigner, SignerError> {
+        let wallet = match self {
+            SignerSource::Keystore{path, passphrase_env} => {
+                let passphrase = match passphrase_env {
+                    Some(var) => std::env::var(var).map_err(|_| SignerError::MissingEnv(var.clone()))?,
+                    None => rpassword::prompt_password(format!("Passphrase for {}: ", path)).map_err(SignerError::Prompt)?,
+                };
+                AnySigner::Local(LocalWallet::decrypt_keystore(path, passphrase)?)
+            },
+            SignerSource::Env{var} => {
+                let key = std::env::var(var).map_err(|_| SignerError::MissingEnv(var.clone()))?;
+                AnySigner::Local(key.trim().parse::<LocalWallet>()?)
+            },
+            SignerSource::File{path} => {
+                check_permissions(path)?;
+                let key = fs::read_to_string(path).map_err(|source| SignerError::Read{path: path.clone(), source})?;
+                AnySigner::Local(key.trim().parse::<LocalWallet>()?)
+            },
+            SignerSource::Remote{url, token_env} => {
+                let token = match token_env {
+                    Some(var) => Some(std::env::var(var).map_err(|_| SignerError::MissingEnv(var.clone()))?),
+                    None => None,
+                };
+                AnySigner::Remote(RemoteSigner::connect(url.clone(), token).await?)
+            },
+        };
+        Ok(wallet.with_chain_id(chain_id))
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn check_permissions(path: &str) -> Result<(), SignerError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path).map_err(|source| SignerError::Read{path: path.to_string(), source})?.permissions().mode() & 0o777;
+    match mode & 0o077 {
+        0 => Ok(()),
+        _ => Err(SignerError::Permissions{path: path.to_string(), mode}),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn check_permissions(_path: &str) -> Result<(), SignerError> {
+    Ok(())
+}
+
+#[async_trait]
+impl Signer for AnySigner {
+    type Error = SignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(&self, message: S) -> Result<Signature, SignerError> {
+        match self {
+            AnySigner::Local(wallet) => Ok(wallet.sign_message(message).await?),
+            AnySigner::Remote(remote) => {
+                let message = message.as_ref().to_vec();
+                let signature = remote.sign("sign_message", json!({"message": Bytes::from(message.clone())})).await?;
+                remote.verify(&signature, message)?;
+                Ok(signature)
+            },
+        }
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, SignerError> {
+        match self {
+            AnySigner::Local(wallet) => Ok(wallet.sign_transaction(tx).await?),
+            AnySigner::Remote(remote) => {
+                let chain_id = tx.chain_id().map_or(remote.chain_id, |chain_id| chain_id.as_u64());
+                let signature = remote.sign("sign_transaction", json!({"chain_id": chain_id, "tx": tx})).await?;
+                let mut tx = tx.clone();
+                tx.set_chain_id(chain_id);
+                remote.verify(&signature, tx.sighash())?;
+                Ok(signature)
+            },
+        }
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(&self, payload: &T) -> Result<Signature, SignerError> {
+        match self {
+            AnySigner::Local(wallet) => Ok(wallet.sign_typed_data(payload).await?),
+            AnySigner::Remote(remote) => {
+                let hash = H256::from(payload.encode_eip712().map_err(|e| SignerError::Remote(e.to_string()))?);
+                let signature = remote.sign("sign_hash", json!({"hash": hash})).await?;
+                remote.verify(&signature, hash)?;
+                Ok(signature)
+            },
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            AnySigner::Local(wallet) => wallet.address(),
+            AnySigner::Remote(remote) => remote.address,
+        }
+    }
+
+    fn chain_id(&self) -> u64 {
+        match self {
+            AnySigner::Local(wallet) => wallet.chain_id(),
+            AnySigner::Remote(remote) => remote.chain_id,
+        }
+    }
+
+    fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+        match self {
+            AnySigner::Local(wallet) => AnySigner::Local(wallet.with_chain_id(chain_id)),
+            AnySigner::Remote(remote) => AnySigner::Remote(RemoteSigner { chain_id: chain_id.into(), ..remote }),
+        }
+    }
+}
+
+// Client side of the remote signer protocol. JSON over HTTP, with an optional bearer token:
+//   GET  /address           -> {"address"}
+//   POST /sign_transaction  {"chain_id", "tx"} -> {"signature"}
+//   POST /sign_message      {"message"}        -> {"signature"}
+//   POST /sign_hash         {"hash"}           -> {"signature"}
+// Every signature is recovered locally and checked against the address before it is used.
+#[derive(Clone)]
+pub struct RemoteSigner {
+    url: String,
+    token: Option<String>,
+    address: Address,
+    chain_id: u64,
+    client: reqwest::Client,
+}
+
+impl fmt::Debug for RemoteSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteSigner").field("url", &self.url).field("address", &self.address).field("chain_id", &self.chain_id).finish()
+    }
+}
+
+impl RemoteSigner {
+    pub async fn connect(url: String, token: Option<String>) -> Result<Self, SignerError> {
+        let mut signer = Self { url: url.trim_end_matches('/').to_string(), token, address: Address::zero(), chain_id: 1, client: reqwest::Client::new() };
+        let res = signer.request(Method::GET, "address", Value::Null).await?;
+        signer.address = res.get("address").and_then(|address| address.as_str()).and_then(|address| address.parse().ok())
+            .ok_or_else(|| SignerError::Remote(format!("no address in {}", res)))?;
+        info!(url = %signer.url, address = ?signer.address, "Connected to remote signer");
+        Ok(signer)
+    }
+
+    async fn request(&self, method: Method, route: &str, body: Value) -> Result<Value, SignerError> {
+        let url = format!("{}/{}", self.url, route);
+        let mut req = match method {
+            Method::GET => self.client.get(&url),
+            _ => self.client.post(&url).header(CONTENT_TYPE, "application/json").body(body.to_string()),
+        };
+        if let Some(token) = self.token.as_ref() {
+            req = req.bearer_auth(token);
+        }
+        let res = req.send().await.map_err(|e| SignerError::Remote(e.to_string()))?;
+        let status = res.status();
+        let text = res.text().await.map_err(|e| SignerError::Remote(e.to_string()))?;
+        if !status.is_success() {
+            return Err(SignerError::Remote(format!("{} {}: {}", route, status, text)));
+        }
+        serde_json::from_str(&text).map_err(|e| SignerError::Remote(format!("{}: {}", route, e)))
+    }
+
+    async fn sign(&self, route: &str, body: Value) -> Result<Signature, SignerError> {
+        let res = self.request(Method::POST, route, body).await?;
+        res.get("signature").and_then(|signature| signature.as_str()).and_then(|signature| signature.parse().ok())
+            .ok_or_else(|| SignerError::Remote(format!("no signature in {}", res)))
+    }
+
+    fn verify<M: Into<RecoveryMessage>>(&self, signature: &Signature, message: M) -> Result<(), SignerError> {
+        signature.verify(message, self.address).map_err(|e| SignerError::Remote(format!("signature does not match {:?}: {}", self.address, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+
+    use crate::admin::{bearer_authorized, respond};
+
+    // Anvil's first dev account
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    // Local stand-in for a remote signer. Holds the key in process and speaks the same protocol as RemoteSigner
+    #[derive(Clone, Debug)]
+    struct StandInSigner {
+        wallet: LocalWallet,
+        token: Option<String>,
+    }
+
+    impl StandInSigner {
+        // Serves on a free local port and returns its url
+        fn spawn(self) -> String {
+            let make_svc = make_service_fn(move |_| {
+                let signer = self.clone();
+                async move { Ok::<_, Infallible>(service_fn(move |req| signer.clone().handle(req))) }
+            });
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+            let url = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+            url
+        }
+
+        async fn handle(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+            if self.token.as_ref().map_or(false, |token| !bearer_authorized(&req, token)) {
+                return Ok(respond(StatusCode::UNAUTHORIZED, json!({"error": "unauthorized"})));
+            }
+            let method = req.method().clone();
+            let path = req.uri().path().trim_matches('/').to_string();
+            let bytes = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let body = serde_json::from_slice::<Value>(&bytes).unwrap_or(Value::Null);
+
+            let signature = match (&method, path.as_str()) {
+                (&Method::GET, "address") => return Ok(respond(StatusCode::OK, json!({"address": self.wallet.address()}))),
+                (&Method::POST, "sign_transaction") => {
+                    let chain_id = body.get("chain_id").and_then(|chain_id| chain_id.as_u64()).unwrap_or(self.wallet.chain_id());
+                    match serde_json::from_value::<TypedTransaction>(body.get("tx").cloned().unwrap_or(Value::Null)) {
+                        Ok(tx) => self.wallet.clone().with_chain_id(chain_id).sign_transaction(&tx).await,
+                        Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()}))),
+                    }
+                },
+                (&Method::POST, "sign_message") => match serde_json::from_value::<Bytes>(body.get("message").cloned().unwrap_or(Value::Null)) {
+                    Ok(message) => self.wallet.sign_message(message).await,
+                    Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()}))),
+                },
+                (&Method::POST, "sign_hash") => match serde_json::from_value::<H256>(body.get("hash").cloned().unwrap_or(Value::Null)) {
+                    Ok(hash) => self.wallet.sign_hash(hash),
+                    Err(e) => return Ok(respond(StatusCode::BAD_REQUEST, json!({"error": e.to_string()}))),
+                },
+                _ => return Ok(respond(StatusCode::NOT_FOUND, json!({"error": format!("no route for {} /{}", method, path)}))),
+            };
+            match signature {
+                Ok(signature) => Ok(respond(StatusCode::OK, json!({"signature": signature.to_string()}))),
+                Err(e) => Ok(respond(StatusCode::INTERNAL_SERVER_ERROR, json!({"error": e.to_string()}))),
+            }
+        }
+    }
+
+    fn stand_in(token: Option<&str>) -> (LocalWallet, String) {
+        let wallet = KEY.parse::<LocalWallet>().unwrap();
+        let url = StandInSigner { wallet: wallet.clone(), token: token.map(|token| token.to_string()) }.spawn();
+        (wallet, url)
+    }
+
+    // Fresh scratch dir per test, so parallel tests don't share files
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("quoter-signer-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn address() -> Address {
+        KEY.parse::<LocalWallet>().unwrap().address()
+    }
+
+    #[tokio::test]
+    async fn keystore_decrypts_with_the_right_passphrase_only() {
+        let dir = scratch("keystore");
+        let key = ethers::utils::hex::decode(KEY).unwrap();
+        LocalWallet::encrypt_keystore(&dir, &mut ethers::core::rand::thread_rng(), key, "right", Some("owner.json")).unwrap();
+        let path = dir.join("owner.json").to_string_lossy().to_string();
+
+        std::env::set_var("QUOTER_TEST_KEYSTORE_RIGHT", "right");
+        let source = SignerSource::Keystore{path: path.clone(), passphrase_env: Some("QUOTER_TEST_KEYSTORE_RIGHT".to_string())};
+        let signer = source.load(43114).await.unwrap();
+        assert_eq!(signer.address(), address());
+        assert_eq!(signer.chain_id(), 43114);
+
+        std::env::set_var("QUOTER_TEST_KEYSTORE_WRONG", "wrong");
+        let source = SignerSource::Keystore{path: path.clone(), passphrase_env: Some("QUOTER_TEST_KEYSTORE_WRONG".to_string())};
+        assert!(matches!(source.load(43114).await, Err(SignerError::Wallet(_))));
+
+        let source = SignerSource::Keystore{path, passphrase_env: Some("QUOTER_TEST_KEYSTORE_UNSET".to_string())};
+        assert!(matches!(source.load(43114).await, Err(SignerError::MissingEnv(var)) if var == "QUOTER_TEST_KEYSTORE_UNSET"));
+    }
+
+    #[tokio::test]
+    async fn env_key_is_read_and_trimmed() {
+        std::env::set_var("QUOTER_TEST_ENV_KEY", format!("0x{}\n", KEY));
+        let signer = SignerSource::Env{var: "QUOTER_TEST_ENV_KEY".to_string()}.load(43114).await.unwrap();
+        assert_eq!(signer.address(), address());
+
+        let source = SignerSource::Env{var: "QUOTER_TEST_ENV_UNSET".to_string()};
+        assert!(matches!(source.load(43114).await, Err(SignerError::MissingEnv(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn key_file_must_be_private_to_its_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("file");
+        let path = dir.join("owner.key");
+        fs::write(&path, format!("{}\n", KEY)).unwrap();
+        let source = SignerSource::File{path: path.to_string_lossy().to_string()};
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(source.load(43114).await.unwrap().address(), address());
+
+        for mode in [0o640, 0o604, 0o644] {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            match source.load(43114).await {
+                Err(SignerError::Permissions{mode: found, ..}) => assert_eq!(found, mode),
+                res => panic!("expected mode {:o} to be rejected, got {:?}", mode, res),
+            }
+        }
+
+        let missing = SignerSource::File{path: dir.join("missing.key").to_string_lossy().to_string()};
+        assert!(matches!(missing.load(43114).await, Err(SignerError::Read{..})));
+    }
+
+    #[tokio::test]
+    async fn remote_signer_signs_like_the_local_key() {
+        let (wallet, url) = stand_in(Some("secret"));
+        let remote = RemoteSigner::connect(url, Some("secret".to_string())).await.unwrap();
+        assert_eq!(remote.address, wallet.address());
+
+        let signer = AnySigner::Remote(remote).with_chain_id(43114u64);
+        let wallet = wallet.with_chain_id(43114u64);
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::repeat_byte(1))
+            .value(1)
+            .nonce(0)
+            .gas(21000)
+            .gas_price(25_000_000_000u64)
+            .into();
+        let signature = signer.sign_transaction(&tx).await.unwrap();
+        assert_eq!(signature, wallet.sign_transaction(&tx).await.unwrap());
+        let mut with_chain = tx.clone();
+        with_chain.set_chain_id(43114u64);
+        assert_eq!(signature.recover(with_chain.sighash()).unwrap(), wallet.address());
+
+        let signature = signer.sign_message("hello").await.unwrap();
+        assert_eq!(signature, wallet.sign_message("hello").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bad_token_is_rejected_with_401() {
+        let (_, url) = stand_in(Some("secret"));
+        for token in [Some("wrong".to_string()), None] {
+            match RemoteSigner::connect(url.clone(), token).await {
+                Err(SignerError::Remote(e)) => assert!(e.contains("401"), "{}", e),
+                res => panic!("expected 401, got {:?}", res),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_signer_fails_to_connect() {
+        // Bind then drop a listener, so nothing is listening on the port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let res = RemoteSigner::connect(format!("http://{}", addr), None).await;
+        assert!(matches!(res, Err(SignerError::Remote(_))));
+    }
+}
@@ -0,0 +1,668 @@
+use amm::lb;
+use ethers::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::executor::{Amount, Execute};
+use crate::portfolio::{self, Pair, Portfolio, PortfolioConfig};
+use crate::state;
+
+// Bins either side of the active id kept in each recorded frame. Replays and backtests see no liquidity past them
+pub const RECORD_RANGE: u32 = 50;
+
+// What one live decision saw. Markets append a frame per decision when record_dir is set. Times are unix millis
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Frame {
+    pub ts: u64,
+    pub block: u64,
+    pub cex_bid: f64,
+    pub cex_ask: f64,
+    pub bin_step: u16,
+    pub active_id: u32,
+    pub x_decimals: usize,
+    pub y_decimals: usize,
+    // The pair's bins within RECORD_RANGE of the active id
+    pub bins: Vec<SimBin>,
+    // What the executor held
+    pub x_free: u128,
+    pub y_free: u128,
+    pub positions: Vec<portfolio::Bin>,
+}
+
+impl Frame {
+    pub fn capture(amm: &impl Pair, block: u64, cex_bid: f64, cex_ask: f64, portfolio: &Portfolio) -> Self {
+        let active_id = amm.active_id();
+        let bins = (active_id.saturating_sub(RECORD_RANGE)..=active_id.saturating_add(RECORD_RANGE))
+            .filter_map(|id| amm.reserves(id).map(|(x, y)| SimBin { id, x, y, supply: amm.supply(id).unwrap_or(0) }))
+            .collect();
+        let mut positions = portfolio.positions.values().copied().collect::<Vec<portfolio::Bin>>();
+        positions.sort_by_key(|bin| bin.id);
+        Self {
+            ts: state::now_millis(),
+            block,
+            cex_bid,
+            cex_ask,
+            bin_step: amm.bin_step(),
+            active_id,
+            x_decimals: portfolio.x_decimals,
+            y_decimals: portfolio.y_decimals,
+            bins,
+            x_free: portfolio.x_free,
+            y_free: portfolio.y_free,
+            positions,
+        }
+    }
+}
+
+// Appends frames to <dir>/<market>.jsonl, one JSON object per line
+pub struct Recorder {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Recorder {
+    pub fn open(dir: &str, market: &str) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = PathBuf::from(dir).join(format!("{}.jsonl", market));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        info!(path = ?path, "Recording frames");
+        Ok(Self { path, file })
+    }
+
+    pub fn record(&mut self, frame: &Frame) {
+        let mut line = serde_json::to_string(frame).unwrap();
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!(path = ?self.path, error = ?e, "Failed to record frame");
+        }
+    }
+}
+
+pub fn read_frames(path: &str) -> Result<Vec<Frame>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut frames = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        frames.push(serde_json::from_str::<Frame>(&line).map_err(|e| format!("{}:{}: {}", path, n + 1, e))?);
+    }
+    Ok(frames)
+}
+
+#[derive(PartialEq, Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SimBin {
+    pub id: u32,
+    pub x: u128,
+    pub y: u128,
+    pub supply: u128,
+}
+
+// Offline stand-in for the LB pair. Swaps walk the bins from the active id at each bin's price and leave their fee
+// in the bins they cross. Mints only take the side a bin can hold, burns return a share of both
+#[derive(Clone, Debug)]
+pub struct PairSim {
+    pub bin_step: u16,
+    pub active_id: u32,
+    // Swap fee charged on the input, in bps
+    pub fee_bps: u128,
+    pub bins: BTreeMap<u32, SimBin>,
+    tokens: (Uuid, Uuid),
+}
+
+impl PairSim {
+    pub fn new(bin_step: u16, active_id: u32, fee_bps: u128, bins: impl IntoIterator<Item = SimBin>) -> Self {
+        Self {
+            bin_step,
+            active_id,
+            fee_bps,
+            bins: bins.into_iter().map(|bin| (bin.id, bin)).collect(),
+            tokens: (Uuid::new_v4(), Uuid::new_v4()),
+        }
+    }
+
+    pub fn from_frame(frame: &Frame, fee_bps: u128) -> Self {
+        Self::new(frame.bin_step, frame.active_id, fee_bps, frame.bins.iter().copied())
+    }
+
+    fn px(&self, id: u32) -> U256 {
+        lb::Bin::getPriceFromId(id.into(), self.bin_step.into())
+    }
+
+    // Bins a swap walks through, starting at the active id
+    fn path(&self, swap_for_y: bool) -> Vec<u32> {
+        match swap_for_y {
+            true => self.bins.range(..=self.active_id).rev().map(|(id, _)| *id).collect(),
+            false => self.bins.range(self.active_id..).map(|(id, _)| *id).collect(),
+        }
+    }
+
+    // Input, before the fee, that buys out amount of the output side at px. Rounds up
+    fn cost(px: U256, amount: u128, swap_for_y: bool) -> u128 {
+        match swap_for_y {
+            true => Portfolio::y_in_terms_of_x(px, amount),
+            false => Portfolio::x_in_terms_of_y(px, amount),
+        }.saturating_add(1)
+    }
+
+    fn with_fee(&self, net: u128) -> u128 {
+        net.saturating_add(mul_div(net, self.fee_bps, 10000)).saturating_add(1)
+    }
+
+    // Swaps up to amount_in and moves the active id to the last bin it took from. Returns (spent, received)
+    pub fn swap(&mut self, amount_in: u128, swap_for_y: bool) -> (u128, u128) {
+        let mut left = amount_in;
+        let mut received = 0;
+        for id in self.path(swap_for_y) {
+            if left == 0 {
+                break;
+            }
+            let px = self.px(id);
+            let bin = self.bins[&id];
+            let reserve_out = if swap_for_y { bin.y } else { bin.x };
+            if reserve_out == 0 {
+                continue;
+            }
+            let to_empty = Self::cost(px, reserve_out, swap_for_y);
+            let net = mul_div(left, 10000, 10000 + self.fee_bps);
+            let (spent, out) = match net >= to_empty {
+                true => (self.with_fee(to_empty).min(left), reserve_out),
+                false => {
+                    let out = match swap_for_y {
+                        true => Portfolio::x_in_terms_of_y(px, net),
+                        false => Portfolio::y_in_terms_of_x(px, net),
+                    };
+                    (left, out.min(reserve_out))
+                },
+            };
+            let bin = self.bins.get_mut(&id).unwrap();
+            match swap_for_y {
+                true => (bin.x, bin.y) = (bin.x.saturating_add(spent), bin.y - out),
+                false => (bin.x, bin.y) = (bin.x - out, bin.y.saturating_add(spent)),
+            }
+            self.active_id = id;
+            left -= spent;
+            received += out;
+        }
+        (amount_in - left, received)
+    }
+
+    // Adds liquidity. Bins above the active id only take x, bins below only y. Returns (x, y, LB tokens) used and minted
+    pub fn mint(&mut self, id: u32, x: u128, y: u128) -> (u128, u128, u128) {
+        let (x, y) = match id.cmp(&self.active_id) {
+            Ordering::Greater => (x, 0),
+            Ordering::Less => (0, y),
+            Ordering::Equal => (x, y),
+        };
+        (x, y, self.deposit(id, x, y))
+    }
+
+    // Adds liquidity whatever side of the active id the bin is. Tokens are minted in proportion to the bin's value
+    fn deposit(&mut self, id: u32, x: u128, y: u128) -> u128 {
+        let px = self.px(id);
+        let bin = self.bins.entry(id).or_insert(SimBin { id, ..Default::default() });
+        let added = Portfolio::x_in_terms_of_y(px, x).saturating_add(y);
+        let held = Portfolio::x_in_terms_of_y(px, bin.x).saturating_add(bin.y);
+        let tokens = match (bin.supply, held) {
+            (0, _) | (_, 0) => added,
+            (supply, held) => mul_div(added, supply, held),
+        };
+        bin.x = bin.x.saturating_add(x);
+        bin.y = bin.y.saturating_add(y);
+        bin.supply = bin.supply.saturating_add(tokens);
+        tokens
+    }
+
+    // Burns LB tokens for their share of the bin. Returns (x, y)
+    pub fn burn(&mut self, id: u32, tokens: u128) -> (u128, u128) {
+        let (x, y) = self.share(id, tokens);
+        if let Some(bin) = self.bins.get_mut(&id) {
+            bin.x -= x;
+            bin.y -= y;
+            bin.supply -= tokens.min(bin.supply);
+        }
+        (x, y)
+    }
+
+    // Tokens x and y that LB tokens of a bin are worth
+    pub fn share(&self, id: u32, tokens: u128) -> (u128, u128) {
+        match self.bins.get(&id) {
+            Some(bin) if bin.supply > 0 => {
+                let tokens = tokens.min(bin.supply);
+                (mul_div(bin.x, tokens, bin.supply), mul_div(bin.y, tokens, bin.supply))
+            },
+            _ => (0, 0),
+        }
+    }
+
+    // Applies an action the way the MM contract would. Returns false, changing nothing, when it would revert
+    pub fn execute(&mut self, portfolio: &mut Portfolio, action: &Execute, curid: u32) -> bool {
+        let pair = self.clone();
+        let held = (portfolio.x_free, portfolio.y_free, portfolio.positions.clone());
+        if !action.flatten().iter().all(|action| self.apply(portfolio, action, curid)) {
+            *self = pair;
+            (portfolio.x_free, portfolio.y_free, portfolio.positions) = held;
+            return false;
+        }
+        self.sync(portfolio);
+        true
+    }
+
+    fn apply(&mut self, portfolio: &mut Portfolio, action: &Execute, curid: u32) -> bool {
+        match action {
+            Execute::Make(orders) => self.make(portfolio, orders, curid),
+            Execute::Move{from, to} => self.cancel(portfolio, from, curid) && self.make(portfolio, to, curid),
+            Execute::Cancel(orders) => self.cancel(portfolio, orders, curid),
+            Execute::Take{amt_in, amt_out, swap_for_y} => self.take(portfolio, *amt_in, *amt_out, *swap_for_y),
+            Execute::CancelNTake{amt_in, amt_out, swap_for_y, orders} => {
+                self.cancel(portfolio, orders, curid) && self.take(portfolio, *amt_in, *amt_out, *swap_for_y)
+            },
+            // Fees stay in the bins, so there is nothing to claim and no gas to top up
+            Execute::Claim | Execute::CheckGas => true,
+            Execute::Batch(actions) => actions.iter().all(|action| self.apply(portfolio, action, curid)),
+        }
+    }
+
+    fn make(&mut self, portfolio: &mut Portfolio, orders: &[(crate::executor::Tick, u128, u128)], curid: u32) -> bool {
+        for (tick, x, y) in orders {
+            if *x > portfolio.x_free || *y > portfolio.y_free {
+                debug!(tick = ?tick, x = x, y = y, "Make needs more than is free");
+                return false;
+            }
+            let id = tick.id(curid);
+            let (x, y, tokens) = self.mint(id, *x, *y);
+            portfolio.x_free -= x;
+            portfolio.y_free -= y;
+            let bin = portfolio.positions.entry(id).or_insert(portfolio::Bin { id, x: 0, y: 0, tokens: 0 });
+            bin.tokens += tokens;
+        }
+        true
+    }
+
+    // Resolves amounts to LB tokens the same way the executor does before sending
+    fn cancel(&mut self, portfolio: &mut Portfolio, orders: &[(crate::executor::Tick, Amount)], curid: u32) -> bool {
+        for (tick, amount) in orders {
+            let id = tick.id(curid);
+            let held = portfolio.positions.get(&id).map_or(0, |bin| bin.tokens);
+            let (our_x, our_y) = self.share(id, held);
+            let share = |amount: u128, ours: u128| match ours {
+                0 => 0,
+                ours => mul_div(amount, held, ours).min(held),
+            };
+            let tokens = match amount {
+                Amount::Tokens(tokens) => *tokens,
+                Amount::All => held,
+                Amount::Exact(x, y) => share(*x, our_x).max(share(*y, our_y)),
+                Amount::Notional(value) => share(*value, Portfolio::x_in_terms_of_y(self.px(id), our_x).saturating_add(our_y)),
+            }.min(held);
+            if tokens == 0 {
+                continue;
+            }
+            let (x, y) = self.burn(id, tokens);
+            portfolio.x_free += x;
+            portfolio.y_free += y;
+            if let Some(bin) = portfolio.positions.get_mut(&id) {
+                bin.tokens -= tokens;
+            }
+        }
+        true
+    }
+
+    fn take(&mut self, portfolio: &mut Portfolio, amt_in: u128, amt_out: u128, swap_for_y: bool) -> bool {
+        let free_in = if swap_for_y { portfolio.x_free } else { portfolio.y_free };
+        if amt_in > free_in {
+            debug!(amt_in = amt_in, free = free_in, "Take needs more than is free");
+            return false;
+        }
+        let (spent, received) = self.swap(amt_in, swap_for_y);
+        if received < amt_out {
+            debug!(amt_out = amt_out, received = received, "Take slipped past its minimum out");
+            return false;
+        }
+        match swap_for_y {
+            true => (portfolio.x_free, portfolio.y_free) = (portfolio.x_free - spent, portfolio.y_free + received),
+            false => (portfolio.x_free, portfolio.y_free) = (portfolio.x_free + received, portfolio.y_free - spent),
+        }
+        true
+    }
+
+    // Recomputes our positions from the bins they hold a share of, like the live market does after a tx
+    fn sync(&self, portfolio: &mut Portfolio) {
+        portfolio.positions.retain(|_, bin| bin.tokens > 0);
+        for bin in portfolio.positions.values_mut() {
+            (bin.x, bin.y) = self.share(bin.id, bin.tokens);
+        }
+        portfolio.x_balance = portfolio.x_free;
+        portfolio.y_balance = portfolio.y_free;
+    }
+
+    // Moves to a recorded frame's pair. Our liquidity is carried over on top of the recorded reserves, with the side
+    // the price moved through swapped at its bin's price. Fees from that flow aren't credited
+    pub fn follow(&mut self, frame: &Frame, portfolio: &mut Portfolio) {
+        let (from, to) = (self.active_id, frame.active_id);
+        let ours = portfolio.positions.values()
+            .map(|bin| (bin.id, self.share(bin.id, bin.tokens)))
+            .collect::<Vec<(u32, (u128, u128))>>();
+        self.bin_step = frame.bin_step;
+        self.active_id = to;
+        self.bins = frame.bins.iter().map(|bin| (bin.id, *bin)).collect();
+        portfolio.positions.clear();
+        for (id, (x, y)) in ours {
+            let px = self.px(id);
+            let (x, y) = if to > from && id >= from && id < to {
+                (0, y.saturating_add(Portfolio::x_in_terms_of_y(px, x)))
+            } else if to < from && id <= from && id > to {
+                (x.saturating_add(Portfolio::y_in_terms_of_x(px, y)), 0)
+            } else {
+                (x, y)
+            };
+            let tokens = self.deposit(id, x, y);
+            portfolio.positions.insert(id, portfolio::Bin { id, x, y, tokens });
+        }
+        self.sync(portfolio);
+    }
+}
+
+impl Pair for PairSim {
+    fn tokens(&self) -> (Uuid, Uuid) {
+        self.tokens
+    }
+
+    fn bin_step(&self) -> u16 {
+        self.bin_step
+    }
+
+    fn active_id(&self) -> u32 {
+        self.active_id
+    }
+
+    // Bins the sim doesn't hold are empty
+    fn reserves(&self, id: u32) -> Option<(u128, u128)> {
+        Some(self.bins.get(&id).map_or((0, 0), |bin| (bin.x, bin.y)))
+    }
+
+    fn supply(&self, id: u32) -> Option<u128> {
+        Some(self.bins.get(&id).map_or(0, |bin| bin.supply))
+    }
+
+    fn bid_ask(&self, x_unit: U256) -> (U256, U256) {
+        let x_unit = x_unit.min(U256::from(u128::MAX)).as_u128();
+        let (_, bid) = self.clone().swap(x_unit, true);
+        (bid.into(), self.amount_in(x_unit, false).into())
+    }
+
+    // Saturates at u128::MAX when the recorded bins can't fill amount_out
+    fn amount_in(&self, amount_out: u128, swap_for_y: bool) -> u128 {
+        let mut left = amount_out;
+        let mut spent: u128 = 0;
+        for id in self.path(swap_for_y) {
+            if left == 0 {
+                break;
+            }
+            let bin = self.bins[&id];
+            let reserve_out = if swap_for_y { bin.y } else { bin.x };
+            let out = left.min(reserve_out);
+            if out == 0 {
+                continue;
+            }
+            spent = spent.saturating_add(self.with_fee(Self::cost(self.px(id), out, swap_for_y)));
+            left -= out;
+        }
+        match left {
+            0 => spent,
+            _ => u128::MAX,
+        }
+    }
+}
+
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    if c == 0 {
+        return 0;
+    }
+    (U256::from(a) * U256::from(b) / U256::from(c)).min(U256::from(u128::MAX)).as_u128()
+}
+
+// Whole tokens x and y valued in token y at px
+fn value(x: u128, y: u128, x_decimals: usize, y_decimals: usize, px: Decimal) -> Decimal {
+    Portfolio::to_dec(x.into(), x_decimals as u32).saturating_mul(px).saturating_add(Portfolio::to_dec(y.into(), y_decimals as u32))
+}
+
+// One decision of a replay
+#[derive(PartialEq, Clone, Debug, Serialize)]
+pub struct Decision {
+    pub block: u64,
+    pub active_id: u32,
+    pub action: Execute,
+}
+
+// Re-runs the strategy on each frame as the live run saw it, its balances and positions included. Nothing is filled,
+// so every decision is against what actually happened rather than against earlier replayed decisions
+pub fn replay(frames: &[Frame], config: PortfolioConfig, fee_bps: u128) -> Vec<Decision> {
+    let first = match frames.first() {
+        Some(first) => first,
+        None => return Vec::new(),
+    };
+    let mut portfolio = Portfolio::new(&PairSim::from_frame(first, fee_bps), first.x_free, first.y_free, first.x_decimals, first.y_decimals, config);
+    let mut last_ts = first.ts;
+    let mut decisions = Vec::new();
+    for frame in frames {
+        let pair = PairSim::from_frame(frame, fee_bps);
+        (portfolio.x_balance, portfolio.y_balance) = (frame.x_free, frame.y_free);
+        (portfolio.x_free, portfolio.y_free) = (frame.x_free, frame.y_free);
+        portfolio.positions = frame.positions.iter().map(|bin| (bin.id, *bin)).collect();
+        portfolio.advance(Duration::from_millis(frame.ts.saturating_sub(last_ts)));
+        last_ts = frame.ts;
+        if let (Some(action), active_id) = portfolio.on_state(frame.cex_bid, frame.cex_ask, &pair) {
+            decisions.push(Decision { block: frame.block, active_id, action });
+        }
+    }
+    decisions
+}
+
+// Summary of a backtest. Values are in whole token y at the last frame's CEX mid
+#[derive(PartialEq, Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub frames: usize,
+    pub first_block: u64,
+    pub last_block: u64,
+    // Actions by name, and how many of them the contract would have reverted
+    pub actions: BTreeMap<String, u64>,
+    pub reverted: u64,
+    pub start_x: Decimal,
+    pub start_y: Decimal,
+    pub end_x: Decimal,
+    pub end_y: Decimal,
+    pub end_value: Decimal,
+    // Value of the starting balances had they been held throughout
+    pub hold_value: Decimal,
+    pub pnl: Decimal,
+}
+
+// Runs the strategy over the frames on a PairSim. Starts from the first frame's holdings, all of it free, and keeps its
+// own balances and positions from there. Recorded reserves include whatever the live run held. Hedging and risk limits
+// are left out, and claims are no-ops since fees stay in the bins
+pub fn backtest(frames: &[Frame], config: PortfolioConfig, fee_bps: u128) -> Option<Report> {
+    let (first, last) = (frames.first()?, frames.last()?);
+    let (x_start, y_start) = first.positions.iter().fold((first.x_free, first.y_free), |(x, y), bin| (x + bin.x, y + bin.y));
+    let mut pair = PairSim::from_frame(first, fee_bps);
+    let mut portfolio = Portfolio::new(&pair, x_start, y_start, first.x_decimals, first.y_decimals, config);
+    let mut report = Report {
+        frames: frames.len(),
+        first_block: first.block,
+        last_block: last.block,
+        ..Report::default()
+    };
+    let mut last_ts = first.ts;
+    for frame in frames {
+        pair.follow(frame, &mut portfolio);
+        portfolio.advance(Duration::from_millis(frame.ts.saturating_sub(last_ts)));
+        last_ts = frame.ts;
+        let (action, curid) = portfolio.on_state(frame.cex_bid, frame.cex_ask, &pair);
+        if let Some(action) = action {
+            *report.actions.entry(action.name().to_string()).or_default() += 1;
+            if !pair.execute(&mut portfolio, &action, curid) {
+                debug!(block = frame.block, action = ?action, "Action would revert");
+                report.reverted += 1;
+            }
+        }
+    }
+
+    let (x_end, y_end) = portfolio.positions.values().fold((portfolio.x_free, portfolio.y_free), |(x, y), bin| (x + bin.x, y + bin.y));
+    let (x_decimals, y_decimals) = (first.x_decimals, first.y_decimals);
+    let mid = Portfolio::dec((last.cex_bid + last.cex_ask) / 2.0);
+    report.start_x = Portfolio::to_dec(x_start.into(), x_decimals as u32);
+    report.start_y = Portfolio::to_dec(y_start.into(), y_decimals as u32);
+    report.end_x = Portfolio::to_dec(x_end.into(), x_decimals as u32);
+    report.end_y = Portfolio::to_dec(y_end.into(), y_decimals as u32);
+    report.end_value = value(x_end, y_end, x_decimals, y_decimals, mid);
+    report.hold_value = value(x_start, y_start, x_decimals, y_decimals, mid);
+    report.pnl = report.end_value.saturating_sub(report.hold_value);
+    Some(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Tick;
+    use crate::portfolio::tests::config;
+
+    // Price 1 in 128.128
+    const MID: u32 = 1 << 23;
+
+    fn pair() -> PairSim {
+        PairSim::new(20, MID, 0, [
+            SimBin { id: MID - 1, x: 0, y: 1_000_000, supply: 1_000_000 },
+            SimBin { id: MID, x: 1_000_000, y: 1_000_000, supply: 2_000_000 },
+            SimBin { id: MID + 1, x: 1_000_000, y: 0, supply: 1_000_000 },
+        ])
+    }
+
+    fn frame(block: u64, active_id: u32, pair: &PairSim) -> Frame {
+        Frame {
+            ts: block * 1000,
+            block,
+            cex_bid: 0.999,
+            cex_ask: 1.001,
+            bin_step: pair.bin_step,
+            active_id,
+            x_decimals: 6,
+            y_decimals: 6,
+            bins: pair.bins.values().copied().collect(),
+            x_free: 5_000_000,
+            y_free: 5_000_000,
+            positions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn swap_walks_bins_and_moves_the_active_id() {
+        let mut pair = pair();
+        let (spent, received) = pair.swap(1_500_000, true);
+        assert_eq!(spent, 1_500_000);
+        assert!(received > 1_000_000 && received < 1_500_000);
+        assert_eq!(pair.active_id, MID - 1);
+        assert_eq!(pair.bins[&MID].y, 0);
+        // Emptying the bin costs its y at price 1, plus a unit for each rounding up
+        assert_eq!(pair.bins[&MID].x, 2_000_002);
+    }
+
+    #[test]
+    fn amount_in_buys_at_least_amount_out() {
+        let mut pair = pair();
+        pair.fee_bps = 30;
+        for swap_for_y in [true, false] {
+            let amount_in = pair.amount_in(1_500_000, swap_for_y);
+            let (_, received) = pair.clone().swap(amount_in, swap_for_y);
+            assert!(received >= 1_500_000);
+        }
+        assert_eq!(pair.amount_in(5_000_000, true), u128::MAX);
+    }
+
+    #[test]
+    fn mint_then_burn_returns_the_deposit() {
+        let mut pair = pair();
+        let (x, y, tokens) = pair.mint(MID, 500_000, 500_000);
+        assert_eq!((x, y), (500_000, 500_000));
+        let (x, y) = pair.burn(MID, tokens);
+        assert!(500_000 - x <= 1 && 500_000 - y <= 1);
+
+        // Bins above the active id only take x
+        let (x, y, _) = pair.mint(MID + 1, 500_000, 500_000);
+        assert_eq!((x, y), (500_000, 0));
+    }
+
+    #[test]
+    fn failed_actions_change_nothing() {
+        let mut pair = pair();
+        let mut portfolio = Portfolio::new(&pair, 1_000_000, 1_000_000, 6, 6, config());
+        let before = pair.bins.clone();
+        let action = Execute::Batch(vec![
+            Execute::Make(vec![(Tick::Delta(0), 500_000, 500_000)]),
+            Execute::Make(vec![(Tick::Delta(1), 2_000_000, 0)]),
+        ]);
+        assert!(!pair.execute(&mut portfolio, &action, MID));
+        assert_eq!(pair.bins, before);
+        assert_eq!((portfolio.x_free, portfolio.y_free), (1_000_000, 1_000_000));
+        assert!(portfolio.positions.is_empty());
+
+        assert!(pair.execute(&mut portfolio, &Execute::Make(vec![(Tick::Delta(0), 500_000, 500_000)]), MID));
+        assert_eq!((portfolio.x_free, portfolio.y_free), (500_000, 500_000));
+        assert!(pair.execute(&mut portfolio, &Execute::Cancel(vec![(Tick::Delta(0), Amount::All)]), MID));
+        assert!(portfolio.positions.is_empty());
+        assert!(1_000_000 - portfolio.x_free <= 1 && 1_000_000 - portfolio.y_free <= 1);
+    }
+
+    #[test]
+    fn crossing_our_bin_converts_it_to_the_other_side() {
+        let mut pair = pair();
+        let mut portfolio = Portfolio::new(&pair, 1_000_000, 0, 6, 6, config());
+        assert!(pair.execute(&mut portfolio, &Execute::Make(vec![(Tick::Delta(1), 1_000_000, 0)]), MID));
+        assert_eq!(portfolio.positions[&(MID + 1)].x, 1_000_000);
+
+        let mut frame = frame(2, MID + 2, &pair);
+        frame.bins.clear();
+        pair.follow(&frame, &mut portfolio);
+        let bin = portfolio.positions[&(MID + 1)];
+        assert_eq!(bin.x, 0);
+        assert!(bin.y > 1_000_000);
+        assert_eq!(pair.active_id, MID + 2);
+    }
+
+    #[test]
+    fn backtest_covers_every_frame() {
+        assert!(backtest(&[], config(), 10).is_none());
+        let pair = pair();
+        let frames = [frame(1, MID, &pair), frame(2, MID, &pair), frame(3, MID + 1, &pair)];
+        let report = backtest(&frames, config(), 10).unwrap();
+        assert_eq!(report.frames, 3);
+        assert_eq!((report.first_block, report.last_block), (1, 3));
+        assert_eq!((report.start_x, report.start_y), (Decimal::new(5, 0), Decimal::new(5, 0)));
+        assert!(report.reverted <= report.actions.values().sum::<u64>());
+    }
+
+    #[test]
+    fn recorded_frames_read_back() {
+        let dir = std::env::temp_dir().join(format!("quoter-sim-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let pair = pair();
+        let mut recorder = Recorder::open(dir, "avax-usdc").unwrap();
+        let frames = vec![frame(1, MID, &pair), frame(2, MID - 1, &pair)];
+        for frame in &frames {
+            recorder.record(frame);
+        }
+        let path = format!("{}/avax-usdc.jsonl", dir);
+        assert_eq!(read_frames(&path).unwrap(), frames);
+        fs::write(&path, "{}\n").unwrap();
+        assert!(read_frames(&path).unwrap_err().contains(":1:"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
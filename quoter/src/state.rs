@@ -0,0 +1,100 @@
+use ethers::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn, error};
+
+// Everything a market needs to pick up where it left off. Times are unix millis
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MarketState {
+    pub bins_touched: Vec<u32>,
+    pub pending_txs: Vec<TxHash>,
+    pub sent_ts: Vec<u64>,
+    pub fees_x_claimed: u128,
+    pub fees_y_claimed: u128,
+    #[serde(default)]
+    pub fee_pnl: Decimal,
+
+    pub last_fee_claim: Option<u64>,
+    pub last_gas_check: Option<u64>,
+    pub last_rebalance: Option<u64>,
+    pub x_hedge: i128,
+    pub hedge_cash: Decimal,
+}
+
+// JSON file per market, replaced atomically on every write
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    path: PathBuf,
+    state: Arc<Mutex<MarketState>>,
+}
+
+impl StateStore {
+    pub fn open(dir: &str, market: &str) -> std::io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = PathBuf::from(dir).join(format!("{}.json", market));
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(state) => {
+                    info!(path = ?path, state = ?state, "Loaded state");
+                    state
+                },
+                Err(e) => {
+                    error!(path = ?path, error = ?e, "Corrupt state file. Starting fresh");
+                    MarketState::default()
+                }
+            },
+            Err(_) => {
+                info!(path = ?path, "No state file. Starting fresh");
+                MarketState::default()
+            }
+        };
+        Ok(Self {
+            path,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn get(&self) -> MarketState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn update<F: FnOnce(&mut MarketState)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        if let Err(e) = self.write(&state) {
+            error!(path = ?self.path, error = ?e, "Failed to write state");
+        }
+    }
+
+    fn write(&self, state: &MarketState) -> std::io::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(state).unwrap().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+pub fn to_millis(instant: Instant) -> u64 {
+    now_millis().saturating_sub(instant.elapsed().as_millis() as u64)
+}
+
+pub fn from_millis(millis: u64) -> Instant {
+    let age = Duration::from_millis(now_millis().saturating_sub(millis));
+    match Instant::now().checked_sub(age) {
+        Some(instant) => instant,
+        None => {
+            warn!(millis = millis, "Timestamp too old to restore");
+            Instant::now()
+        }
+    }
+}
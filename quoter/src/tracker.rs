@@ -0,0 +1,293 @@
+use ethers::prelude::*;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, error};
+
+use crate::executor::{ERC20, LBPair};
+use crate::executor::lb_pair::LBPairEvents;
+use crate::metrics;
+
+// Wait before resubscribing after a stream ends
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+// How long to wait for the logs of a mined tx before falling back to RPC
+const TX_LOG_TIMEOUT: Duration = Duration::from_secs(10);
+// Logs of one block arrive one by one. Stop draining once none arrive for this long
+const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum Change {
+    // Signed change of the executor's token balances
+    X(i128),
+    Y(i128),
+    // Signed change of the executor's LB tokens per bin
+    LiqTokens(Vec<(u32, i128)>),
+    // Fees the pair paid out to the executor. The tokens themselves show up as a Transfer
+    Fees(u128, u128),
+    // Someone traded against or changed the liquidity of a bin
+    BinChanged(u32),
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct Update {
+    pub block: u64,
+    pub change: Change,
+}
+
+// Subscribes to the pair and to token transfers in and out of the executor.
+// Each subscription runs in its own task and resubscribes when its stream ends.
+pub fn spawn<M>(client: Arc<M>, market: String, executor: Address, pair: Address, x: Address, y: Address) -> Subscriptions
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut tasks = vec![tokio::spawn(watch_pair(client.clone(), market.clone(), pair, executor, tx.clone()))];
+    for (token, is_x) in [(x, true), (y, false)] {
+        for incoming in [true, false] {
+            tasks.push(tokio::spawn(watch_transfers(client.clone(), market.clone(), token, is_x, executor, incoming, tx.clone())));
+        }
+    }
+    Subscriptions { rx, tasks }
+}
+
+// Updates from the subscription tasks. The tasks are aborted when this is dropped,
+// so a market restarted on new providers doesn't leave them running
+pub struct Subscriptions {
+    rx: mpsc::UnboundedReceiver<Update>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+async fn watch_pair<M>(client: Arc<M>, market: String, address: Address, executor: Address, tx: mpsc::UnboundedSender<Update>)
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+{
+    let pair = LBPair::new(address, client);
+    loop {
+        let events = pair.events();
+        match events.subscribe_with_meta().await {
+            Ok(mut stream) => {
+                info!(pair = ?address, "Subscribed to pair events");
+                while let Some(res) = stream.next().await {
+                    let (event, meta) = match res {
+                        Ok(event) => event,
+                        Err(e) => {
+                            warn!(error = ?e, "Failed to decode pair event");
+                            continue;
+                        }
+                    };
+                    if let Some(change) = pair_change(event, executor) {
+                        if tx.send(Update { block: meta.block_number.as_u64(), change }).is_err() {
+                            return;
+                        }
+                    }
+                }
+                warn!(pair = ?address, "Pair event stream ended");
+            },
+            Err(e) => error!(error = ?e, pair = ?address, "Failed to subscribe to pair events"),
+        }
+        metrics::RECONNECTS.with_label_values(&[&market, "pair"]).inc();
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+fn pair_change(event: LBPairEvents, executor: Address) -> Option<Change> {
+    let sign = |from: Address, to: Address| match (from == executor, to == executor) {
+        (false, true) => Some(1),
+        (true, false) => Some(-1),
+        _ => None,
+    };
+    match event {
+        LBPairEvents::TransferBatchFilter(transfer) => {
+            let sign = sign(transfer.from, transfer.to)?;
+            Some(Change::LiqTokens(
+                transfer.ids.iter().zip(transfer.amounts.iter()).map(|(id, amount)| (id.as_u32(), sign * amount.as_u128() as i128)).collect()
+            ))
+        },
+        LBPairEvents::TransferSingleFilter(transfer) => {
+            let sign = sign(transfer.from, transfer.to)?;
+            Some(Change::LiqTokens(vec![(transfer.id.as_u32(), sign * transfer.amount.as_u128() as i128)]))
+        },
+        LBPairEvents::FeesCollectedFilter(fees) if fees.recipient == executor => {
+            Some(Change::Fees(fees.amount_x.as_u128(), fees.amount_y.as_u128()))
+        },
+        LBPairEvents::SwapFilter(swap) => Some(Change::BinChanged(swap.id.as_u32())),
+        LBPairEvents::DepositedToBinFilter(deposit) => Some(Change::BinChanged(deposit.id.as_u32())),
+        LBPairEvents::WithdrawnFromBinFilter(withdraw) => Some(Change::BinChanged(withdraw.id.as_u32())),
+        _ => None,
+    }
+}
+
+async fn watch_transfers<M>(client: Arc<M>, market: String, address: Address, is_x: bool, executor: Address, incoming: bool, tx: mpsc::UnboundedSender<Update>)
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+{
+    let token = ERC20::new(address, client);
+    loop {
+        let events = match incoming {
+            true => token.transfer_filter().topic2(executor),
+            false => token.transfer_filter().topic1(executor),
+        };
+        match events.subscribe_with_meta().await {
+            Ok(mut stream) => {
+                info!(token = ?address, incoming = incoming, "Subscribed to token transfers");
+                while let Some(res) = stream.next().await {
+                    let (transfer, meta) = match res {
+                        Ok(transfer) => transfer,
+                        Err(e) => {
+                            warn!(error = ?e, "Failed to decode token transfer");
+                            continue;
+                        }
+                    };
+                    // Transfers to self show up on both streams and cancel out
+                    let amount = transfer.value.as_u128() as i128;
+                    let delta = if incoming { amount } else { -amount };
+                    let change = if is_x { Change::X(delta) } else { Change::Y(delta) };
+                    if tx.send(Update { block: meta.block_number.as_u64(), change }).is_err() {
+                        return;
+                    }
+                }
+                warn!(token = ?address, incoming = incoming, "Token transfer stream ended");
+            },
+            Err(e) => error!(error = ?e, token = ?address, "Failed to subscribe to token transfers"),
+        }
+        metrics::RECONNECTS.with_label_values(&[&market, "transfers"]).inc();
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+// The executor's balances and LB tokens, kept up to date from logs. Logs can be missed while
+// resubscribing or reverted by a reorg, so the market resyncs this from the chain periodically.
+#[derive(Debug)]
+pub struct PositionTracker {
+    pub x_balance: u128,
+    pub y_balance: u128,
+    pub tokens: HashMap<u32, u128>,
+    pub fees_x: u128,
+    pub fees_y: u128,
+    pub last_block: u64,
+    // Earliest block that changed a bin we hold, until positions are recomputed
+    pub dirty: Option<u64>,
+    subs: Subscriptions,
+}
+
+impl PositionTracker {
+    pub fn new(subs: Subscriptions, x_balance: u128, y_balance: u128, tokens: HashMap<u32, u128>) -> Self {
+        Self {
+            x_balance,
+            y_balance,
+            tokens,
+            fees_x: 0,
+            fees_y: 0,
+            last_block: 0,
+            dirty: None,
+            subs,
+        }
+    }
+
+    // Applies every update received so far. Returns whether the executor's balances changed
+    pub fn drain(&mut self) -> bool {
+        let mut changed = false;
+        while let Ok(update) = self.subs.rx.try_recv() {
+            changed |= self.apply(update);
+        }
+        changed
+    }
+
+    // Waits until the logs of a tx mined in this block have been applied
+    pub async fn wait_for_block(&mut self, block: u64) -> bool {
+        self.drain();
+        let start = tokio::time::Instant::now();
+        while self.last_block < block {
+            match tokio::time::timeout(TX_LOG_TIMEOUT.saturating_sub(start.elapsed()), self.subs.rx.recv()).await {
+                Ok(Some(update)) => { self.apply(update); },
+                Ok(None) | Err(_) => {
+                    warn!(block = block, last_block = self.last_block, "Timed out waiting for tx logs");
+                    return false;
+                }
+            }
+        }
+        while let Ok(Some(update)) = tokio::time::timeout(QUIET_PERIOD, self.subs.rx.recv()).await {
+            self.apply(update);
+        }
+        true
+    }
+
+    fn apply(&mut self, update: Update) -> bool {
+        debug!(update = ?update, "Tracker update");
+        let block = update.block;
+        self.last_block = self.last_block.max(block);
+        let mark_dirty = |dirty: &mut Option<u64>| *dirty = Some(dirty.map_or(block, |b| b.min(block)));
+        match update.change {
+            Change::X(delta) => self.x_balance = self.x_balance.saturating_add_signed(delta),
+            Change::Y(delta) => self.y_balance = self.y_balance.saturating_add_signed(delta),
+            Change::LiqTokens(deltas) => {
+                for (id, delta) in deltas {
+                    let tokens = self.tokens.get(&id).cloned().unwrap_or(0).saturating_add_signed(delta);
+                    match tokens {
+                        0 => self.tokens.remove(&id),
+                        _ => self.tokens.insert(id, tokens),
+                    };
+                }
+                mark_dirty(&mut self.dirty);
+            },
+            Change::Fees(x, y) => {
+                self.fees_x += x;
+                self.fees_y += y;
+                return false;
+            },
+            Change::BinChanged(id) => {
+                if self.tokens.contains_key(&id) {
+                    mark_dirty(&mut self.dirty);
+                }
+                return false;
+            },
+        }
+        true
+    }
+
+    // Replaces tracked state with what the chain reports. Returns whether they differed
+    pub fn resync(&mut self, x_balance: u128, y_balance: u128, tokens: HashMap<u32, u128>, block: u64) -> bool {
+        let mut bins = self.tokens.keys().chain(tokens.keys()).cloned().collect::<Vec<u32>>();
+        bins.sort();
+        bins.dedup();
+        let token_drift = bins.into_iter().filter_map(|id| {
+            let tracked = self.tokens.get(&id).cloned().unwrap_or(0);
+            let chain = tokens.get(&id).cloned().unwrap_or(0);
+            (tracked != chain).then(|| (id, tracked, chain))
+        }).collect::<Vec<(u32, u128, u128)>>();
+        let drifted = self.x_balance != x_balance || self.y_balance != y_balance || !token_drift.is_empty();
+        if drifted {
+            warn!(
+                block = block,
+                x_tracked = self.x_balance,
+                x_chain = x_balance,
+                y_tracked = self.y_balance,
+                y_chain = y_balance,
+                token_drift = ?token_drift,
+                "Tracked positions drifted from chain"
+            );
+            self.dirty = Some(self.dirty.map_or(block, |b| b.min(block)));
+        } else {
+            debug!(block = block, "Tracked positions match chain");
+        }
+        self.x_balance = x_balance;
+        self.y_balance = y_balance;
+        self.tokens = tokens;
+        self.last_block = self.last_block.max(block);
+        drifted
+    }
+}
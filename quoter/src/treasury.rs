@@ -0,0 +1,123 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::metrics;
+
+// Actions whose mined txs count as trades when reporting cost per trade
+const TRADES: [&str; 5] = ["Make", "Move", "Cancel", "Take", "CancelNTake"];
+
+// Limits on turning the contracts' WETH into callers' gas. Amounts are in wei
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TreasuryConfig {
+    // Max WETH unwrapped into gas across every market since the start of the UTC day
+    pub max_daily_topup: u128,
+    // Alert once a contract holds less WETH than this
+    pub min_reserve: u128,
+}
+
+// Gas paid for one action of one market
+#[derive(PartialEq, Clone, Copy, Debug, Default, Serialize)]
+pub struct Spend {
+    pub txs: u64,
+    pub mined: u64,
+    pub wei: u128,
+}
+
+// What GET /state reports for each market
+#[derive(PartialEq, Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub spent: HashMap<String, Spend>,
+    // Across every market
+    pub topped_up_today: u128,
+    // Every wei of gas the market paid, over its mined trades
+    pub cost_per_trade: Option<f64>,
+}
+
+// Tracks gas spent and budgets top-ups. Shared by every market in the process
+#[derive(Clone, Debug)]
+pub struct Treasury {
+    pub config: Option<TreasuryConfig>,
+    spent: HashMap<(String, String), Spend>,
+    day: NaiveDate,
+    topped_up: u128,
+}
+
+impl Treasury {
+    pub fn new(config: Option<TreasuryConfig>) -> Self {
+        Self {
+            config,
+            spent: HashMap::new(),
+            day: Utc::now().date_naive(),
+            topped_up: 0,
+        }
+    }
+
+    fn roll(&mut self) {
+        let today = Utc::now().date_naive();
+        if today != self.day {
+            info!(day = %today, topped_up = self.topped_up, "New treasury day");
+            self.day = today;
+            self.topped_up = 0;
+        }
+    }
+
+    // Gas paid by a mined or reverted tx
+    pub fn record(&mut self, market: &str, action: &str, wei: u128, mined: bool) {
+        let spend = self.spent.entry((market.to_string(), action.to_string())).or_default();
+        spend.txs += 1;
+        spend.mined += mined as u64;
+        spend.wei += wei;
+        metrics::GAS_SPENT.with_label_values(&[market, action]).inc_by(wei as f64 / 1e18);
+        if let Some(cost) = self.cost_per_trade(market) {
+            metrics::COST_PER_TRADE.with_label_values(&[market]).set(cost / 1e18);
+        }
+    }
+
+    pub fn cost_per_trade(&self, market: &str) -> Option<f64> {
+        let (wei, trades) = self.spent.iter()
+            .filter(|((spender, _), _)| spender == market)
+            .fold((0, 0), |(wei, trades), ((_, action), spend)| {
+                (wei + spend.wei, trades + if TRADES.contains(&action.as_str()) { spend.mined } else { 0 })
+            });
+        match trades {
+            0 => None,
+            trades => Some(wei as f64 / trades as f64),
+        }
+    }
+
+    // Reserves up to amount of today's top-up budget and returns how much was granted.
+    // Give back whatever isn't unwrapped with refund
+    pub fn reserve_topup(&mut self, market: &str, amount: u128) -> u128 {
+        self.roll();
+        let granted = match self.config {
+            Some(config) => amount.min(config.max_daily_topup.saturating_sub(self.topped_up)),
+            None => amount,
+        };
+        if granted < amount {
+            warn!(market = market, requested = amount, granted = granted, topped_up = self.topped_up, "Daily top-up cap reached");
+        }
+        self.topped_up += granted;
+        granted
+    }
+
+    pub fn refund(&mut self, amount: u128) {
+        self.topped_up = self.topped_up.saturating_sub(amount);
+    }
+
+    pub fn low_reserve(&self, reserve: u128) -> bool {
+        self.config.map_or(false, |config| reserve < config.min_reserve)
+    }
+
+    pub fn report(&self, market: &str) -> Report {
+        Report {
+            spent: self.spent.iter()
+                .filter(|((spender, _), _)| spender == market)
+                .map(|((_, action), spend)| (action.clone(), *spend))
+                .collect(),
+            topped_up_today: self.topped_up,
+            cost_per_trade: self.cost_per_trade(market),
+        }
+    }
+}